@@ -0,0 +1,340 @@
+//! Translating the styles that terminal programs set with ANSI escape
+//! sequences into formatting that can be shown in a notebook
+
+use crate::redact::Redactor;
+use fiberplane::models::formatting::{Annotation, AnnotationWithOffset, Formatting};
+use termwiz::cell::{Intensity, Underline};
+use termwiz::color::ColorSpec;
+use termwiz::escape::csi::Sgr;
+use termwiz::escape::{parser::Parser, Action, ControlCode, CSI};
+
+/// The parts of the terminal's text style that can be represented in a
+/// notebook. Notebooks don't support colours, so coloured text is highlighted
+/// instead. There is only one kind of highlight, so text in different colours
+/// looks the same.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Style {
+    bold: bool,
+    italic: bool,
+    underline: bool,
+    strikethrough: bool,
+    foreground: bool,
+    background: bool,
+    inverse: bool,
+}
+
+impl Style {
+    pub const DEFAULT: Style = Style {
+        bold: false,
+        italic: false,
+        underline: false,
+        strikethrough: false,
+        foreground: false,
+        background: false,
+        inverse: false,
+    };
+
+    /// Update the style with an SGR ("Select Graphic Rendition") sequence
+    pub fn apply(&mut self, sgr: &Sgr) {
+        match sgr {
+            Sgr::Reset => *self = Style::default(),
+            Sgr::Intensity(intensity) => self.bold = *intensity == Intensity::Bold,
+            Sgr::Italic(italic) => self.italic = *italic,
+            Sgr::Underline(underline) => self.underline = *underline != Underline::None,
+            Sgr::StrikeThrough(strikethrough) => self.strikethrough = *strikethrough,
+            Sgr::Foreground(color) => self.foreground = *color != ColorSpec::Default,
+            Sgr::Background(color) => self.background = *color != ColorSpec::Default,
+            Sgr::Inverse(inverse) => self.inverse = *inverse,
+            _ => {}
+        }
+    }
+
+    /// Update the style with an annotation of the formatting it translates to
+    fn apply_annotation(&mut self, annotation: &Annotation) {
+        match annotation {
+            Annotation::StartBold => self.bold = true,
+            Annotation::EndBold => self.bold = false,
+            Annotation::StartItalics => self.italic = true,
+            Annotation::EndItalics => self.italic = false,
+            Annotation::StartUnderline => self.underline = true,
+            Annotation::EndUnderline => self.underline = false,
+            Annotation::StartStrikethrough => self.strikethrough = true,
+            Annotation::EndStrikethrough => self.strikethrough = false,
+            Annotation::StartHighlight => self.inverse = true,
+            Annotation::EndHighlight => {
+                self.foreground = false;
+                self.background = false;
+                self.inverse = false;
+            }
+            _ => {}
+        }
+    }
+
+    fn is_highlighted(&self) -> bool {
+        self.foreground || self.background || self.inverse
+    }
+
+    /// An escape sequence that resets the terminal to this style. Colours are
+    /// not kept, so highlighted text is shown inverted.
+    pub fn escape_sequence(&self) -> String {
+        let mut sequence = String::from("\x1b[0");
+        for (enabled, code) in [
+            (self.bold, ";1"),
+            (self.italic, ";3"),
+            (self.underline, ";4"),
+            (self.is_highlighted(), ";7"),
+            (self.strikethrough, ";9"),
+        ] {
+            if enabled {
+                sequence.push_str(code);
+            }
+        }
+        sequence.push('m');
+        sequence
+    }
+
+    /// The attributes of the style along with the annotations that start and
+    /// end them
+    fn attributes(&self) -> [(bool, Annotation, Annotation); 5] {
+        [
+            (self.bold, Annotation::StartBold, Annotation::EndBold),
+            (
+                self.italic,
+                Annotation::StartItalics,
+                Annotation::EndItalics,
+            ),
+            (
+                self.underline,
+                Annotation::StartUnderline,
+                Annotation::EndUnderline,
+            ),
+            (
+                self.strikethrough,
+                Annotation::StartStrikethrough,
+                Annotation::EndStrikethrough,
+            ),
+            (
+                self.is_highlighted(),
+                Annotation::StartHighlight,
+                Annotation::EndHighlight,
+            ),
+        ]
+    }
+}
+
+/// Text with the escape sequences removed, along with the formatting that
+/// they translate to
+#[derive(Debug, PartialEq)]
+pub struct StyledText {
+    pub text: String,
+    pub formatting: Formatting,
+}
+
+impl StyledText {
+    /// Parse text that contains ANSI escape sequences. Only the styles are
+    /// kept, other escape sequences such as cursor movements are dropped.
+    pub fn parse(input: &str) -> Self {
+        let mut text = String::with_capacity(input.len());
+        let mut formatting = Formatting::new();
+        let mut offset = 0;
+        let mut style = Style::default();
+        // The style of the text that was added so far. Changes to the style
+        // are only applied once there is text to apply them to, so we don't
+        // end up with empty annotations.
+        let mut applied_style = Style::default();
+
+        for action in Parser::new().parse_as_vec(input.as_bytes()) {
+            let c = match action {
+                Action::Print(c) => c,
+                Action::Control(
+                    code @ (ControlCode::LineFeed
+                    | ControlCode::CarriageReturn
+                    | ControlCode::HorizontalTab),
+                ) => code as u8 as char,
+                Action::CSI(CSI::Sgr(sgr)) => {
+                    style.apply(&sgr);
+                    continue;
+                }
+                _ => continue,
+            };
+
+            if style != applied_style {
+                add_style_change(&mut formatting, offset, &applied_style, &style);
+                applied_style = style;
+            }
+            text.push(c);
+            offset += 1;
+        }
+        add_style_change(&mut formatting, offset, &applied_style, &Style::default());
+
+        Self { text, formatting }
+    }
+
+    /// Redact the text, moving the formatting along with it. Redacting the
+    /// text rather than the input means escape sequences in the middle of a
+    /// secret can't hide it from the redactor.
+    pub fn redact(self, redactor: &mut Redactor) -> Self {
+        let redacted = redactor.redact_tracked(&self.text);
+
+        // Formatting is positioned by characters, while the redactor keeps
+        // track of bytes
+        let byte_offsets: Vec<_> = self
+            .text
+            .char_indices()
+            .map(|(index, _)| index)
+            .chain(std::iter::once(self.text.len()))
+            .collect();
+        let formatting = self
+            .formatting
+            .into_iter()
+            .map(|annotation| {
+                let offset = redacted.offset(byte_offsets[annotation.offset as usize]);
+                AnnotationWithOffset::new(
+                    redacted.text[..offset].chars().count() as u32,
+                    annotation.annotation,
+                )
+            })
+            .collect();
+
+        Self {
+            text: redacted.text,
+            formatting,
+        }
+    }
+
+    /// The text with escape sequences that set its styles again
+    pub fn to_escaped(&self) -> String {
+        let mut output = String::with_capacity(self.text.len());
+        let mut style = Style::default();
+        let mut annotations = self.formatting.iter().peekable();
+        for (offset, c) in self.text.chars().enumerate() {
+            let previous_style = style;
+            while let Some(annotation) = annotations.next_if(|a| a.offset as usize <= offset) {
+                style.apply_annotation(&annotation.annotation);
+            }
+            if style != previous_style {
+                output.push_str(&style.escape_sequence());
+            }
+            output.push(c);
+        }
+        if style != Style::default() {
+            output.push_str(&Style::default().escape_sequence());
+        }
+        output
+    }
+
+    /// The formatting of the text, which is additionally shown as code so it
+    /// keeps the monospace font of the terminal
+    pub fn code_formatting(&self) -> Formatting {
+        if self.text.is_empty() {
+            return Formatting::new();
+        }
+
+        let mut formatting = Vec::with_capacity(self.formatting.len() + 2);
+        formatting.push(AnnotationWithOffset::new(0, Annotation::StartCode));
+        formatting.extend(self.formatting.iter().cloned());
+        formatting.push(AnnotationWithOffset::new(
+            self.text.chars().count() as u32,
+            Annotation::EndCode,
+        ));
+        formatting
+    }
+}
+
+/// Redact text that contains escape sequences. The escape sequences are
+/// removed first, so they can't hide a secret from the redactor, and only the
+/// ones that set the text style are put back.
+pub fn redact_escaped(input: &str, redactor: &mut Redactor) -> String {
+    StyledText::parse(input).redact(redactor).to_escaped()
+}
+
+/// Remove all escape sequences from the text
+pub fn strip_escape_sequences(input: &str) -> String {
+    StyledText::parse(input).text
+}
+
+fn add_style_change(formatting: &mut Formatting, offset: u32, from: &Style, to: &Style) {
+    let from = from.attributes();
+    let to = to.attributes();
+
+    for ((was_enabled, _, end), (enabled, _, _)) in from.iter().zip(to.iter()) {
+        if *was_enabled && !enabled {
+            formatting.push(AnnotationWithOffset::new(offset, end.clone()));
+        }
+    }
+    for ((was_enabled, _, _), (enabled, start, _)) in from.iter().zip(to.iter()) {
+        if !was_enabled && *enabled {
+            formatting.push(AnnotationWithOffset::new(offset, start.clone()));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_styles() {
+        let styled = StyledText::parse(
+            "\x1b[1mbold\x1b[0m \x1b[31;4mred\x1b[24m\x1b[K!\x1b[39m\r\n\x1b[32m\x1b[0mplain",
+        );
+        assert_eq!(styled.text, "bold red!\r\nplain");
+        assert_eq!(
+            styled.formatting,
+            vec![
+                AnnotationWithOffset::new(0, Annotation::StartBold),
+                AnnotationWithOffset::new(4, Annotation::EndBold),
+                AnnotationWithOffset::new(5, Annotation::StartUnderline),
+                AnnotationWithOffset::new(5, Annotation::StartHighlight),
+                AnnotationWithOffset::new(8, Annotation::EndUnderline),
+                AnnotationWithOffset::new(9, Annotation::EndHighlight),
+            ]
+        );
+    }
+
+    #[test]
+    fn escape_sequence_round_trip() {
+        let mut style = Style::default();
+        style.apply(&Sgr::Intensity(Intensity::Bold));
+        style.apply(&Sgr::Foreground(ColorSpec::PaletteIndex(1)));
+
+        let styled = StyledText::parse(&format!("{}text", style.escape_sequence()));
+        assert_eq!(
+            styled.formatting,
+            vec![
+                AnnotationWithOffset::new(0, Annotation::StartBold),
+                AnnotationWithOffset::new(0, Annotation::StartHighlight),
+                AnnotationWithOffset::new(4, Annotation::EndBold),
+                AnnotationWithOffset::new(4, Annotation::EndHighlight),
+            ]
+        );
+    }
+
+    #[test]
+    fn redact_styled_text() {
+        let mut redactor = Redactor::new(&[]).unwrap();
+        let styled =
+            StyledText::parse("ip \x1b[1m10.0.\x1b[0m12.1 \x1b[4mé\x1b[0m").redact(&mut redactor);
+        assert_eq!(styled.text, "ip [REDACTED:ip-address] é");
+        assert_eq!(
+            styled.formatting,
+            vec![
+                AnnotationWithOffset::new(3, Annotation::StartBold),
+                AnnotationWithOffset::new(24, Annotation::EndBold),
+                AnnotationWithOffset::new(25, Annotation::StartUnderline),
+                AnnotationWithOffset::new(26, Annotation::EndUnderline),
+            ]
+        );
+    }
+
+    #[test]
+    fn redact_escaped_text() {
+        let mut redactor = Redactor::new(&[]).unwrap();
+        let input = "\x1b[31mip 10.0.\x1b[1m12.1\x1b[0m\r\nplain";
+        let redacted = redact_escaped(input, &mut redactor);
+        assert_eq!(
+            redacted,
+            "\x1b[0;7mip [REDACTED:ip-address]\x1b[0m\r\nplain"
+        );
+    }
+}
@@ -0,0 +1,104 @@
+use anyhow::{Context, Result};
+use fiberplane::api_client::clients::ApiClient;
+use fiberplane::base64uuid::Base64Uuid;
+use fiberplane::models::formatting::{Annotation, AnnotationWithOffset};
+use fiberplane::models::notebooks::{Cell, TextCell};
+use fiberplane::models::utils::char_count;
+use reqwest::multipart::{Form, Part};
+use reqwest::StatusCode;
+use serde::Deserialize;
+use url::Url;
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct FileSummary {
+    file_id: String,
+    #[serde(default)]
+    file_name: Option<String>,
+}
+
+/// Upload a file that is attached to the notebook and return its URL
+pub(crate) async fn upload_file(
+    client: &ApiClient,
+    notebook_id: Base64Uuid,
+    file_name: &str,
+    mime_type: &str,
+    content: Vec<u8>,
+) -> Result<Url> {
+    let files_url = client
+        .server
+        .join(&format!("api/notebooks/{notebook_id}/files"))?;
+    let part = Part::bytes(content)
+        .file_name(file_name.to_string())
+        .mime_str(mime_type)?;
+
+    let file: FileSummary = client
+        .client
+        .post(files_url.clone())
+        .multipart(Form::new().part("file", part))
+        .send()
+        .await
+        .and_then(|response| response.error_for_status())
+        .with_context(|| format!("Error uploading {file_name}"))?
+        .json()
+        .await?;
+
+    file_url(&files_url, &file.file_id)
+}
+
+/// Find a file that was uploaded to the notebook with the given name, and
+/// return its URL. Returns `None` if there is no such file, or if the server
+/// can't list the files of a notebook.
+pub(crate) async fn find_uploaded_file(
+    client: &ApiClient,
+    notebook_id: Base64Uuid,
+    file_name: &str,
+) -> Result<Option<Url>> {
+    let files_url = client
+        .server
+        .join(&format!("api/notebooks/{notebook_id}/files"))?;
+    let response = client.client.get(files_url.clone()).send().await?;
+    if matches!(
+        response.status(),
+        StatusCode::NOT_FOUND | StatusCode::METHOD_NOT_ALLOWED
+    ) {
+        return Ok(None);
+    }
+
+    let files: Vec<FileSummary> = response
+        .error_for_status()
+        .context("Error listing the files of the notebook")?
+        .json()
+        .await?;
+    files
+        .into_iter()
+        .find(|file| file.file_name.as_deref() == Some(file_name))
+        .map(|file| file_url(&files_url, &file.file_id))
+        .transpose()
+}
+
+fn file_url(files_url: &Url, file_id: &str) -> Result<Url> {
+    Ok(Url::parse(&format!("{files_url}/{file_id}"))?)
+}
+
+/// Create a text cell with the given text, where the `link` part of it links
+/// to the attached file
+pub(crate) fn file_link_cell(prefix: &str, link: &str, url: &Url) -> Cell {
+    let start = char_count(prefix);
+
+    Cell::Text(
+        TextCell::builder()
+            .id(String::new())
+            .content(format!("{prefix}{link}"))
+            .formatting(vec![
+                AnnotationWithOffset::new(
+                    start,
+                    Annotation::StartLink {
+                        url: url.to_string(),
+                    },
+                ),
+                AnnotationWithOffset::new(start + char_count(link), Annotation::EndLink),
+            ])
+            .build(),
+    )
+}
@@ -10,6 +10,7 @@ use tokio::fs;
 use tracing::debug;
 use url::Url;
 
+use crate::redact::RedactPattern;
 use crate::MANIFEST;
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -18,6 +19,11 @@ pub struct Config {
     pub path: PathBuf,
 
     pub api_token: Option<String>,
+
+    /// Additional patterns for values that are redacted before they are
+    /// uploaded by `fp run`, `fp shell` and `fp logs`
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub redact: Vec<RedactPattern>,
 }
 
 impl Config {
@@ -37,6 +43,7 @@ impl Config {
                 Ok(Config {
                     path,
                     api_token: None,
+                    redact: Vec::new(),
                 })
             }
             Err(err) => Err(err.into()),
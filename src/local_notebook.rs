@@ -0,0 +1,422 @@
+//! Notebook files that `fp run --local` and `fp shell --local` write their
+//! output to, instead of sending it to Fiberplane.
+//!
+//! The file contains the same cells that would have been appended to a
+//! notebook, and `fp notebooks import` uploads them to a notebook later.
+//! Attached files are saved next to the notebook file and linked with a
+//! `file://` URL until they are imported.
+//!
+//! With `--local-format markdown`, the notebook is written as Markdown
+//! instead, which is easier to read. It can be imported as well, but not every
+//! kind of cell survives the conversion to Markdown and back.
+
+use crate::attachments::{file_link_cell, upload_file};
+use crate::spool::Operation;
+use anyhow::{anyhow, Context, Result};
+use clap::ValueEnum;
+use fiberplane::api_client::clients::ApiClient;
+use fiberplane::base64uuid::Base64Uuid;
+use fiberplane::markdown::notebook_to_markdown;
+use fiberplane::models::formatting::{Annotation, AnnotationWithOffset, Formatting};
+use fiberplane::models::notebooks::{
+    Cell, CodeCell, FrontMatter, HeadingCell, NewNotebook, Notebook, TextCell,
+};
+use fiberplane::models::timestamps::{NewTimeRange, TimeRange};
+use fiberplane::models::utils::char_count;
+use serde_json::json;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+use time::macros::format_description;
+use time::OffsetDateTime;
+use tokio::fs;
+use tracing::{info, warn};
+use url::Url;
+
+/// The notebook file is rewritten entirely, so changes are written at most
+/// this often while the command is running
+const WRITE_INTERVAL: Duration = Duration::from_secs(5);
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LocalFormat {
+    /// A notebook that can be uploaded with `fp notebooks import`
+    Json,
+
+    /// A Markdown document, which can be imported as well
+    Markdown,
+}
+
+impl LocalFormat {
+    fn extension(&self) -> &'static str {
+        match self {
+            Self::Json => "json",
+            Self::Markdown => "md",
+        }
+    }
+}
+
+/// A notebook that is kept in memory and written to a JSON or Markdown file
+pub struct LocalNotebook {
+    dir: PathBuf,
+    format: LocalFormat,
+    /// Name of the notebook file without its extension, which is also used as
+    /// prefix for the attached files
+    name: String,
+    title: String,
+    started_at: OffsetDateTime,
+    cells: Vec<Cell>,
+    /// Whether there are changes that were not written to the file yet
+    changed: bool,
+    last_write: Instant,
+}
+
+impl LocalNotebook {
+    /// Create a notebook file in the given directory. The name of the file
+    /// starts with `kind`, followed by the current time.
+    pub async fn create(
+        dir: &Path,
+        kind: &str,
+        title: String,
+        format: LocalFormat,
+    ) -> Result<Self> {
+        fs::create_dir_all(dir)
+            .await
+            .with_context(|| format!("Error creating directory: {}", dir.display()))?;
+        // Links to attached files need an absolute path
+        let dir = fs::canonicalize(dir).await?;
+
+        let started_at = OffsetDateTime::now_utc();
+        let timestamp = started_at.format(format_description!(
+            "[year][month][day]-[hour][minute][second]"
+        ))?;
+        let mut name = format!("{kind}-{timestamp}");
+        let mut suffix = 1;
+        while fs::metadata(dir.join(format!("{name}.{}", format.extension())))
+            .await
+            .is_ok()
+        {
+            suffix += 1;
+            name = format!("{kind}-{timestamp}-{suffix}");
+        }
+
+        let mut notebook = Self {
+            dir,
+            format,
+            name,
+            title,
+            started_at,
+            cells: Vec::new(),
+            changed: true,
+            last_write: Instant::now(),
+        };
+        notebook.write().await?;
+        Ok(notebook)
+    }
+
+    /// Path of the notebook file
+    pub fn path(&self) -> PathBuf {
+        self.dir
+            .join(format!("{}.{}", self.name, self.format.extension()))
+    }
+
+    /// Apply the operation to the cells. They are written to the file on the
+    /// next flush.
+    pub async fn push(&mut self, operation: Operation) -> Result<()> {
+        match operation {
+            Operation::AppendCells { cells } => self.cells.extend(cells),
+            Operation::AppendText {
+                cell_id,
+                content,
+                formatting,
+            } => {
+                let cell = self
+                    .cells
+                    .iter_mut()
+                    .find(|cell| cell.id() == cell_id)
+                    .ok_or_else(|| anyhow!("Cell not found: {}", cell_id))?;
+                append_text(cell, &content, formatting);
+            }
+            Operation::AttachFile {
+                file_name,
+                content,
+                link_prefix,
+                link_text,
+                ..
+            } => {
+                let path = self.dir.join(format!("{}-{}", self.name, file_name));
+                fs::write(&path, content)
+                    .await
+                    .with_context(|| format!("Error writing {}", path.display()))?;
+                let url = Url::from_file_path(&path)
+                    .map_err(|_| anyhow!("Invalid path: {}", path.display()))?;
+                self.cells
+                    .push(file_link_cell(&link_prefix, &link_text, &url));
+            }
+        }
+
+        self.changed = true;
+        Ok(())
+    }
+
+    /// Write the notebook file if the cells changed since it was last written,
+    /// and that was long enough ago
+    pub async fn flush(&mut self) -> Result<()> {
+        if !self.changed || self.last_write.elapsed() < WRITE_INTERVAL {
+            return Ok(());
+        }
+
+        self.write().await
+    }
+
+    async fn write(&mut self) -> Result<()> {
+        let time_range = TimeRange {
+            from: self.started_at.into(),
+            to: OffsetDateTime::now_utc().into(),
+        };
+        let content = match self.format {
+            LocalFormat::Json => {
+                let notebook = NewNotebook::builder()
+                    .title(self.title.clone())
+                    .time_range(NewTimeRange::Absolute(time_range))
+                    .cells(self.cells.clone())
+                    .front_matter(FrontMatter::new())
+                    .build();
+                serde_json::to_vec_pretty(&notebook)?
+            }
+            LocalFormat::Markdown => {
+                // The Markdown conversion takes a notebook as it is stored by
+                // Fiberplane, so the fields that only exist there are filled in
+                let notebook: Notebook = serde_json::from_value(json!({
+                    "id": self.name,
+                    "workspaceId": Base64Uuid::new(),
+                    "createdAt": time_range.from,
+                    "updatedAt": time_range.to,
+                    "timeRange": time_range,
+                    "title": self.title,
+                    "cells": self.cells,
+                    "selectedDataSources": {},
+                    "revision": 0,
+                    "visibility": "private",
+                    "readOnly": false,
+                    "createdBy": { "type": "unknown", "name": "" },
+                }))
+                .with_context(|| "Error converting the notebook to Markdown")?;
+                notebook_to_markdown(notebook).into_bytes()
+            }
+        };
+        let path = self.path();
+        fs::write(&path, content)
+            .await
+            .with_context(|| format!("Error writing notebook: {}", path.display()))?;
+
+        self.changed = false;
+        self.last_write = Instant::now();
+        Ok(())
+    }
+
+    pub async fn finish(&mut self) -> Result<()> {
+        if self.changed {
+            self.write().await?;
+        }
+        info!(
+            "Saved the notebook to {}. Use `fp notebooks import` to upload it.",
+            self.path().display()
+        );
+        Ok(())
+    }
+}
+
+/// Append text to the content of a cell, moving its formatting to the end of
+/// the existing content
+fn append_text(cell: &mut Cell, text: &str, formatting: Formatting) {
+    let (content, cell_formatting) = match cell {
+        Cell::Text(TextCell {
+            content,
+            formatting,
+            ..
+        })
+        | Cell::Heading(HeadingCell {
+            content,
+            formatting,
+            ..
+        }) => (content, Some(formatting)),
+        Cell::Code(CodeCell { content, .. }) => (content, None),
+        _ => {
+            warn!("Unable to append text to cell {}", cell.id());
+            return;
+        }
+    };
+
+    let offset = char_count(content);
+    content.push_str(text);
+    if let Some(cell_formatting) = cell_formatting {
+        cell_formatting.extend(formatting.into_iter().map(|annotation| {
+            AnnotationWithOffset::new(annotation.offset + offset, annotation.annotation)
+        }));
+    }
+}
+
+/// Upload the files that are attached to the local notebook at `notebook_path`
+/// and link to the uploaded files instead.
+///
+/// Only `file://` links to files next to the notebook file, whose name starts
+/// with the name of the notebook file, are uploaded. Other links are left
+/// as they are, so importing a notebook can't be used to upload arbitrary
+/// files.
+pub(crate) async fn upload_linked_files(
+    client: &ApiClient,
+    notebook_id: Base64Uuid,
+    notebook_path: &Path,
+    cells: &mut [Cell],
+) -> Result<()> {
+    let notebook_path = fs::canonicalize(notebook_path)
+        .await
+        .with_context(|| format!("Error reading {}", notebook_path.display()))?;
+    let dir = notebook_path
+        .parent()
+        .ok_or_else(|| anyhow!("Invalid path: {}", notebook_path.display()))?;
+    let prefix = notebook_path
+        .file_stem()
+        .map(|stem| format!("{}-", stem.to_string_lossy()))
+        .ok_or_else(|| anyhow!("Invalid path: {}", notebook_path.display()))?;
+
+    for cell in cells {
+        let formatting = match cell {
+            Cell::Text(TextCell { formatting, .. }) => formatting,
+            _ => continue,
+        };
+        for annotation in formatting {
+            let url = match &mut annotation.annotation {
+                Annotation::StartLink { url } if url.starts_with("file:") => url,
+                _ => continue,
+            };
+            let path = match Url::parse(url).ok().and_then(|url| url.to_file_path().ok()) {
+                Some(path) => path,
+                None => continue,
+            };
+            // Resolving symlinks makes sure the file really is in the
+            // directory of the notebook
+            let path = match fs::canonicalize(&path).await {
+                Ok(path) if is_attachment(&path, dir, &prefix) => path,
+                Ok(_) => {
+                    warn!(
+                        "{} is not attached to the notebook file, it is not uploaded",
+                        path.display()
+                    );
+                    continue;
+                }
+                Err(err) => {
+                    warn!(
+                        "Unable to read {}, it is not uploaded: {}",
+                        path.display(),
+                        err
+                    );
+                    continue;
+                }
+            };
+
+            let content = match fs::read(&path).await {
+                Ok(content) => content,
+                Err(err) => {
+                    warn!(
+                        "Unable to read {}, it is not uploaded: {}",
+                        path.display(),
+                        err
+                    );
+                    continue;
+                }
+            };
+            let file_name = path
+                .file_name()
+                .map(|name| name.to_string_lossy().to_string())
+                .unwrap_or_default();
+            let uploaded =
+                upload_file(client, notebook_id, &file_name, mime_type(&path), content).await?;
+            *url = uploaded.to_string();
+        }
+    }
+
+    Ok(())
+}
+
+/// Whether the file is attached to a notebook file in `dir`, which means that
+/// it is in the same directory and that its name starts with `prefix`. Both
+/// paths need to be canonical.
+fn is_attachment(path: &Path, dir: &Path, prefix: &str) -> bool {
+    path.parent() == Some(dir)
+        && path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .map_or(false, |name| name.starts_with(prefix))
+}
+
+fn mime_type(path: &Path) -> &'static str {
+    match path.extension().and_then(|ext| ext.to_str()) {
+        Some("cast") => crate::shell::asciicast::MIME_TYPE,
+        Some("txt") => "text/plain",
+        _ => "application/octet-stream",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn append_text_moves_formatting() {
+        let mut cell = Cell::Text(
+            TextCell::builder()
+                .id("1".to_string())
+                .content("$ ls".to_string())
+                .formatting(vec![AnnotationWithOffset::new(0, Annotation::StartCode)])
+                .build(),
+        );
+
+        append_text(
+            &mut cell,
+            "\nfile",
+            vec![AnnotationWithOffset::new(1, Annotation::StartBold)],
+        );
+
+        match cell {
+            Cell::Text(cell) => {
+                assert_eq!(cell.content, "$ ls\nfile");
+                assert_eq!(
+                    cell.formatting,
+                    vec![
+                        AnnotationWithOffset::new(0, Annotation::StartCode),
+                        AnnotationWithOffset::new(5, Annotation::StartBold),
+                    ]
+                );
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn only_attachments_next_to_the_notebook() {
+        let dir = Path::new("/notebooks");
+        let prefix = "run-20240101-120000-";
+
+        assert!(is_attachment(
+            Path::new("/notebooks/run-20240101-120000-output.txt"),
+            dir,
+            prefix
+        ));
+        assert!(!is_attachment(
+            Path::new("/notebooks/run-20240101-120000"),
+            dir,
+            prefix
+        ));
+        assert!(!is_attachment(
+            Path::new("/notebooks/shell-20240101-120000-recording.cast"),
+            dir,
+            prefix
+        ));
+        assert!(!is_attachment(
+            Path::new("/notebooks/other/run-20240101-120000-output.txt"),
+            dir,
+            prefix
+        ));
+        assert!(!is_attachment(Path::new("/etc/passwd"), dir, prefix));
+    }
+}
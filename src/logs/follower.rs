@@ -0,0 +1,197 @@
+use anyhow::{Context, Result};
+use std::fs::Metadata;
+use std::io::{ErrorKind, SeekFrom};
+use std::path::PathBuf;
+use tokio::fs::{self, File};
+use tokio::io::{AsyncReadExt, AsyncSeekExt};
+use tracing::debug;
+
+/// The maximum number of bytes that are read from the end of a file in order
+/// to find the last lines when we start following it
+const MAX_INITIAL_READ: u64 = 1024 * 1024;
+
+/// Follows a single file as it grows, similar to `tail -F`.
+///
+/// The file is re-opened when it is rotated (the path points to a different
+/// file than the one we have open) and read from the start again when it is
+/// truncated.
+pub struct FileFollower {
+    path: PathBuf,
+    file: Option<File>,
+    file_id: Option<FileId>,
+    position: u64,
+    /// Trailing data that didn't end with a newline yet
+    partial_line: Vec<u8>,
+}
+
+impl FileFollower {
+    pub fn new(path: PathBuf) -> Self {
+        Self {
+            path,
+            file: None,
+            file_id: None,
+            position: 0,
+            partial_line: Vec::new(),
+        }
+    }
+
+    pub fn path(&self) -> &PathBuf {
+        &self.path
+    }
+
+    /// Open the file and return (at most) its last `lines` lines. Any data
+    /// that is written after this will be returned by `read_new_lines`.
+    pub async fn open(&mut self, lines: usize) -> Result<String> {
+        let mut file = File::open(&self.path)
+            .await
+            .with_context(|| format!("Error opening log file: {}", self.path.display()))?;
+        let metadata = file.metadata().await?;
+
+        let start = metadata.len().saturating_sub(MAX_INITIAL_READ);
+        file.seek(SeekFrom::Start(start)).await?;
+        let mut data = Vec::new();
+        file.read_to_end(&mut data).await?;
+
+        self.position = start + data.len() as u64;
+        self.file_id = file_id(&metadata);
+        self.file = Some(file);
+
+        let data = self.complete_lines(data);
+        let data = String::from_utf8_lossy(&data);
+        let skip = data.lines().count().saturating_sub(lines);
+        Ok(data
+            .lines()
+            .skip(skip)
+            .flat_map(|line| [line, "\n"])
+            .collect())
+    }
+
+    /// Read all complete lines that were written since the last time the file
+    /// was read.
+    pub async fn read_new_lines(&mut self) -> Result<String> {
+        let metadata = match fs::metadata(&self.path).await {
+            Ok(metadata) => metadata,
+            // The file was moved away, but no new file was created yet
+            Err(err) if err.kind() == ErrorKind::NotFound => return Ok(String::new()),
+            Err(err) => return Err(err.into()),
+        };
+
+        let mut data = Vec::new();
+        if self.file.is_none() || file_id(&metadata) != self.file_id {
+            debug!("{} was rotated, reopening it", self.path.display());
+
+            // Make sure we don't lose anything that was written to the old
+            // file between the last read and the rotation. Nothing is written
+            // to it anymore, so its last line is complete even without a
+            // newline and must not be joined with the first line of the new
+            // file.
+            if self.file.is_some() {
+                let rest = self.read_to_end().await?;
+                data = self.complete_lines(rest);
+                let last_line = std::mem::take(&mut self.partial_line);
+                if !last_line.is_empty() {
+                    data.extend(last_line);
+                    data.push(b'\n');
+                }
+            }
+
+            self.file = Some(File::open(&self.path).await?);
+            self.file_id = file_id(&metadata);
+            self.position = 0;
+        } else if metadata.len() < self.position {
+            debug!(
+                "{} was truncated, reading from the start",
+                self.path.display()
+            );
+            self.position = 0;
+            self.partial_line.clear();
+        }
+
+        data.extend(self.read_to_end().await?);
+        let data = self.complete_lines(data);
+        Ok(String::from_utf8_lossy(&data).into_owned())
+    }
+
+    async fn read_to_end(&mut self) -> Result<Vec<u8>> {
+        let mut data = Vec::new();
+        if let Some(file) = &mut self.file {
+            file.seek(SeekFrom::Start(self.position)).await?;
+            file.read_to_end(&mut data).await?;
+            self.position += data.len() as u64;
+        }
+
+        Ok(data)
+    }
+
+    /// Prepends any partial line from the previous read and holds on to the
+    /// data after the last newline, so we only ever return complete lines.
+    fn complete_lines(&mut self, data: Vec<u8>) -> Vec<u8> {
+        let mut data = [std::mem::take(&mut self.partial_line), data].concat();
+        let complete_len = data
+            .iter()
+            .rposition(|byte| *byte == b'\n')
+            .map_or(0, |index| index + 1);
+        self.partial_line = data.split_off(complete_len);
+        data
+    }
+}
+
+type FileId = (u64, u64);
+
+#[cfg(unix)]
+fn file_id(metadata: &Metadata) -> Option<FileId> {
+    use std::os::unix::fs::MetadataExt;
+    Some((metadata.dev(), metadata.ino()))
+}
+
+// There is no stable equivalent of an inode on other platforms, so rotation
+// can only be detected as a truncation there
+#[cfg(not(unix))]
+fn file_id(_metadata: &Metadata) -> Option<FileId> {
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::AsyncWriteExt;
+
+    #[tokio::test]
+    async fn follow_file() {
+        let dir = std::env::temp_dir().join(format!("fp-follower-{}", std::process::id()));
+        fs::create_dir_all(&dir).await.unwrap();
+        let path = dir.join("app.log");
+        fs::write(&path, "one\ntwo\nthree\nfour").await.unwrap();
+
+        let mut follower = FileFollower::new(path.clone());
+        assert_eq!(follower.open(2).await.unwrap(), "two\nthree\n");
+
+        // Partial lines are held back until they are complete
+        let mut file = fs::OpenOptions::new()
+            .append(true)
+            .open(&path)
+            .await
+            .unwrap();
+        file.write_all(b" and more\nfive").await.unwrap();
+        assert_eq!(follower.read_new_lines().await.unwrap(), "four and more\n");
+
+        // Truncation
+        fs::write(&path, "six\n").await.unwrap();
+        assert_eq!(follower.read_new_lines().await.unwrap(), "six\n");
+
+        // Rotation, with a partial line left in the old file
+        let mut file = fs::OpenOptions::new()
+            .append(true)
+            .open(&path)
+            .await
+            .unwrap();
+        file.write_all(b"half").await.unwrap();
+        assert_eq!(follower.read_new_lines().await.unwrap(), "");
+        fs::rename(&path, dir.join("app.log.1")).await.unwrap();
+        assert_eq!(follower.read_new_lines().await.unwrap(), "");
+        fs::write(&path, "seven\n").await.unwrap();
+        assert_eq!(follower.read_new_lines().await.unwrap(), "half\nseven\n");
+
+        fs::remove_dir_all(&dir).await.unwrap();
+    }
+}
@@ -0,0 +1,613 @@
+use self::follower::FileFollower;
+use crate::config::api_client_configuration;
+use crate::fp_urls::NotebookUrlBuilder;
+use crate::interactive;
+use crate::output::output_json;
+use crate::redact::Redactor;
+use crate::run::cell_writer::log_cell;
+use crate::run::parse_logs::{last_record_start, parse_logs, ParseOptions};
+use anyhow::{anyhow, Context, Result};
+use clap::{Parser, ValueEnum, ValueHint};
+use fiberplane::api_client::clients::ApiClient;
+use fiberplane::api_client::notebook_cells_append;
+use fiberplane::base64uuid::Base64Uuid;
+use fiberplane::models::notebooks::{Cell, TextCell};
+use fiberplane::models::providers::ProviderEvent;
+use fiberplane::models::timestamps::Timestamp;
+use flate2::read::MultiGzDecoder;
+use regex::Regex;
+use std::io::Read;
+use std::num::NonZeroUsize;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use time::{format_description::well_known::Rfc3339, OffsetDateTime};
+use tokio::io::AsyncReadExt;
+use tokio::{fs, signal};
+use tracing::info;
+use url::Url;
+
+mod follower;
+
+/// The first two bytes of every gzip archive
+const GZIP_MAGIC_BYTES: [u8; 2] = [0x1f, 0x8b];
+
+/// How often followed files are checked for new data
+const POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+#[derive(Parser)]
+pub struct Arguments {
+    #[clap(subcommand)]
+    sub_command: SubCommand,
+}
+
+#[derive(Parser)]
+enum SubCommand {
+    /// Parse a log file and append the log records to a notebook
+    ///
+    /// Plain text files and gzip archives are supported. Use `-` to read the
+    /// logs from stdin, for example: `kubectl logs my-pod | fp logs ingest -`
+    Ingest(IngestArgs),
+
+    /// Send the last lines of one or more log files to a notebook, and
+    /// optionally keep following them as they grow
+    ///
+    /// When following, new records are appended to the notebook in batches.
+    /// Every batch gets its own log cell(s), so no single cell grows without
+    /// bounds. Rotated and truncated files are picked up automatically.
+    Tail(TailArgs),
+}
+
+pub async fn handle_command(args: Arguments) -> Result<()> {
+    match args.sub_command {
+        SubCommand::Ingest(args) => handle_ingest_command(args).await,
+        SubCommand::Tail(args) => handle_tail_command(args).await,
+    }
+}
+
+#[derive(ValueEnum, Clone)]
+enum LogsOutput {
+    /// Output the URLs of the created cells
+    Table,
+
+    /// Output the created cells as JSON encoded objects
+    Json,
+}
+
+#[derive(Parser)]
+struct IngestArgs {
+    /// The notebook to append the logs to
+    #[clap(long, short, env)]
+    notebook_id: Option<Base64Uuid>,
+
+    /// Path to the log file, or `-` to read from stdin
+    #[clap(value_hint = ValueHint::FilePath, default_value = "-")]
+    file: PathBuf,
+
+    /// Only include records that occurred at or after this time (RFC3339)
+    #[clap(long)]
+    from: Option<Timestamp>,
+
+    /// Only include records that occurred at or before this time (RFC3339)
+    #[clap(long)]
+    to: Option<Timestamp>,
+
+    /// Only include records whose body matches this regular expression
+    #[clap(long, value_name = "REGEX")]
+    grep: Option<Regex>,
+
+    /// Only include one out of every N records
+    #[clap(long, value_name = "N", default_value = "1")]
+    sample: NonZeroUsize,
+
+    /// Maximum size (in bytes) of the records in a single log cell. Once this
+    /// size is reached, the remaining records are written to a new cell.
+    #[clap(long, default_value = "1000000")]
+    max_cell_size: usize,
+
+    /// Regular expression that matches the first line of a log record
+    /// (you can specify multiple patterns).
+    ///
+    /// Lines that don't match any of the patterns are appended to the previous
+    /// record. See `fp run --help` for details.
+    #[clap(long, value_name = "REGEX")]
+    record_start: Vec<Regex>,
+
+    /// Don't redact secrets and personal information from the logs
+    #[clap(long)]
+    no_redact: bool,
+
+    /// Output type to display
+    #[clap(long, short, default_value = "table", value_enum)]
+    output: LogsOutput,
+
+    #[clap(from_global)]
+    workspace_id: Option<Base64Uuid>,
+
+    #[clap(from_global)]
+    base_url: Url,
+
+    #[clap(from_global)]
+    config: Option<PathBuf>,
+}
+
+#[derive(Parser)]
+struct TailArgs {
+    /// The notebook to append the logs to
+    #[clap(long, short, env)]
+    notebook_id: Option<Base64Uuid>,
+
+    /// Paths to the log files
+    #[clap(value_hint = ValueHint::FilePath, num_args = 1.., required = true)]
+    files: Vec<PathBuf>,
+
+    /// Number of lines to send from the end of each file
+    #[clap(long, short = 'l', default_value = "10")]
+    lines: usize,
+
+    /// Keep following the files and send new records as they are written
+    #[clap(long, short)]
+    follow: bool,
+
+    /// How often new records are sent to the notebook (in seconds)
+    #[clap(long, default_value = "5", value_parser = clap::value_parser!(u64).range(1..))]
+    interval: u64,
+
+    /// Only include records whose body matches this regular expression
+    #[clap(long, value_name = "REGEX")]
+    grep: Option<Regex>,
+
+    /// Maximum size (in bytes) of the records in a single log cell. Once this
+    /// size is reached, the remaining records are written to a new cell.
+    #[clap(long, default_value = "1000000")]
+    max_cell_size: usize,
+
+    /// Regular expression that matches the first line of a log record
+    /// (you can specify multiple patterns).
+    ///
+    /// Lines that don't match any of the patterns are appended to the previous
+    /// record. See `fp run --help` for details.
+    #[clap(long, value_name = "REGEX")]
+    record_start: Vec<Regex>,
+
+    /// Don't redact secrets and personal information from the logs
+    #[clap(long)]
+    no_redact: bool,
+
+    #[clap(from_global)]
+    workspace_id: Option<Base64Uuid>,
+
+    #[clap(from_global)]
+    base_url: Url,
+
+    #[clap(from_global)]
+    config: Option<PathBuf>,
+}
+
+async fn handle_ingest_command(args: IngestArgs) -> Result<()> {
+    let mut redactor = Redactor::load(args.config.clone(), args.no_redact).await?;
+    let client = api_client_configuration(args.config, args.base_url.clone()).await?;
+    let workspace_id = interactive::workspace_picker(&client, args.workspace_id).await?;
+    let notebook_id =
+        interactive::notebook_picker(&client, args.notebook_id, Some(workspace_id)).await?;
+
+    let content = read_logs(&args.file).await?;
+    let options = ParseOptions {
+        record_start_patterns: args.record_start,
+    };
+    let filter = LogFilter {
+        from: args.from,
+        to: args.to,
+        grep: args.grep,
+        sample: args.sample,
+    };
+    let mut events = filter.apply(parse_logs(&content, &options));
+    for event in &mut events {
+        redactor.redact_event(event);
+    }
+    if events.is_empty() {
+        info!("No log records found");
+        return Ok(());
+    }
+
+    let source = if args.file == Path::new("-") {
+        "stdin".to_string()
+    } else {
+        args.file.display().to_string()
+    };
+    let title = title_cell(format!("Logs from {source} ({} records)", events.len()))?;
+    append_cell(&client, notebook_id, title).await?;
+
+    // Each chunk is appended separately, so a large log file doesn't result
+    // in a single huge request
+    let mut cells = Vec::new();
+    for chunk in split_events(events, args.max_cell_size) {
+        cells.push(append_cell(&client, notebook_id, log_cell(&chunk)).await?);
+    }
+    redactor.report();
+
+    match args.output {
+        LogsOutput::Table => print_cell_urls(&args.base_url, workspace_id, notebook_id, &cells),
+        LogsOutput::Json => output_json(&cells),
+    }
+}
+
+async fn handle_tail_command(args: TailArgs) -> Result<()> {
+    let redactor = Redactor::load(args.config.clone(), args.no_redact).await?;
+    let client = api_client_configuration(args.config, args.base_url.clone()).await?;
+    let workspace_id = interactive::workspace_picker(&client, args.workspace_id).await?;
+    let notebook_id =
+        interactive::notebook_picker(&client, args.notebook_id, Some(workspace_id)).await?;
+
+    let mut followers = Vec::with_capacity(args.files.len());
+    let mut buffers = Vec::with_capacity(args.files.len());
+    for path in args.files {
+        let mut follower = FileFollower::new(path);
+        buffers.push(follower.open(args.lines).await?);
+        followers.push(follower);
+    }
+
+    let mut batch = LogBatch {
+        client: &client,
+        notebook_id,
+        options: ParseOptions {
+            record_start_patterns: args.record_start,
+        },
+        filter: LogFilter {
+            from: None,
+            to: None,
+            grep: args.grep,
+            sample: NonZeroUsize::new(1).unwrap(),
+        },
+        max_cell_size: args.max_cell_size,
+        redactor,
+        held_back: vec![0; buffers.len()],
+    };
+
+    let sources: Vec<_> = followers
+        .iter()
+        .map(|follower| follower.path().display().to_string())
+        .collect();
+    let sources = sources.join(", ");
+    append_cell(
+        &client,
+        notebook_id,
+        title_cell(format!("Logs from {sources}"))?,
+    )
+    .await?;
+
+    let cells = batch.flush(&mut buffers, !args.follow).await?;
+    print_cell_urls(&args.base_url, workspace_id, notebook_id, &cells)?;
+
+    if !args.follow {
+        return Ok(());
+    }
+
+    info!("Following {}. Press CTRL + C to stop.", sources);
+    let mut poll_interval = tokio::time::interval(POLL_INTERVAL);
+    let mut flush_interval = tokio::time::interval(Duration::from_secs(args.interval));
+    loop {
+        tokio::select! {
+            biased;
+            _ = signal::ctrl_c() => {
+                break;
+            }
+            _ = poll_interval.tick() => {
+                for (follower, buffer) in followers.iter_mut().zip(buffers.iter_mut()) {
+                    buffer.push_str(&follower.read_new_lines().await?);
+                }
+            }
+            _ = flush_interval.tick() => {
+                let cells = batch.flush(&mut buffers, false).await?;
+                print_cell_urls(&args.base_url, workspace_id, notebook_id, &cells)?;
+            }
+        }
+    }
+
+    // Send anything that was written since the last time we checked
+    for (follower, buffer) in followers.iter_mut().zip(buffers.iter_mut()) {
+        buffer.push_str(&follower.read_new_lines().await?);
+    }
+    let cells = batch.flush(&mut buffers, true).await?;
+    batch.redactor.report();
+    print_cell_urls(&args.base_url, workspace_id, notebook_id, &cells)
+}
+
+/// Turns buffered log lines into log cells in the notebook
+struct LogBatch<'a> {
+    client: &'a ApiClient,
+    notebook_id: Base64Uuid,
+    options: ParseOptions,
+    filter: LogFilter,
+    max_cell_size: usize,
+    redactor: Redactor,
+    /// Length of the record that was held back in each buffer by the
+    /// previous flush
+    held_back: Vec<usize>,
+}
+
+impl LogBatch<'_> {
+    /// Parse the records buffered for each file and append them to the
+    /// notebook. Unless `flush_all` is set, the last record of each buffer is
+    /// kept until the next record starts, since more of its lines could still
+    /// be written.
+    async fn flush(&mut self, buffers: &mut [String], flush_all: bool) -> Result<Vec<Cell>> {
+        let mut events = Vec::new();
+        for (buffer, held_back) in buffers.iter_mut().zip(self.held_back.iter_mut()) {
+            let records = take_complete_records(buffer, &self.options, held_back, flush_all);
+            if !records.is_empty() {
+                events.extend(self.filter.apply(parse_logs(&records, &self.options)));
+            }
+        }
+
+        // Interleave the records of the different files
+        events.sort_by_key(|event| event.time.0);
+        for event in &mut events {
+            self.redactor.redact_event(event);
+        }
+
+        let mut cells = Vec::new();
+        for chunk in split_events(events, self.max_cell_size) {
+            cells.push(append_cell(self.client, self.notebook_id, log_cell(&chunk)).await?);
+        }
+
+        Ok(cells)
+    }
+}
+
+/// Remove the records from the buffer that can't get more lines, and return
+/// them. The last record is held back, unless nothing was added to the buffer
+/// since the previous call (so the file has been idle for a whole flush
+/// interval) or `flush_all` is set.
+fn take_complete_records(
+    buffer: &mut String,
+    options: &ParseOptions,
+    held_back: &mut usize,
+    flush_all: bool,
+) -> String {
+    let split_at = if flush_all || buffer.len() == *held_back {
+        buffer.len()
+    } else {
+        last_record_start(buffer, options).unwrap_or(buffer.len())
+    };
+
+    let rest = buffer.split_off(split_at);
+    let records = std::mem::replace(buffer, rest);
+    *held_back = buffer.len();
+    records
+}
+
+fn title_cell(description: String) -> Result<Cell> {
+    let timestamp = OffsetDateTime::now_utc().format(&Rfc3339)?;
+    Ok(Cell::Text(
+        TextCell::builder()
+            .id(String::new())
+            .content(format!("{timestamp}\n{description}"))
+            .build(),
+    ))
+}
+
+fn print_cell_urls(
+    base_url: &Url,
+    workspace_id: Base64Uuid,
+    notebook_id: Base64Uuid,
+    cells: &[Cell],
+) -> Result<()> {
+    for cell in cells {
+        let url = NotebookUrlBuilder::new(workspace_id, notebook_id)
+            .base_url(base_url.clone())
+            .cell_id(cell.id())
+            .url()?;
+        info!("Created cell: {}", url);
+    }
+
+    Ok(())
+}
+
+async fn append_cell(client: &ApiClient, notebook_id: Base64Uuid, cell: Cell) -> Result<Cell> {
+    notebook_cells_append(client, notebook_id, None, None, vec![cell])
+        .await
+        .context("Error appending cell to notebook")?
+        .pop()
+        .ok_or_else(|| anyhow!("No cells returned"))
+}
+
+async fn read_logs(path: &Path) -> Result<String> {
+    let bytes = if path == Path::new("-") {
+        let mut bytes = Vec::new();
+        tokio::io::stdin()
+            .read_to_end(&mut bytes)
+            .await
+            .context("Error reading logs from stdin")?;
+        bytes
+    } else {
+        fs::read(path)
+            .await
+            .with_context(|| format!("Error reading log file: {}", path.display()))?
+    };
+
+    decode_logs(bytes)
+}
+
+/// Decompress the logs if they are a gzip archive (which is detected by
+/// looking at the magic bytes rather than the file extension, so this also
+/// works for stdin) and convert them to a string.
+fn decode_logs(bytes: Vec<u8>) -> Result<String> {
+    let bytes = if bytes.starts_with(&GZIP_MAGIC_BYTES) {
+        let mut decompressed = Vec::new();
+        MultiGzDecoder::new(bytes.as_slice())
+            .read_to_end(&mut decompressed)
+            .context("Error decompressing gzip archive")?;
+        decompressed
+    } else {
+        bytes
+    };
+
+    Ok(String::from_utf8_lossy(&bytes).into_owned())
+}
+
+/// Filters that are applied to the parsed log records before they are sent
+/// to the notebook.
+pub(crate) struct LogFilter {
+    pub from: Option<Timestamp>,
+    pub to: Option<Timestamp>,
+    pub grep: Option<Regex>,
+    pub sample: NonZeroUsize,
+}
+
+impl LogFilter {
+    fn matches(&self, event: &ProviderEvent) -> bool {
+        self.from
+            .as_ref()
+            .map_or(true, |from| event.time.0 >= from.0)
+            && self.to.as_ref().map_or(true, |to| event.time.0 <= to.0)
+            && self
+                .grep
+                .as_ref()
+                .map_or(true, |grep| grep.is_match(&event.title))
+    }
+
+    pub fn apply(&self, events: Vec<ProviderEvent>) -> Vec<ProviderEvent> {
+        events
+            .into_iter()
+            .filter(|event| self.matches(event))
+            .step_by(self.sample.get())
+            .collect()
+    }
+}
+
+/// Split the records into chunks that each serialize to at most `max_size`
+/// bytes. A single record that is larger than `max_size` gets a chunk of its
+/// own.
+pub(crate) fn split_events(events: Vec<ProviderEvent>, max_size: usize) -> Vec<Vec<ProviderEvent>> {
+    let mut chunks = Vec::new();
+    let mut chunk = Vec::new();
+    let mut chunk_size = 0;
+
+    for event in events {
+        // Add one byte for the comma that separates the records
+        let size = serde_json::to_vec(&event)
+            .map(|json| json.len() + 1)
+            .unwrap_or_default();
+        if !chunk.is_empty() && chunk_size + size > max_size {
+            chunks.push(std::mem::take(&mut chunk));
+            chunk_size = 0;
+        }
+
+        chunk_size += size;
+        chunk.push(event);
+    }
+
+    if !chunk.is_empty() {
+        chunks.push(chunk);
+    }
+
+    chunks
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::write::GzEncoder;
+    use flate2::Compression;
+    use std::io::Write;
+
+    fn event(time: &str, title: &str) -> ProviderEvent {
+        ProviderEvent::builder()
+            .time(OffsetDateTime::parse(time, &Rfc3339).unwrap().into())
+            .title(title.to_string())
+            .build()
+    }
+
+    #[test]
+    fn decode_gzip() {
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(b"hello\nworld\n").unwrap();
+        let archive = encoder.finish().unwrap();
+
+        assert_eq!(decode_logs(archive).unwrap(), "hello\nworld\n");
+        assert_eq!(decode_logs(b"plain".to_vec()).unwrap(), "plain");
+    }
+
+    #[test]
+    fn filter_records() {
+        let events = vec![
+            event("2022-07-11T10:00:00Z", "starting"),
+            event("2022-07-11T10:01:00Z", "error: one"),
+            event("2022-07-11T10:02:00Z", "error: two"),
+            event("2022-07-11T10:03:00Z", "error: three"),
+            event("2022-07-11T10:04:00Z", "error: four"),
+        ];
+        let filter = LogFilter {
+            from: Some(
+                OffsetDateTime::parse("2022-07-11T10:01:00Z", &Rfc3339)
+                    .unwrap()
+                    .into(),
+            ),
+            to: Some(
+                OffsetDateTime::parse("2022-07-11T10:03:00Z", &Rfc3339)
+                    .unwrap()
+                    .into(),
+            ),
+            grep: Some(Regex::new("^error").unwrap()),
+            sample: NonZeroUsize::new(2).unwrap(),
+        };
+
+        let titles: Vec<_> = filter
+            .apply(events)
+            .into_iter()
+            .map(|event| event.title)
+            .collect();
+        assert_eq!(titles, vec!["error: one", "error: three"]);
+    }
+
+    #[test]
+    fn split_records() {
+        let events: Vec<_> = (0..10)
+            .map(|i| event("2022-07-11T10:00:00Z", &format!("record {i}")))
+            .collect();
+        let size = serde_json::to_vec(&events[0]).unwrap().len() + 1;
+
+        let chunks = split_events(events.clone(), size * 4);
+        assert_eq!(
+            chunks.iter().map(Vec::len).collect::<Vec<_>>(),
+            vec![4, 4, 2]
+        );
+
+        // Records that exceed the limit by themselves still get a cell
+        let chunks = split_events(events, 1);
+        assert_eq!(chunks.len(), 10);
+    }
+
+    #[test]
+    fn holds_back_records_that_can_continue() {
+        let options = ParseOptions::default();
+        let mut held_back = 0;
+        let mut buffer = "2022-07-11T10:00:00Z INFO starting\n\
+            2022-07-11T10:00:01Z ERROR java.lang.IllegalStateException: oops\n\
+            \tat com.example.Foo.bar(Foo.java:12)\n"
+            .to_string();
+
+        // The stack trace could still continue, so it is held back
+        let records = take_complete_records(&mut buffer, &options, &mut held_back, false);
+        assert_eq!(records, "2022-07-11T10:00:00Z INFO starting\n");
+
+        // The rest of the trace is written before the next flush
+        buffer.push_str(
+            "\tat com.example.Main.main(Main.java:5)\n\
+            2022-07-11T10:00:02Z INFO recovered\n",
+        );
+        let records = take_complete_records(&mut buffer, &options, &mut held_back, false);
+        let events = parse_logs(&records, &options);
+        assert_eq!(events.len(), 1);
+        assert!(events[0].title.starts_with("2022-07-11T10:00:01Z ERROR"));
+        assert!(events[0]
+            .title
+            .ends_with("at com.example.Main.main(Main.java:5)"));
+        assert_eq!(buffer, "2022-07-11T10:00:02Z INFO recovered\n");
+
+        // Nothing was written since the last flush
+        let records = take_complete_records(&mut buffer, &options, &mut held_back, false);
+        assert_eq!(records, "2022-07-11T10:00:02Z INFO recovered\n");
+        assert!(buffer.is_empty());
+    }
+}
@@ -27,6 +27,8 @@ use tracing_subscriber::fmt::format;
 use update::retrieve_latest_version;
 use url::Url;
 
+mod ansi;
+mod attachments;
 mod auth;
 mod config;
 mod daemons;
@@ -36,13 +38,17 @@ mod experiments;
 mod fp_urls;
 mod interactive;
 mod labels;
+mod local_notebook;
+mod logs;
 mod manifest;
 mod notebooks;
 mod output;
 mod providers;
+mod redact;
 mod run;
 mod shell;
 mod snippets;
+mod spool;
 mod templates;
 mod tokens;
 mod triggers;
@@ -124,6 +130,12 @@ enum SubCommand {
     #[clap(alias = "label")]
     Labels(labels::Arguments),
 
+    /// Send logs to a notebook
+    ///
+    /// Logs are parsed the same way as the output of `fp run`.
+    #[clap(alias = "log")]
+    Logs(logs::Arguments),
+
     /// Create a new notebook and open it in the browser.
     ///
     /// If you need access to the json use the `notebook create` command.
@@ -157,6 +169,13 @@ enum SubCommand {
     #[clap(trailing_var_arg = true)]
     Run(run::Arguments),
 
+    /// Upload output that `fp run` and `fp shell` could not send
+    ///
+    /// Output is buffered locally when the connection to Fiberplane is lost.
+    /// If `fp` is stopped before it could send everything, this uploads the
+    /// rest.
+    Sync(spool::Arguments),
+
     /// Interact with templates
     ///
     /// Templates allow you to create notebooks based on jsonnet.
@@ -283,6 +302,7 @@ async fn main() {
         Login => auth::handle_login_command(args).await,
         Logout => auth::handle_logout_command(args).await,
         Labels(args) => labels::handle_command(args).await,
+        Logs(args) => logs::handle_command(args).await,
         New(args) => handle_new_command(args).await,
         Notebooks(args) => notebooks::handle_command(args).await,
         Providers(args) => providers::handle_command(args).await,
@@ -290,6 +310,7 @@ async fn main() {
         Run(args) => run::handle_command(args).await,
         Shell(args) => shell::handle_command(args).await,
         Snippets(args) => snippets::handle_command(args).await,
+        Sync(args) => spool::handle_command(args).await,
         Views(args) => views::handle_command(args).await,
         Templates(args) => templates::handle_command(args).await,
         Triggers(args) => triggers::handle_command(args).await,
@@ -449,7 +470,7 @@ pub async fn background_version_check() -> Result<Option<String>> {
     }
 }
 
-#[derive(Clone)]
+#[derive(Clone, Debug)]
 pub struct KeyValueArgument {
     pub key: String,
     pub value: String,
@@ -2,6 +2,7 @@ use crate::interactive::{
     self, notebook_picker, snippet_picker, view_picker, workspace_picker,
     workspace_picker_with_prompt,
 };
+use crate::local_notebook::upload_linked_files;
 use crate::output::{output_details, output_json, output_list, GenericKeyValue};
 use crate::KeyValueArgument;
 use crate::{config::api_client_configuration, fp_urls::NotebookUrlBuilder};
@@ -27,6 +28,7 @@ use std::collections::HashMap;
 use std::path::PathBuf;
 use time::format_description::well_known::Rfc3339;
 use time::{ext::NumericalDuration, OffsetDateTime};
+use tokio::fs;
 use tracing::info;
 use url::Url;
 use webbrowser::open;
@@ -71,6 +73,22 @@ pub enum SubCommand {
     #[clap(alias = "append")]
     AppendCell(AppendCellArgs),
 
+    /// Append the cells of a notebook file to a notebook
+    ///
+    /// The file can be a JSON notebook written by `fp run --local` or
+    /// `fp shell --local`, or a Markdown file. Files that are attached to the
+    /// notebook file are uploaded as well.
+    Import(ImportArgs),
+
+    /// Run the queries of the provider cells in the notebook with local
+    /// providers
+    ///
+    /// The results are appended to the end of the notebook below a heading
+    /// with the time of the run, or shown in the terminal with `--output
+    /// print`. Results of earlier runs are not removed, so running the queries
+    /// again adds another section of results.
+    RunQueries(crate::providers::run_queries::Arguments),
+
     /// Interact with front matter
     ///
     /// Front matter adds additional metadata to notebooks.
@@ -90,6 +108,8 @@ pub async fn handle_command(args: Arguments) -> Result<()> {
         Open(args) => handle_open_command(args).await,
         Delete(args) => handle_delete_command(args).await,
         AppendCell(args) => handle_append_cell_command(args).await,
+        Import(args) => handle_import_command(args).await,
+        RunQueries(args) => crate::providers::run_queries::handle_command(args).await,
         FrontMatter(args) => handle_front_matter_command(args).await,
     }
 }
@@ -578,6 +598,60 @@ async fn handle_append_cell_command(args: AppendCellArgs) -> Result<()> {
     }
 }
 
+#[derive(Parser)]
+pub struct ImportArgs {
+    /// JSON or Markdown file to import
+    #[clap(value_hint = ValueHint::FilePath)]
+    file: PathBuf,
+
+    /// ID of the notebook to append the cells to
+    #[clap(long, short, env)]
+    notebook_id: Option<Base64Uuid>,
+
+    #[clap(from_global)]
+    workspace_id: Option<Base64Uuid>,
+
+    #[clap(from_global)]
+    base_url: Url,
+
+    #[clap(from_global)]
+    config: Option<PathBuf>,
+}
+
+async fn handle_import_command(args: ImportArgs) -> Result<()> {
+    let content = fs::read_to_string(&args.file)
+        .await
+        .with_context(|| format!("Error reading {}", args.file.display()))?;
+    let notebook: NewNotebook = if args.file.extension().map_or(false, |ext| ext == "md") {
+        let notebook = markdown_to_notebook(&content);
+        let notebook = serde_json::to_string(&notebook)?;
+        serde_json::from_str(&notebook).with_context(|| "Error parsing notebook struct (there is a mismatch between the API client model and the fiberplane notebooks model)")?
+    } else {
+        serde_json::from_str(&content)
+            .with_context(|| format!("Error parsing notebook: {}", args.file.display()))?
+    };
+
+    let client = api_client_configuration(args.config, args.base_url.clone()).await?;
+    let workspace_id = workspace_picker(&client, args.workspace_id).await?;
+    let notebook_id = notebook_picker(&client, args.notebook_id, Some(workspace_id)).await?;
+
+    // The server assigns new IDs, so the file can be imported more than once
+    let mut cells: Vec<Cell> = notebook
+        .cells
+        .into_iter()
+        .map(|cell| cell.with_id(""))
+        .collect();
+    upload_linked_files(&client, notebook_id, &args.file, &mut cells).await?;
+    let count = cells.len();
+    notebook_cells_append(&client, notebook_id, None, None, cells).await?;
+
+    let url = NotebookUrlBuilder::new(workspace_id, notebook_id)
+        .base_url(args.base_url)
+        .url()?;
+    info!("Imported {} cells into the notebook: {}", count, url);
+    Ok(())
+}
+
 #[derive(Parser)]
 pub struct InsertSnippetArgs {
     /// The workspace to get the snippet from
@@ -0,0 +1,403 @@
+//! Benchmarks for providers: `fp providers bench` instantiates a provider and
+//! invokes it with the same query repeatedly.
+//!
+//! Every build is benchmarked in a worker process of its own, so builds that
+//! are compared don't affect each other. Memory is measured as the growth of
+//! the peak resident memory of the worker while the provider is loaded and
+//! invoked, which is only available on Linux.
+
+use super::cassette::{Cassette, CassetteArguments};
+use super::conformance::process_memory;
+use super::query::{fields_from_value, read_json_or_toml};
+use super::render::render_table;
+use super::{encode_query_fields, load_runtime, parse_config, worker};
+use crate::output::output_json;
+use crate::KeyValueArgument;
+use anyhow::{anyhow, bail, Context, Result};
+use clap::{Parser, ValueEnum, ValueHint};
+use fiberplane::provider_runtime::spec::types::{Blob, ProviderConfig, ProviderRequest};
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+use tracing::info;
+
+#[derive(Parser)]
+pub struct Arguments {
+    /// Path to the provider WASM file
+    #[clap(value_hint = ValueHint::FilePath)]
+    provider_path: PathBuf,
+
+    /// Another build of the provider to compare with
+    #[clap(long, value_hint = ValueHint::FilePath)]
+    compare: Option<PathBuf>,
+
+    /// Type of query to invoke the provider with
+    #[clap(long, short = 't')]
+    query_type: String,
+
+    /// Query field to send to the provider (you can specify multiple fields)
+    #[clap(long = "query", short = 'q', value_name = "NAME=VALUE")]
+    query: Vec<KeyValueArgument>,
+
+    /// JSON or TOML file with the query fields to send to the provider
+    #[clap(long, value_hint = ValueHint::FilePath)]
+    query_file: Option<PathBuf>,
+
+    /// JSON encoded config that will be sent to the provider
+    #[clap(long, short = 'c', value_name = "JSON")]
+    provider_config: Option<String>,
+
+    /// JSON or TOML file with the config that will be sent to the provider
+    #[clap(long, value_hint = ValueHint::FilePath, conflicts_with = "provider_config")]
+    config_file: Option<PathBuf>,
+
+    /// Number of times to invoke the provider
+    #[clap(long, short = 'n', default_value = "50")]
+    iterations: usize,
+
+    /// Number of invocations to run before measuring
+    #[clap(long, default_value = "3")]
+    warmup: usize,
+
+    /// Number of times to instantiate the provider
+    #[clap(long, default_value = "5")]
+    instantiations: usize,
+
+    #[clap(flatten)]
+    cassette: CassetteArguments,
+
+    /// Output of the results
+    #[clap(long, short, default_value = "table", value_enum)]
+    output: BenchOutput,
+}
+
+#[derive(ValueEnum, Clone, PartialEq)]
+enum BenchOutput {
+    /// Output the results as a table
+    Table,
+
+    /// Output the results as JSON
+    Json,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+struct BenchResult {
+    provider: String,
+    /// Median time to instantiate the provider
+    instantiation_ms: f64,
+    latency_ms: Percentiles,
+    /// Growth of the peak resident memory of the worker while loading and
+    /// invoking the provider
+    peak_memory_bytes: Option<u64>,
+    result_bytes: usize,
+    /// Number of measured invocations that returned an error. Errors during
+    /// the warmup are not counted, like their latencies.
+    errors: usize,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+struct Percentiles {
+    min: f64,
+    p50: f64,
+    p90: f64,
+    p99: f64,
+    max: f64,
+    mean: f64,
+}
+
+impl Percentiles {
+    fn new(durations: &[Duration]) -> Self {
+        let mut millis: Vec<f64> = durations.iter().map(|d| d.as_secs_f64() * 1000.0).collect();
+        millis.sort_by(|a, b| a.total_cmp(b));
+
+        // Nearest-rank percentiles
+        let percentile = |p: f64| {
+            if millis.is_empty() {
+                return 0.0;
+            }
+            let rank = (p / 100.0 * millis.len() as f64).ceil() as usize;
+            millis[rank.clamp(1, millis.len()) - 1]
+        };
+        Self {
+            min: percentile(0.0),
+            p50: percentile(50.0),
+            p90: percentile(90.0),
+            p99: percentile(99.0),
+            max: percentile(100.0),
+            mean: if millis.is_empty() {
+                0.0
+            } else {
+                millis.iter().sum::<f64>() / millis.len() as f64
+            },
+        }
+    }
+}
+
+pub async fn handle_command(args: Arguments) -> Result<()> {
+    if args.iterations == 0 || args.instantiations == 0 {
+        bail!("--iterations and --instantiations need to be at least 1");
+    }
+
+    let config = match (&args.provider_config, &args.config_file) {
+        (_, Some(path)) => serde_json::from_value(read_json_or_toml(path)?)
+            .context("unable to deserialize config")?,
+        (config, None) => parse_config(config.as_deref().unwrap_or("{}"))
+            .context("unable to deserialize config")?,
+    };
+    let mut fields = match &args.query_file {
+        Some(path) => fields_from_value(&read_json_or_toml(path)?)?,
+        None => Vec::new(),
+    };
+    fields.extend(
+        args.query
+            .iter()
+            .map(|kv| (kv.key.clone(), kv.value.clone())),
+    );
+
+    let mut builds = vec![&args.provider_path];
+    builds.extend(&args.compare);
+
+    if let Some(task) = worker::task() {
+        let path = task
+            .parse::<usize>()
+            .ok()
+            .and_then(|index| builds.get(index))
+            .ok_or_else(|| anyhow!("invalid worker task: {}", task))?;
+        let result = bench(&args, path, &config, &fields).await?;
+        println!("{}", serde_json::to_string(&result)?);
+        return Ok(());
+    }
+
+    let cassette = args.cassette.start().await?;
+    if let Some(cassette) = &cassette {
+        cassette.check_config(&serde_json::to_value(&config)?)?;
+    }
+    let mut results = Vec::with_capacity(builds.len());
+    for index in 0..builds.len() {
+        results.push(bench_in_worker(index, cassette.as_ref()).await?);
+    }
+    if let Some(cassette) = cassette {
+        cassette.finish()?;
+    }
+
+    match args.output {
+        BenchOutput::Table => {
+            let (columns, rows) = results_table(&results);
+            print!("{}", render_table(&columns, &rows));
+            Ok(())
+        }
+        BenchOutput::Json => output_json(&results),
+    }
+}
+
+/// Benchmark one of the builds in a worker process
+async fn bench_in_worker(index: usize, cassette: Option<&Cassette>) -> Result<BenchResult> {
+    let output = worker::spawn(&index.to_string(), cassette)?
+        .wait_with_output()
+        .await?;
+    if !output.status.success() {
+        bail!("benchmark did not finish successfully ({})", output.status);
+    }
+    serde_json::from_slice(&output.stdout).context("invalid result from worker")
+}
+
+async fn bench(
+    args: &Arguments,
+    path: &Path,
+    config: &ProviderConfig,
+    fields: &[(String, String)],
+) -> Result<BenchResult> {
+    info!("Benchmarking {}", path.display());
+    let baseline_memory = process_memory("VmRSS");
+
+    let mut instantiations = Vec::with_capacity(args.instantiations);
+    let mut runtime = None;
+    for _ in 0..args.instantiations {
+        let started = Instant::now();
+        runtime = Some(load_runtime(path)?);
+        instantiations.push(started.elapsed());
+    }
+    let runtime = runtime.expect("provider was instantiated");
+
+    let query_data = encode_query_fields(&runtime, config, &args.query_type, fields).await?;
+    let request = ProviderRequest::builder()
+        .query_type(args.query_type.clone())
+        .query_data(
+            Blob::builder()
+                .data(query_data.into_bytes())
+                .mime_type("application/x-www-form-urlencoded".to_string())
+                .build(),
+        )
+        .config(config.clone())
+        .build();
+
+    let mut latencies = Vec::with_capacity(args.iterations);
+    let mut result_bytes = 0;
+    let mut errors = 0;
+    let mut last_error = None;
+    for iteration in 0..args.warmup + args.iterations {
+        let started = Instant::now();
+        let result = runtime.invoke2(request.clone()).await;
+        let elapsed = started.elapsed();
+
+        let measured = iteration >= args.warmup;
+        match result {
+            Ok(Ok(blob)) => result_bytes = result_bytes.max(blob.data.len()),
+            Ok(Err(err)) => {
+                if measured {
+                    errors += 1;
+                }
+                last_error = Some(err);
+            }
+            Err(e) => bail!("unable to invoke provider: {:?}", e),
+        }
+        if measured {
+            latencies.push(elapsed);
+        }
+    }
+
+    // The latencies are meaningless if the query can't be answered at all
+    if let Some(err) = last_error.filter(|_| errors == args.iterations) {
+        bail!("Provider failed on every invocation: {:?}", err);
+    }
+
+    Ok(BenchResult {
+        provider: path.display().to_string(),
+        instantiation_ms: Percentiles::new(&instantiations).p50,
+        latency_ms: Percentiles::new(&latencies),
+        peak_memory_bytes: process_memory("VmHWM")
+            .zip(baseline_memory)
+            .map(|(peak, baseline)| peak.saturating_sub(baseline)),
+        result_bytes,
+        errors,
+    })
+}
+
+/// Name of a metric, how to get it from the results, and how to format it
+type Metric = (
+    &'static str,
+    fn(&BenchResult) -> Option<f64>,
+    fn(f64) -> String,
+);
+
+/// Table with a row per metric and a column per build, and the relative
+/// change when two builds are compared
+fn results_table(results: &[BenchResult]) -> (Vec<String>, Vec<Vec<String>>) {
+    let mut columns = vec!["metric".to_string()];
+    columns.extend(results.iter().map(|result| result.provider.clone()));
+    if results.len() == 2 {
+        columns.push("change".to_string());
+    }
+
+    let metrics: Vec<Metric> = vec![
+        ("instantiation", |r| Some(r.instantiation_ms), format_ms),
+        ("latency min", |r| Some(r.latency_ms.min), format_ms),
+        ("latency p50", |r| Some(r.latency_ms.p50), format_ms),
+        ("latency p90", |r| Some(r.latency_ms.p90), format_ms),
+        ("latency p99", |r| Some(r.latency_ms.p99), format_ms),
+        ("latency max", |r| Some(r.latency_ms.max), format_ms),
+        ("latency mean", |r| Some(r.latency_ms.mean), format_ms),
+        (
+            "peak memory",
+            |r| r.peak_memory_bytes.map(|b| b as f64),
+            format_bytes,
+        ),
+        ("result size", |r| Some(r.result_bytes as f64), format_bytes),
+        ("errors", |r| Some(r.errors as f64), |n| n.to_string()),
+    ];
+
+    let rows = metrics
+        .into_iter()
+        .map(|(name, metric, format)| {
+            let values: Vec<Option<f64>> = results.iter().map(metric).collect();
+            let mut row = vec![name.to_string()];
+            row.extend(
+                values
+                    .iter()
+                    .map(|value| value.map(format).unwrap_or_default()),
+            );
+            if let [Some(before), Some(after)] = values[..] {
+                row.push(format_change(before, after));
+            } else if values.len() == 2 {
+                row.push(String::new());
+            }
+            row
+        })
+        .collect();
+    (columns, rows)
+}
+
+fn format_ms(ms: f64) -> String {
+    format!("{ms:.2} ms")
+}
+
+fn format_bytes(bytes: f64) -> String {
+    if bytes >= 1024.0 * 1024.0 {
+        format!("{:.1} MB", bytes / 1024.0 / 1024.0)
+    } else if bytes >= 1024.0 {
+        format!("{:.1} kB", bytes / 1024.0)
+    } else {
+        format!("{bytes} B")
+    }
+}
+
+fn format_change(before: f64, after: f64) -> String {
+    if before == after {
+        "0%".to_string()
+    } else if before == 0.0 {
+        "n/a".to_string()
+    } else {
+        format!("{:+.1}%", (after - before) / before * 100.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn calculates_percentiles() {
+        let durations: Vec<Duration> = (1..=100).map(Duration::from_millis).collect();
+        let percentiles = Percentiles::new(&durations);
+        assert_eq!(percentiles.min, 1.0);
+        assert_eq!(percentiles.p50, 50.0);
+        assert_eq!(percentiles.p90, 90.0);
+        assert_eq!(percentiles.p99, 99.0);
+        assert_eq!(percentiles.max, 100.0);
+        assert_eq!(percentiles.mean, 50.5);
+
+        assert_eq!(Percentiles::new(&[Duration::from_millis(7)]).p99, 7.0);
+    }
+
+    #[test]
+    fn compares_builds() {
+        let result = |provider: &str, p50: f64, memory: Option<u64>| BenchResult {
+            provider: provider.to_string(),
+            instantiation_ms: 12.5,
+            latency_ms: Percentiles {
+                min: 1.0,
+                p50,
+                p90: 3.0,
+                p99: 4.0,
+                max: 5.0,
+                mean: 2.5,
+            },
+            peak_memory_bytes: memory,
+            result_bytes: 2048,
+            errors: 0,
+        };
+        let (columns, rows) = results_table(&[
+            result("main.wasm", 2.0, Some(3 * 1024 * 1024)),
+            result("branch.wasm", 1.5, None),
+        ]);
+
+        assert_eq!(
+            columns,
+            vec!["metric", "main.wasm", "branch.wasm", "change"]
+        );
+        assert_eq!(rows[0], vec!["instantiation", "12.50 ms", "12.50 ms", "0%"]);
+        assert_eq!(rows[2], vec!["latency p50", "2.00 ms", "1.50 ms", "-25.0%"]);
+        assert_eq!(rows[7], vec!["peak memory", "3.0 MB", "", ""]);
+        assert_eq!(rows[8], vec!["result size", "2.0 kB", "2.0 kB", "0%"]);
+    }
+}
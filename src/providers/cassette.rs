@@ -0,0 +1,514 @@
+//! Recording and replaying the HTTP requests of providers.
+//!
+//! Providers make HTTP requests through a host function of the runtime, which
+//! we can't hook into. To capture these, a proxy is started on localhost and
+//! the provider runs in a worker process that is pointed at the proxy with the
+//! `HTTP_PROXY` environment variable. When recording, the proxy forwards the
+//! requests and saves every request and its response to a cassette. When
+//! replaying, the responses are served from the cassette and nothing is sent
+//! over the network.
+//!
+//! Only plain HTTP requests can be captured: HTTPS requests are tunnelled
+//! through the proxy, so they are rejected and the command fails. Credentials
+//! in headers and query strings are not saved.
+
+use anyhow::{anyhow, bail, Context, Result};
+use clap::{Parser, ValueHint};
+use hyper::header::{self, HeaderMap, HeaderName, HeaderValue};
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Method, Request, Response, Server, StatusCode};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::BTreeMap;
+use std::convert::Infallible;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use tokio::process::Command;
+use tokio::task::JoinHandle;
+use tracing::{info, warn};
+
+/// Headers that are not saved, because they may contain credentials
+const REDACTED_HEADERS: [&str; 4] = ["authorization", "cookie", "set-cookie", "x-api-key"];
+
+/// Query parameters whose values are not saved, if their name contains one of
+/// these, such as `api_key` or `access_token`
+const REDACTED_PARAMS: [&str; 6] = ["auth", "key", "password", "secret", "signature", "token"];
+
+const REDACTED: &str = "[redacted]";
+
+/// Headers that apply to a single connection, rather than to the request
+const HOP_BY_HOP_HEADERS: [&str; 7] = [
+    "connection",
+    "content-length",
+    "host",
+    "keep-alive",
+    "proxy-authorization",
+    "proxy-connection",
+    "transfer-encoding",
+];
+
+#[derive(Parser, Clone, Debug)]
+pub struct CassetteArguments {
+    /// Record the HTTP requests the provider makes, and their responses, to
+    /// this cassette file
+    #[clap(long, value_name = "CASSETTE", value_hint = ValueHint::FilePath, conflicts_with = "replay")]
+    record: Option<PathBuf>,
+
+    /// Serve the HTTP requests the provider makes from this cassette file,
+    /// instead of sending them over the network
+    #[clap(long, value_name = "CASSETTE", value_hint = ValueHint::FilePath)]
+    replay: Option<PathBuf>,
+}
+
+#[derive(Serialize, Deserialize, Default)]
+struct CassetteFile {
+    interactions: Vec<Interaction>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+struct Interaction {
+    request: RecordedRequest,
+    response: RecordedResponse,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+struct RecordedRequest {
+    method: String,
+    url: String,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    headers: BTreeMap<String, String>,
+    #[serde(flatten)]
+    body: RecordedBody,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+struct RecordedResponse {
+    status: u16,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    headers: BTreeMap<String, String>,
+    #[serde(flatten)]
+    body: RecordedBody,
+}
+
+/// Body of a request or response, which is saved as text when it's valid
+/// UTF-8 to keep cassettes readable, and base64 encoded otherwise
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+struct RecordedBody {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    body: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    body_base64: Option<String>,
+}
+
+impl RecordedBody {
+    fn new(bytes: &[u8]) -> Self {
+        if bytes.is_empty() {
+            Self::default()
+        } else if let Ok(text) = std::str::from_utf8(bytes) {
+            Self {
+                body: Some(text.to_string()),
+                body_base64: None,
+            }
+        } else {
+            Self {
+                body: None,
+                body_base64: Some(base64::encode(bytes)),
+            }
+        }
+    }
+
+    fn bytes(&self) -> Vec<u8> {
+        match (&self.body, &self.body_base64) {
+            (Some(text), _) => text.as_bytes().to_vec(),
+            (None, Some(encoded)) => base64::decode(encoded).unwrap_or_default(),
+            (None, None) => Vec::new(),
+        }
+    }
+}
+
+enum Mode {
+    Record(reqwest::Client),
+    Replay,
+}
+
+struct State {
+    mode: Mode,
+    interactions: Mutex<Vec<Interaction>>,
+    /// Which interactions were served, when replaying
+    served: Mutex<Vec<bool>>,
+    /// Requests that could not be recorded or replayed
+    failures: Mutex<Vec<String>>,
+}
+
+/// A running recording or replay, which needs to be finished to save the
+/// cassette or report requests that could not be replayed
+pub(crate) struct Cassette {
+    path: PathBuf,
+    /// URL of the proxy
+    proxy: String,
+    state: Arc<State>,
+    server: JoinHandle<hyper::Result<()>>,
+}
+
+impl CassetteArguments {
+    /// Start the proxy if recording or replaying. The provider then needs to
+    /// run in a worker that is started with the cassette.
+    pub(crate) async fn start(&self) -> Result<Option<Cassette>> {
+        let (path, mode, interactions) = match (&self.record, &self.replay) {
+            (Some(path), _) => {
+                let client = reqwest::Client::builder().no_proxy().build()?;
+                (path, Mode::Record(client), Vec::new())
+            }
+            (None, Some(path)) => {
+                let content = std::fs::read_to_string(path)
+                    .with_context(|| format!("unable to read cassette {}", path.display()))?;
+                let cassette: CassetteFile = serde_json::from_str(&content)
+                    .with_context(|| format!("invalid cassette {}", path.display()))?;
+                (path, Mode::Replay, cassette.interactions)
+            }
+            (None, None) => return Ok(None),
+        };
+
+        let state = Arc::new(State {
+            mode,
+            served: Mutex::new(vec![false; interactions.len()]),
+            interactions: Mutex::new(interactions),
+            failures: Mutex::new(Vec::new()),
+        });
+        let service_state = state.clone();
+        let make_service = make_service_fn(move |_| {
+            let state = service_state.clone();
+            async move { Ok::<_, Infallible>(service_fn(move |req| handle_request(req, state.clone()))) }
+        });
+        let server = Server::try_bind(&([127, 0, 0, 1], 0).into())?.serve(make_service);
+
+        Ok(Some(Cassette {
+            path: path.clone(),
+            proxy: format!("http://{}", server.local_addr()),
+            state,
+            server: tokio::spawn(server),
+        }))
+    }
+}
+
+impl Cassette {
+    /// Send the requests of the worker through the proxy
+    pub(crate) fn set_proxy(&self, command: &mut Command) {
+        for name in ["HTTP_PROXY", "http_proxy", "HTTPS_PROXY", "https_proxy"] {
+            command.env(name, &self.proxy);
+        }
+        for name in ["NO_PROXY", "no_proxy"] {
+            command.env_remove(name);
+        }
+    }
+
+    /// Check that the config doesn't point the provider at an HTTPS URL, since
+    /// requests to it can't be captured
+    pub(crate) fn check_config(&self, config: &Value) -> Result<()> {
+        match https_url(config) {
+            Some(url) => bail!(
+                "requests to {} can't be recorded or replayed, only plain HTTP requests are supported",
+                url
+            ),
+            None => Ok(()),
+        }
+    }
+
+    /// Stop the proxy and save the recording. Fails if any request could not
+    /// be recorded or replayed.
+    pub(crate) fn finish(self) -> Result<()> {
+        self.server.abort();
+
+        let failures = self.state.failures.lock().unwrap();
+        for failure in failures.iter() {
+            warn!("{}", failure);
+        }
+
+        if let Mode::Record(_) = self.state.mode {
+            let interactions = self.state.interactions.lock().unwrap().clone();
+            let count = interactions.len();
+            let cassette = serde_json::to_string_pretty(&CassetteFile { interactions })?;
+            std::fs::write(&self.path, cassette)
+                .with_context(|| format!("unable to write {}", self.path.display()))?;
+            info!("Recorded {} requests to {}", count, self.path.display());
+        }
+
+        if !failures.is_empty() {
+            bail!(
+                "{} requests could not be {} {}",
+                failures.len(),
+                match self.state.mode {
+                    Mode::Record(_) => "recorded to",
+                    Mode::Replay => "replayed from",
+                },
+                self.path.display()
+            );
+        }
+        Ok(())
+    }
+}
+
+/// Find a string in the config that is an HTTPS URL
+fn https_url(config: &Value) -> Option<&str> {
+    match config {
+        Value::String(string) if string.starts_with("https://") => Some(string),
+        Value::Array(values) => values.iter().find_map(https_url),
+        Value::Object(fields) => fields.values().find_map(https_url),
+        _ => None,
+    }
+}
+
+async fn handle_request(
+    req: Request<Body>,
+    state: Arc<State>,
+) -> Result<Response<Body>, Infallible> {
+    let result = if req.method() == Method::CONNECT {
+        Err(anyhow!(
+            "HTTPS request to {} can't be captured, only plain HTTP requests are supported",
+            req.uri()
+        ))
+    } else {
+        match &state.mode {
+            Mode::Record(client) => record(req, client, &state).await,
+            Mode::Replay => replay(req, &state).await,
+        }
+    };
+
+    Ok(result.unwrap_or_else(|err| {
+        let message = format!("{:#}", err);
+        state.failures.lock().unwrap().push(message.clone());
+        Response::builder()
+            .status(StatusCode::BAD_GATEWAY)
+            .body(Body::from(message))
+            .unwrap()
+    }))
+}
+
+async fn record(
+    req: Request<Body>,
+    client: &reqwest::Client,
+    state: &State,
+) -> Result<Response<Body>> {
+    let (parts, body) = req.into_parts();
+    let body = hyper::body::to_bytes(body).await?;
+    let url = parts.uri.to_string();
+
+    let mut headers = parts.headers.clone();
+    for name in HOP_BY_HOP_HEADERS {
+        headers.remove(name);
+    }
+    let response = client
+        .request(parts.method.clone(), &url)
+        .headers(headers.clone())
+        .body(body.clone())
+        .send()
+        .await
+        .with_context(|| format!("unable to send {} {}", parts.method, url))?;
+
+    let status = response.status();
+    let mut response_headers = response.headers().clone();
+    for name in HOP_BY_HOP_HEADERS {
+        response_headers.remove(name);
+    }
+    let response_body = response.bytes().await?;
+
+    state.interactions.lock().unwrap().push(Interaction {
+        request: RecordedRequest {
+            method: parts.method.to_string(),
+            url: redact_url(&url),
+            headers: recorded_headers(&headers),
+            body: RecordedBody::new(&body),
+        },
+        response: RecordedResponse {
+            status: status.as_u16(),
+            headers: recorded_headers(&response_headers),
+            body: RecordedBody::new(&response_body),
+        },
+    });
+
+    let mut response = Response::new(Body::from(response_body));
+    *response.status_mut() = status;
+    *response.headers_mut() = response_headers;
+    Ok(response)
+}
+
+async fn replay(req: Request<Body>, state: &State) -> Result<Response<Body>> {
+    let (parts, body) = req.into_parts();
+    let body = hyper::body::to_bytes(body).await?;
+    // Recorded URLs don't contain credentials, so they're not compared either
+    let url = redact_url(&parts.uri.to_string());
+
+    let interactions = state.interactions.lock().unwrap();
+    let mut served = state.served.lock().unwrap();
+    let index = find_interaction(&interactions, &served, parts.method.as_str(), &url, &body)
+        .ok_or_else(|| anyhow!("no recorded response for {} {}", parts.method, url))?;
+    served[index] = true;
+
+    let recorded = &interactions[index].response;
+    let mut response = Response::new(Body::from(recorded.body.bytes()));
+    *response.status_mut() = StatusCode::from_u16(recorded.status)?;
+    for (name, value) in &recorded.headers {
+        response.headers_mut().insert(
+            HeaderName::from_bytes(name.as_bytes())?,
+            HeaderValue::from_str(value)?,
+        );
+    }
+    if !response.headers().contains_key(header::CONTENT_TYPE) {
+        response.headers_mut().insert(
+            header::CONTENT_TYPE,
+            HeaderValue::from_static("application/octet-stream"),
+        );
+    }
+    Ok(response)
+}
+
+/// Find the recorded interaction for a request, matching on the method, URL
+/// and body. Interactions are served in the order they were recorded, and the
+/// last match is served again for repeated requests.
+fn find_interaction(
+    interactions: &[Interaction],
+    served: &[bool],
+    method: &str,
+    url: &str,
+    body: &[u8],
+) -> Option<usize> {
+    let matches: Vec<usize> = interactions
+        .iter()
+        .enumerate()
+        .filter(|(_, interaction)| {
+            interaction.request.method == method
+                && interaction.request.url == url
+                && interaction.request.body.bytes() == body
+        })
+        .map(|(index, _)| index)
+        .collect();
+
+    matches
+        .iter()
+        .copied()
+        .find(|index| !served[*index])
+        .or_else(|| matches.last().copied())
+}
+
+fn recorded_headers(headers: &HeaderMap) -> BTreeMap<String, String> {
+    headers
+        .iter()
+        .map(|(name, value)| {
+            let value = if REDACTED_HEADERS.contains(&name.as_str()) {
+                REDACTED.to_string()
+            } else {
+                String::from_utf8_lossy(value.as_bytes()).to_string()
+            };
+            (name.to_string(), value)
+        })
+        .collect()
+}
+
+/// Replace the values of query parameters that may contain credentials
+fn redact_url(url: &str) -> String {
+    let (base, query) = match url.split_once('?') {
+        Some(parts) => parts,
+        None => return url.to_string(),
+    };
+
+    let params: Vec<String> = query
+        .split('&')
+        .map(|param| match param.split_once('=') {
+            Some((name, _)) if is_credential(name) => format!("{}={}", name, REDACTED),
+            _ => param.to_string(),
+        })
+        .collect();
+    format!("{}?{}", base, params.join("&"))
+}
+
+fn is_credential(name: &str) -> bool {
+    let name = name.to_ascii_lowercase();
+    REDACTED_PARAMS.iter().any(|param| name.contains(param))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn interaction(method: &str, url: &str, body: &str, response: &str) -> Interaction {
+        Interaction {
+            request: RecordedRequest {
+                method: method.to_string(),
+                url: url.to_string(),
+                headers: BTreeMap::new(),
+                body: RecordedBody::new(body.as_bytes()),
+            },
+            response: RecordedResponse {
+                status: 200,
+                headers: BTreeMap::new(),
+                body: RecordedBody::new(response.as_bytes()),
+            },
+        }
+    }
+
+    #[test]
+    fn finds_interactions_in_order() {
+        let url = "http://localhost:9090/api/v1/query";
+        let interactions = vec![
+            interaction("POST", url, "query=up", "1"),
+            interaction("POST", url, "query=down", "2"),
+            interaction("POST", url, "query=up", "3"),
+        ];
+
+        let find = |served: &[bool], body: &str| {
+            find_interaction(&interactions, served, "POST", url, body.as_bytes())
+        };
+        assert_eq!(find(&[false, false, false], "query=up"), Some(0));
+        assert_eq!(find(&[true, false, false], "query=up"), Some(2));
+        assert_eq!(find(&[true, false, true], "query=up"), Some(2));
+        assert_eq!(find(&[true, false, true], "query=down"), Some(1));
+        assert_eq!(find(&[false, false, false], "query=sideways"), None);
+        assert_eq!(
+            find_interaction(&interactions, &[false; 3], "GET", url, b"query=up"),
+            None
+        );
+    }
+
+    #[test]
+    fn saves_bodies_as_text_when_possible() {
+        let text = RecordedBody::new(b"{\"status\":\"success\"}");
+        assert_eq!(text.body.as_deref(), Some("{\"status\":\"success\"}"));
+        assert_eq!(text.bytes(), b"{\"status\":\"success\"}");
+
+        let binary = RecordedBody::new(&[0x82, 0xa1, 0xff]);
+        assert_eq!(binary.body, None);
+        assert_eq!(binary.bytes(), vec![0x82, 0xa1, 0xff]);
+
+        assert_eq!(RecordedBody::new(b""), RecordedBody::default());
+    }
+
+    #[test]
+    fn redacts_credentials() {
+        assert_eq!(
+            redact_url("http://localhost/api?query=up&api_key=abc&Access_Token=def&time=1"),
+            "http://localhost/api?query=up&api_key=[redacted]&Access_Token=[redacted]&time=1"
+        );
+        assert_eq!(redact_url("http://localhost/api"), "http://localhost/api");
+
+        let mut headers = HeaderMap::new();
+        headers.insert(header::SET_COOKIE, HeaderValue::from_static("session=abc"));
+        headers.insert(header::CONTENT_TYPE, HeaderValue::from_static("text/plain"));
+        assert_eq!(
+            recorded_headers(&headers),
+            BTreeMap::from([
+                ("content-type".to_string(), "text/plain".to_string()),
+                ("set-cookie".to_string(), "[redacted]".to_string()),
+            ])
+        );
+    }
+
+    #[test]
+    fn finds_https_urls() {
+        let config = serde_json::json!({ "url": "http://localhost:9090", "fallback": ["https://example.com"] });
+        assert_eq!(https_url(&config), Some("https://example.com"));
+        assert_eq!(
+            https_url(&serde_json::json!({ "url": "http://localhost" })),
+            None
+        );
+    }
+}
@@ -0,0 +1,717 @@
+//! Conformance checks for providers: `fp providers test` calls a provider the
+//! way Fiberplane does and checks that it follows the provider protocol.
+//!
+//! The checks are run once for every fixture, which is a JSON or TOML file
+//! with a config and optionally some queries:
+//!
+//! ```toml
+//! [config]
+//! url = "http://localhost:9090"
+//!
+//! [[queries]]
+//! query_type = "timeseries"
+//! query = { query = "up", time_range = "2022-10-19T12:00:00Z..2022-10-19T13:00:00Z" }
+//! ```
+//!
+//! The checks of every fixture run in a worker process. Each provider call is
+//! timed, and a worker that doesn't finish a call in time is killed. The
+//! memory that is used is measured as the peak resident memory of the worker,
+//! which is only available on Linux.
+
+use super::cassette::{Cassette, CassetteArguments};
+use super::query::{encode_query, fields_from_value, read_json_or_toml};
+use super::render::decode;
+use super::schema::validate_config;
+use super::{load_runtime, worker, STATUS_QUERY_TYPE};
+use anyhow::{anyhow, bail, Context, Result};
+use clap::{Parser, ValueHint};
+use fiberplane::provider_runtime::spec::types::{
+    Blob, Error as ProviderError, ProviderConfig, ProviderRequest,
+};
+use fiberplane::provider_runtime::spec::Runtime;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::HashSet;
+use std::fmt::Write as _;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncBufReadExt, BufReader};
+use tracing::info;
+
+/// Query type that no provider supports, used to check how unknown query
+/// types are handled
+const UNKNOWN_QUERY_TYPE: &str = "x-fp-conformance-unknown";
+
+const FORM_MIME_TYPE: &str = "application/x-www-form-urlencoded";
+
+/// Time a worker gets on top of the maximum duration of a call to report that
+/// the call returned, before it is killed
+const KILL_GRACE_PERIOD: Duration = Duration::from_secs(1);
+
+#[derive(Parser)]
+pub struct Arguments {
+    /// Path to the provider WASM file
+    #[clap(value_hint = ValueHint::FilePath)]
+    provider_path: PathBuf,
+
+    /// JSON or TOML file with a config and queries to test the provider with
+    /// (you can specify multiple fixtures). Without fixtures, the provider is
+    /// tested with an empty config.
+    #[clap(long, short, value_hint = ValueHint::FilePath)]
+    fixture: Vec<PathBuf>,
+
+    /// Write the results as a JUnit XML report to this file
+    #[clap(long, value_hint = ValueHint::FilePath)]
+    junit: Option<PathBuf>,
+
+    /// Maximum number of milliseconds a single call to the provider may take
+    #[clap(long, default_value = "10000")]
+    max_duration_ms: u64,
+
+    /// Maximum peak memory use in megabytes
+    #[clap(long, default_value = "512")]
+    max_memory_mb: u64,
+
+    #[clap(flatten)]
+    cassette: CassetteArguments,
+}
+
+#[derive(Deserialize)]
+struct Fixture {
+    #[serde(default)]
+    name: Option<String>,
+
+    #[serde(default = "empty_object")]
+    config: Value,
+
+    #[serde(default)]
+    queries: Vec<FixtureQuery>,
+}
+
+#[derive(Deserialize)]
+struct FixtureQuery {
+    query_type: String,
+
+    #[serde(default = "empty_object")]
+    query: Value,
+}
+
+fn empty_object() -> Value {
+    Value::Object(Default::default())
+}
+
+/// Outcome of a single check
+#[derive(Serialize, Deserialize, Debug)]
+struct TestCase {
+    suite: String,
+    name: String,
+    duration: Duration,
+    failure: Option<String>,
+}
+
+struct Limits {
+    max_duration: Duration,
+    max_memory_bytes: u64,
+}
+
+/// What a worker reports to the parent process while it runs the checks of a
+/// fixture, one message per line
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum WorkerMessage {
+    /// A check started
+    Started { name: String },
+    /// The provider is called, so it has to return within the time limit
+    Calling,
+    /// A check finished
+    Finished { case: TestCase },
+}
+
+pub async fn handle_command(args: Arguments) -> Result<()> {
+    let limits = Limits {
+        max_duration: Duration::from_millis(args.max_duration_ms),
+        max_memory_bytes: args.max_memory_mb * 1024 * 1024,
+    };
+
+    let fixtures = if args.fixture.is_empty() {
+        vec![(
+            "default".to_string(),
+            Fixture {
+                name: None,
+                config: empty_object(),
+                queries: Vec::new(),
+            },
+        )]
+    } else {
+        let mut fixtures = Vec::new();
+        for path in &args.fixture {
+            let fixture: Fixture = serde_json::from_value(read_json_or_toml(path)?)
+                .map_err(|e| anyhow!("invalid fixture {}: {}", path.display(), e))?;
+            let name = fixture.name.clone().unwrap_or_else(|| fixture_name(path));
+            fixtures.push((name, fixture));
+        }
+        fixtures
+    };
+
+    if let Some(task) = worker::task() {
+        let (name, fixture) = task
+            .parse::<usize>()
+            .ok()
+            .and_then(|index| fixtures.get(index))
+            .ok_or_else(|| anyhow!("invalid worker task: {}", task))?;
+        let runtime = load_runtime(&args.provider_path)?;
+        run_checks(&runtime, name, fixture, &limits).await;
+        return Ok(());
+    }
+
+    let cassette = args.cassette.start().await?;
+    if let Some(cassette) = &cassette {
+        for (_, fixture) in &fixtures {
+            cassette.check_config(&fixture.config)?;
+        }
+    }
+    let mut cases = Vec::new();
+    for (index, (name, _)) in fixtures.iter().enumerate() {
+        // Every fixture is checked in a process of its own, so they don't
+        // affect each other and the memory use of each can be measured
+        cases.extend(check_fixture(index, name, &limits, cassette.as_ref()).await?);
+    }
+    if let Some(cassette) = cassette {
+        cassette.finish()?;
+    }
+
+    for case in &cases {
+        match &case.failure {
+            None => info!("✅ {}: {}", case.suite, case.name),
+            Some(failure) => info!("❌ {}: {}\n   {}", case.suite, case.name, failure),
+        }
+    }
+
+    if let Some(path) = &args.junit {
+        std::fs::write(path, junit_report(&args.provider_path, &cases))?;
+        info!("Wrote JUnit report to {}", path.display());
+    }
+
+    let failed = cases.iter().filter(|case| case.failure.is_some()).count();
+    if failed > 0 {
+        bail!("{} of {} checks failed", failed, cases.len());
+    }
+    info!("All {} checks passed", cases.len());
+    Ok(())
+}
+
+fn fixture_name(path: &Path) -> String {
+    path.file_stem()
+        .map(|stem| stem.to_string_lossy().to_string())
+        .unwrap_or_else(|| path.display().to_string())
+}
+
+/// Run the checks of a fixture in a worker and collect the results. The worker
+/// is killed if a call to the provider takes too long.
+async fn check_fixture(
+    index: usize,
+    suite: &str,
+    limits: &Limits,
+    cassette: Option<&Cassette>,
+) -> Result<Vec<TestCase>> {
+    let mut child = worker::spawn(&index.to_string(), cassette)?;
+    let mut lines = BufReader::new(child.stdout.take().expect("stdout is piped")).lines();
+
+    let mut cases = Vec::new();
+    let mut current: Option<(String, Instant)> = None;
+    let mut deadline = None;
+    loop {
+        let line = match deadline {
+            Some(deadline) => match tokio::time::timeout_at(deadline, lines.next_line()).await {
+                Ok(line) => line?,
+                Err(_) => {
+                    child.kill().await?;
+                    let (name, started) = current
+                        .take()
+                        .unwrap_or_else(|| ("provider call".to_string(), Instant::now()));
+                    cases.push(TestCase {
+                        suite: suite.to_string(),
+                        name,
+                        duration: started.elapsed(),
+                        failure: Some(format!(
+                            "provider didn't respond within {:?}, so it was stopped and the remaining checks were skipped",
+                            limits.max_duration
+                        )),
+                    });
+                    return Ok(cases);
+                }
+            },
+            None => lines.next_line().await?,
+        };
+        let line = match line {
+            Some(line) => line,
+            None => break,
+        };
+
+        match serde_json::from_str(&line).context("invalid message from worker")? {
+            WorkerMessage::Started { name } => {
+                current = Some((name, Instant::now()));
+                deadline = None;
+            }
+            WorkerMessage::Calling => {
+                deadline =
+                    Some(tokio::time::Instant::now() + limits.max_duration + KILL_GRACE_PERIOD);
+            }
+            WorkerMessage::Finished { case } => {
+                cases.push(case);
+                current = None;
+                deadline = None;
+            }
+        }
+    }
+
+    let status = child.wait().await?;
+    match current {
+        Some((name, started)) => cases.push(TestCase {
+            suite: suite.to_string(),
+            name,
+            duration: started.elapsed(),
+            failure: Some(format!(
+                "provider process exited during this check ({})",
+                status
+            )),
+        }),
+        None if !status.success() => bail!("unable to check fixture {} ({})", suite, status),
+        None => {}
+    }
+    Ok(cases)
+}
+
+/// Reports the checks that run in a worker to the parent process
+struct Checks<'a> {
+    suite: &'a str,
+    name: String,
+    started: Instant,
+}
+
+impl<'a> Checks<'a> {
+    fn new(suite: &'a str) -> Self {
+        Self {
+            suite,
+            name: String::new(),
+            started: Instant::now(),
+        }
+    }
+
+    fn start(&mut self, name: impl Into<String>) {
+        self.name = name.into();
+        self.started = Instant::now();
+        send(&WorkerMessage::Started {
+            name: self.name.clone(),
+        });
+    }
+
+    fn finish(&mut self, result: Result<()>) {
+        send(&WorkerMessage::Finished {
+            case: TestCase {
+                suite: self.suite.to_string(),
+                name: self.name.clone(),
+                duration: self.started.elapsed(),
+                failure: result.err().map(|err| format!("{:#}", err)),
+            },
+        });
+    }
+}
+
+fn send(message: &WorkerMessage) {
+    println!(
+        "{}",
+        serde_json::to_string(message).expect("messages can be serialized")
+    );
+}
+
+async fn run_checks(runtime: &Runtime, suite: &str, fixture: &Fixture, limits: &Limits) {
+    let mut checks = Checks::new(suite);
+
+    checks.start("config schema is valid");
+    checks.finish(check_config_schema(runtime, &fixture.config));
+
+    let config: ProviderConfig = match serde_json::from_value(fixture.config.clone()) {
+        Ok(config) => config,
+        Err(err) => {
+            checks.start("fixture config can be used");
+            checks.finish(Err(anyhow!("unable to deserialize config: {}", err)));
+            return;
+        }
+    };
+
+    checks.start("query types are advertised consistently");
+    let query_types = get_query_types(runtime, config.clone()).await;
+    checks.finish(match &query_types {
+        Ok(query_types) => check_query_types(runtime, config.clone(), query_types).await,
+        Err(err) => Err(anyhow!("{:#}", err)),
+    });
+    let query_types = query_types.unwrap_or_default();
+
+    checks.start("unknown query types return structured errors");
+    checks.finish(
+        match invoke(runtime, &config, UNKNOWN_QUERY_TYPE, Vec::new(), limits).await {
+            Ok(Err(_)) => Ok(()),
+            Ok(Ok(_)) => Err(anyhow!("provider responded to an unknown query type")),
+            Err(err) => Err(err),
+        },
+    );
+
+    for query_type in &query_types {
+        checks.start(format!("invalid `{}` queries don't trap", query_type.name));
+        let data = b"\xff\x00=%%invalid&&=".to_vec();
+        let result = invoke(runtime, &config, &query_type.name, data, limits)
+            .await
+            .map(|_| ());
+        checks.finish(result);
+    }
+
+    if query_types.iter().any(|t| t.name == STATUS_QUERY_TYPE) {
+        checks.start("status query responds");
+        checks.finish(
+            check_response(
+                runtime,
+                &config,
+                &query_types,
+                STATUS_QUERY_TYPE,
+                "",
+                limits,
+            )
+            .await,
+        );
+    }
+
+    for (i, query) in fixture.queries.iter().enumerate() {
+        checks.start(format!(
+            "query {} (`{}`) responds with an advertised MIME type",
+            i + 1,
+            query.query_type
+        ));
+        let result = match query_data(&query_types, query) {
+            Ok(data) => {
+                check_response(
+                    runtime,
+                    &config,
+                    &query_types,
+                    &query.query_type,
+                    &data,
+                    limits,
+                )
+                .await
+            }
+            Err(err) => Err(err),
+        };
+        checks.finish(result);
+    }
+
+    // The worker only loaded this provider, so its peak memory use is mostly
+    // the memory of the provider
+    checks.start("memory use stays within bounds");
+    checks.finish(match process_memory("VmHWM") {
+        Some(bytes) if bytes > limits.max_memory_bytes => {
+            Err(anyhow!("peak memory use was {} MB", bytes / 1024 / 1024))
+        }
+        _ => Ok(()),
+    });
+}
+
+/// Query type as advertised by the provider, with its schema as JSON
+#[derive(Debug, PartialEq)]
+struct QueryType {
+    name: String,
+    schema: Value,
+    mime_types: Vec<String>,
+}
+
+async fn get_query_types(runtime: &Runtime, config: ProviderConfig) -> Result<Vec<QueryType>> {
+    send(&WorkerMessage::Calling);
+    let query_types = runtime
+        .get_supported_query_types(config)
+        .await
+        .map_err(|e| anyhow!("unable to get query types: {:?}", e))?;
+    query_types
+        .into_iter()
+        .map(|query_type| {
+            Ok(QueryType {
+                name: query_type.query_type,
+                schema: serde_json::to_value(&query_type.schema)?,
+                mime_types: query_type.mime_types,
+            })
+        })
+        .collect()
+}
+
+fn check_config_schema(runtime: &Runtime, config: &Value) -> Result<()> {
+    send(&WorkerMessage::Calling);
+    let schema = runtime
+        .get_config_schema()
+        .map_err(|e| anyhow!("unable to get config schema: {:?}", e))?;
+    let schema = serde_json::to_value(&schema)?;
+    check_schema_fields(&schema)?;
+
+    let errors = validate_config(&schema, config);
+    if !errors.is_empty() {
+        bail!("fixture config is invalid: {}", errors.join(", "));
+    }
+    Ok(())
+}
+
+/// Check that every field of a schema has a type and a unique name
+fn check_schema_fields(schema: &Value) -> Result<()> {
+    let fields = schema
+        .as_array()
+        .ok_or_else(|| anyhow!("schema is not a list of fields"))?;
+
+    let mut names = HashSet::new();
+    for (i, field) in fields.iter().enumerate() {
+        let name = field
+            .get("name")
+            .and_then(Value::as_str)
+            .filter(|name| !name.is_empty())
+            .ok_or_else(|| anyhow!("field {} has no name", i + 1))?;
+        if field.get("type").and_then(Value::as_str).is_none() {
+            bail!("field `{}` has no type", name);
+        }
+        if !names.insert(name) {
+            bail!("field `{}` is defined more than once", name);
+        }
+    }
+    Ok(())
+}
+
+async fn check_query_types(
+    runtime: &Runtime,
+    config: ProviderConfig,
+    query_types: &[QueryType],
+) -> Result<()> {
+    let mut names = HashSet::new();
+    for query_type in query_types {
+        if query_type.name.is_empty() {
+            bail!("query type without a name");
+        }
+        if !names.insert(&query_type.name) {
+            bail!(
+                "query type `{}` is advertised more than once",
+                query_type.name
+            );
+        }
+        if query_type.mime_types.is_empty() {
+            bail!("query type `{}` has no MIME types", query_type.name);
+        }
+        check_schema_fields(&query_type.schema)
+            .map_err(|err| anyhow!("schema of `{}`: {}", query_type.name, err))?;
+    }
+
+    if get_query_types(runtime, config).await?.as_slice() != query_types {
+        bail!("query types differ between calls with the same config");
+    }
+    Ok(())
+}
+
+fn query_data(query_types: &[QueryType], query: &FixtureQuery) -> Result<String> {
+    let query_type = query_types
+        .iter()
+        .find(|t| t.name == query.query_type)
+        .ok_or_else(|| anyhow!("query type `{}` is not advertised", query.query_type))?;
+    encode_query(&query_type.schema, &fields_from_value(&query.query)?)
+}
+
+async fn check_response(
+    runtime: &Runtime,
+    config: &ProviderConfig,
+    query_types: &[QueryType],
+    query_type: &str,
+    data: &str,
+    limits: &Limits,
+) -> Result<()> {
+    let blob = match invoke(
+        runtime,
+        config,
+        query_type,
+        data.as_bytes().to_vec(),
+        limits,
+    )
+    .await?
+    {
+        Ok(blob) => blob,
+        Err(err) => bail!("provider returned an error: {:?}", err),
+    };
+
+    let advertised = query_types
+        .iter()
+        .find(|t| t.name == query_type)
+        .map(|t| t.mime_types.as_slice())
+        .unwrap_or_default();
+    if !advertised.contains(&blob.mime_type) {
+        bail!(
+            "response has MIME type {}, but the query type advertises {}",
+            blob.mime_type,
+            advertised.join(", ")
+        );
+    }
+    decode(&blob)?;
+    Ok(())
+}
+
+/// Invoke the provider and check how long it took. The parent process kills the
+/// worker if the provider doesn't return in time. Errors of the provider are
+/// returned in the inner result, while traps and other failures to invoke the
+/// provider are returned as errors.
+async fn invoke(
+    runtime: &Runtime,
+    config: &ProviderConfig,
+    query_type: &str,
+    data: Vec<u8>,
+    limits: &Limits,
+) -> Result<Result<Blob, ProviderError>> {
+    let request = ProviderRequest::builder()
+        .query_type(query_type.to_string())
+        .query_data(
+            Blob::builder()
+                .data(data)
+                .mime_type(FORM_MIME_TYPE.to_string())
+                .build(),
+        )
+        .config(config.clone())
+        .build();
+
+    send(&WorkerMessage::Calling);
+    let started = Instant::now();
+    let result = runtime
+        .invoke2(request)
+        .await
+        .map_err(|e| anyhow!("provider trapped: {:?}", e))?;
+    if started.elapsed() > limits.max_duration {
+        bail!("provider took {:?} to respond", started.elapsed());
+    }
+    Ok(result)
+}
+
+/// Memory use of the process in bytes, as reported for the given field of
+/// `/proc/self/status` (such as `VmHWM` for the peak resident memory)
+pub(super) fn process_memory(field: &str) -> Option<u64> {
+    let status = std::fs::read_to_string("/proc/self/status").ok()?;
+    let line = status.lines().find(|line| {
+        line.strip_prefix(field)
+            .map_or(false, |rest| rest.starts_with(':'))
+    })?;
+    let kilobytes: u64 = line.split_whitespace().nth(1)?.parse().ok()?;
+    Some(kilobytes * 1024)
+}
+
+fn junit_report(provider_path: &Path, cases: &[TestCase]) -> String {
+    let failures = cases.iter().filter(|case| case.failure.is_some()).count();
+    let total: Duration = cases.iter().map(|case| case.duration).sum();
+
+    let mut xml = String::from("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n");
+    let _ = writeln!(
+        xml,
+        "<testsuites name=\"{}\" tests=\"{}\" failures=\"{}\" time=\"{:.3}\">",
+        escape_xml(&provider_path.display().to_string()),
+        cases.len(),
+        failures,
+        total.as_secs_f64()
+    );
+
+    let mut suites: Vec<&str> = Vec::new();
+    for case in cases {
+        if !suites.contains(&case.suite.as_str()) {
+            suites.push(&case.suite);
+        }
+    }
+    for suite in suites {
+        let suite_cases: Vec<&TestCase> = cases.iter().filter(|case| case.suite == suite).collect();
+        let _ = writeln!(
+            xml,
+            "  <testsuite name=\"{}\" tests=\"{}\" failures=\"{}\">",
+            escape_xml(suite),
+            suite_cases.len(),
+            suite_cases
+                .iter()
+                .filter(|case| case.failure.is_some())
+                .count()
+        );
+        for case in suite_cases {
+            let _ = write!(
+                xml,
+                "    <testcase classname=\"{}\" name=\"{}\" time=\"{:.3}\"",
+                escape_xml(&case.suite),
+                escape_xml(&case.name),
+                case.duration.as_secs_f64()
+            );
+            match &case.failure {
+                None => xml.push_str("/>\n"),
+                Some(failure) => {
+                    let _ = writeln!(
+                        xml,
+                        ">\n      <failure message=\"{}\"/>\n    </testcase>",
+                        escape_xml(failure)
+                    );
+                }
+            }
+        }
+        xml.push_str("  </testsuite>\n");
+    }
+    xml.push_str("</testsuites>\n");
+    xml
+}
+
+fn escape_xml(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn checks_schema_fields() {
+        assert!(check_schema_fields(&json!([
+            { "type": "text", "name": "url" },
+            { "type": "checkbox", "name": "insecure" },
+        ]))
+        .is_ok());
+        assert!(check_schema_fields(&json!([{ "type": "text" }])).is_err());
+        assert!(check_schema_fields(&json!([{ "name": "url" }])).is_err());
+        assert!(check_schema_fields(&json!([
+            { "type": "text", "name": "url" },
+            { "type": "text", "name": "url" },
+        ]))
+        .is_err());
+    }
+
+    #[test]
+    fn writes_junit_report() {
+        let cases = vec![
+            TestCase {
+                suite: "local".to_string(),
+                name: "config schema is valid".to_string(),
+                duration: Duration::from_millis(12),
+                failure: None,
+            },
+            TestCase {
+                suite: "local".to_string(),
+                name: "status query responds".to_string(),
+                duration: Duration::from_millis(250),
+                failure: Some("provider returned an error: \"<timeout>\"".to_string()),
+            },
+        ];
+        assert_eq!(
+            junit_report(Path::new("prometheus.wasm"), &cases),
+            r#"<?xml version="1.0" encoding="UTF-8"?>
+<testsuites name="prometheus.wasm" tests="2" failures="1" time="0.262">
+  <testsuite name="local" tests="2" failures="1">
+    <testcase classname="local" name="config schema is valid" time="0.012"/>
+    <testcase classname="local" name="status query responds" time="0.250">
+      <failure message="provider returned an error: &quot;&lt;timeout&gt;&quot;"/>
+    </testcase>
+  </testsuite>
+</testsuites>
+"#
+        );
+    }
+}
@@ -0,0 +1,432 @@
+use self::cassette::{Cassette, CassetteArguments};
+use self::render::{output_blob, BlobOutput};
+use crate::config::api_client_configuration;
+use crate::interactive;
+use crate::output::output_json;
+use crate::KeyValueArgument;
+use anyhow::{anyhow, bail, Context, Result};
+use clap::{Parser, ValueHint};
+use fiberplane::base64uuid::Base64Uuid;
+use fiberplane::models::names::Name;
+use fiberplane::provider_runtime::spec::types::{Blob, ProviderConfig, ProviderRequest};
+use fiberplane::provider_runtime::spec::Runtime;
+use std::path::{Path, PathBuf};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tracing::{info, warn};
+use url::Url;
+
+mod bench;
+mod cassette;
+mod conformance;
+mod query;
+mod render;
+pub(crate) mod run_queries;
+mod schema;
+mod serve;
+mod worker;
+
+/// Query type that providers use to report whether they can reach the service
+/// they query
+const STATUS_QUERY_TYPE: &str = "x-status";
+
+#[derive(Parser)]
+pub struct Arguments {
+    #[clap(subcommand)]
+    sub_command: SubCommand,
+}
+
+pub async fn handle_command(args: Arguments) -> Result<()> {
+    use SubCommand::*;
+    match args.sub_command {
+        Invoke(args) => handle_invoke2_command(args).await,
+        Serve(args) => serve::handle_command(args).await,
+        QueryTypes(args) => handle_query_types_command(args).await,
+        ConfigSchema(args) => handle_config_schema_command(args),
+        Status(args) => handle_status_command(args).await,
+        CreateCells(args) => handle_create_cells_command(args),
+        Test(args) => conformance::handle_command(args).await,
+        Bench(args) => bench::handle_command(args).await,
+    }
+}
+
+#[derive(Parser)]
+pub enum SubCommand {
+    /// Invoke a provider with the new provider protocol
+    #[clap(alias = "invoke2")]
+    Invoke(InvokeArguments),
+
+    /// Serve a provider locally while developing it
+    ///
+    /// The provider is reloaded whenever the WASM file changes, and can be
+    /// called over HTTP or from the prompt that is shown in the terminal.
+    Serve(serve::Arguments),
+
+    /// List the query types the provider supports for the given config
+    QueryTypes(QueryTypesArguments),
+
+    /// Show the schema of the provider config
+    ///
+    /// Use `--validate` to check a data source config against the schema.
+    ConfigSchema(ConfigSchemaArguments),
+
+    /// Check whether the provider can reach the service it queries
+    Status(StatusArguments),
+
+    /// Create the cells the provider would show for one of its responses
+    CreateCells(CreateCellsArguments),
+
+    /// Check that a provider follows the provider protocol
+    ///
+    /// The checks are run for every fixture, and can be reported as JUnit XML
+    /// for use in CI.
+    Test(conformance::Arguments),
+
+    /// Measure how long a provider takes to instantiate and invoke, and how
+    /// much memory it uses
+    ///
+    /// Use `--compare` to compare the results with another build.
+    Bench(bench::Arguments),
+}
+
+#[derive(Parser, Debug)]
+pub struct InvokeArguments {
+    /// Path to the provider WASM file
+    #[clap(long, short)]
+    pub provider_path: String,
+
+    /// Type of query for the provider (available options are set by the provider)
+    #[clap(long, short = 't')]
+    pub query_type: String,
+
+    /// Query field to send to the provider (you can specify multiple fields).
+    ///
+    /// Fields are checked against the schema of the query type and encoded
+    /// the way the provider expects.
+    #[clap(long = "query", value_name = "NAME=VALUE")]
+    pub query: Vec<KeyValueArgument>,
+
+    /// JSON or TOML file with the query fields to send to the provider
+    #[clap(long, value_hint = ValueHint::FilePath)]
+    pub query_file: Option<PathBuf>,
+
+    /// Data to send to the provider as is, instead of encoding query fields
+    #[clap(long, short = 'q', conflicts_with_all = ["query", "query_file"])]
+    pub query_data: Option<String>,
+
+    /// Mime type of the query data
+    #[clap(long, short = 'm', default_value = "application/x-www-form-urlencoded")]
+    pub query_mime_type: String,
+
+    /// JSON encoded config that will be sent to the provider
+    #[clap(long, short = 'c', value_name = "JSON")]
+    pub provider_config: Option<String>,
+
+    /// JSON or TOML file with the config that will be sent to the provider
+    #[clap(long, value_hint = ValueHint::FilePath, conflicts_with = "provider_config")]
+    pub config_file: Option<PathBuf>,
+
+    /// Use the config of this data source in the workspace
+    #[clap(long, conflicts_with_all = ["provider_config", "config_file"])]
+    pub data_source: Option<Name>,
+
+    /// How to output the result. By default, timeseries, events and tables
+    /// are rendered in the terminal.
+    #[clap(long, short, default_value = "pretty", value_enum)]
+    pub output: BlobOutput,
+
+    #[clap(flatten)]
+    pub cassette: CassetteArguments,
+
+    /// Deprecated: this request was never sent to the provider and is ignored
+    #[clap(long, short, hide = true)]
+    pub request: Option<String>,
+
+    #[clap(from_global)]
+    workspace_id: Option<Base64Uuid>,
+
+    #[clap(from_global)]
+    base_url: Url,
+
+    /// Path to the Fiberplane config file. Before `--provider-config` was
+    /// added, this was the JSON encoded provider config, which is still
+    /// accepted for now.
+    #[clap(from_global)]
+    config: Option<PathBuf>,
+}
+
+#[derive(Parser)]
+pub struct QueryTypesArguments {
+    /// Path to the provider WASM file
+    #[clap(long, short, value_hint = ValueHint::FilePath)]
+    provider_path: PathBuf,
+
+    /// JSON encoded config that will be sent to the provider
+    #[clap(long, default_value = "{}", value_name = "JSON")]
+    provider_config: String,
+}
+
+#[derive(Parser)]
+pub struct ConfigSchemaArguments {
+    /// Path to the provider WASM file
+    #[clap(long, short, value_hint = ValueHint::FilePath)]
+    provider_path: PathBuf,
+
+    /// JSON encoded config to validate against the schema, instead of
+    /// showing the schema
+    #[clap(long, value_name = "JSON")]
+    validate: Option<String>,
+}
+
+#[derive(Parser)]
+pub struct StatusArguments {
+    /// Path to the provider WASM file
+    #[clap(long, short, value_hint = ValueHint::FilePath)]
+    provider_path: PathBuf,
+
+    /// JSON encoded config that will be sent to the provider
+    #[clap(long, default_value = "{}", value_name = "JSON")]
+    provider_config: String,
+}
+
+#[derive(Parser)]
+pub struct CreateCellsArguments {
+    /// Path to the provider WASM file
+    #[clap(long, short, value_hint = ValueHint::FilePath)]
+    provider_path: PathBuf,
+
+    /// Query type the response is for
+    #[clap(long, short = 't')]
+    query_type: String,
+
+    /// File containing the response of the provider
+    #[clap(long, short, value_hint = ValueHint::FilePath)]
+    response: PathBuf,
+
+    /// Mime type of the response
+    #[clap(long, short)]
+    mime_type: String,
+}
+
+async fn handle_invoke2_command(args: InvokeArguments) -> Result<()> {
+    let config = match worker::task() {
+        // The config is passed by the parent process, so a data source isn't
+        // requested through the proxy
+        Some(_) => {
+            let mut input = Vec::new();
+            tokio::io::stdin().read_to_end(&mut input).await?;
+            serde_json::from_slice(&input).context("invalid config from parent process")?
+        }
+        None => {
+            if args.request.is_some() {
+                warn!("--request is deprecated and ignored");
+            }
+
+            let config = invoke_config(&args).await?;
+            if let Some(cassette) = args.cassette.start().await? {
+                cassette.check_config(&serde_json::to_value(&config)?)?;
+                return invoke_in_worker(&config, cassette).await;
+            }
+            config
+        }
+    };
+
+    let runtime = load_runtime(Path::new(&args.provider_path))?;
+
+    let query_data = match args.query_data {
+        Some(query_data) => query_data,
+        None => {
+            let mut fields = match &args.query_file {
+                Some(path) => query::fields_from_value(&query::read_json_or_toml(path)?)?,
+                None => Vec::new(),
+            };
+            fields.extend(args.query.into_iter().map(|kv| (kv.key, kv.value)));
+
+            encode_query_fields(&runtime, &config, &args.query_type, &fields).await?
+        }
+    };
+
+    let request = ProviderRequest::builder()
+        .query_type(args.query_type)
+        .query_data(
+            Blob::builder()
+                .data(query_data.into_bytes())
+                .mime_type(args.query_mime_type)
+                .build(),
+        )
+        .config(config)
+        .build();
+
+    match runtime.invoke2(request).await {
+        Ok(Ok(blob)) => output_blob(&blob, args.output),
+        Ok(Err(err)) => bail!("Provider failed: {:?}", err),
+        Err(e) => bail!("unable to invoke provider: {:?}", e),
+    }
+}
+
+/// Encode query fields according to the schema the provider advertises for
+/// the query type
+async fn encode_query_fields(
+    runtime: &Runtime,
+    config: &ProviderConfig,
+    query_type: &str,
+    fields: &[(String, String)],
+) -> Result<String> {
+    let query_types = runtime
+        .get_supported_query_types(config.clone())
+        .await
+        .map_err(|e| anyhow!("unable to invoke provider: {:?}", e))?;
+    let schema = query_types
+        .iter()
+        .find(|t| t.query_type == query_type)
+        .map(|t| &t.schema)
+        .ok_or_else(|| {
+            let names: Vec<_> = query_types.iter().map(|t| t.query_type.as_str()).collect();
+            anyhow!(
+                "provider doesn't support query type `{}` (supported: {})",
+                query_type,
+                names.join(", ")
+            )
+        })?;
+    query::encode_query(&serde_json::to_value(schema)?, fields)
+}
+
+/// Invoke the provider in a worker, so its requests go through the proxy of the
+/// cassette, and show what the worker outputs
+async fn invoke_in_worker(config: &ProviderConfig, cassette: Cassette) -> Result<()> {
+    let mut child = worker::spawn("invoke", Some(&cassette))?;
+    let mut stdin = child.stdin.take().expect("stdin is piped");
+    stdin.write_all(&serde_json::to_vec(config)?).await?;
+    drop(stdin);
+
+    let mut stdout = child.stdout.take().expect("stdout is piped");
+    tokio::io::copy(&mut stdout, &mut tokio::io::stdout()).await?;
+    let status = child.wait().await?;
+
+    cassette.finish()?;
+    if !status.success() {
+        bail!("unable to invoke provider ({})", status);
+    }
+    Ok(())
+}
+
+/// Load the provider config from the argument, file or data source that was
+/// given
+async fn invoke_config(args: &InvokeArguments) -> Result<ProviderConfig> {
+    let config = if let Some(path) = &args.config_file {
+        query::read_json_or_toml(path)?
+    } else if let Some(name) = &args.data_source {
+        let client = api_client_configuration(args.config.clone(), args.base_url.clone()).await?;
+        let data_source =
+            interactive::data_source_picker(&client, args.workspace_id, Some(name.clone())).await?;
+        match data_source.config {
+            Some(config) => serde_json::Value::Object(config),
+            None => bail!(
+                "config of data source {} is not available (it might be provided by a daemon)",
+                data_source.name
+            ),
+        }
+    } else if let Some(config) = legacy_provider_config(args) {
+        warn!("passing the provider config with --config is deprecated, use --provider-config instead");
+        return parse_config(config).context("unable to deserialize config");
+    } else {
+        return parse_config(args.provider_config.as_deref().unwrap_or("{}"))
+            .context("unable to deserialize config");
+    };
+    serde_json::from_value(config).context("unable to deserialize config")
+}
+
+/// The provider config that was passed with `--config`, the way it was before
+/// `--provider-config` was added, if `--config` holds JSON instead of the path
+/// to the Fiberplane config file
+fn legacy_provider_config(args: &InvokeArguments) -> Option<&str> {
+    if args.provider_config.is_some() {
+        return None;
+    }
+
+    args.config
+        .as_deref()
+        .and_then(Path::to_str)
+        .filter(|config| config.trim_start().starts_with('{'))
+}
+
+async fn handle_query_types_command(args: QueryTypesArguments) -> Result<()> {
+    let config = parse_config(&args.provider_config).context("unable to deserialize config")?;
+    let runtime = load_runtime(&args.provider_path)?;
+
+    let query_types = runtime
+        .get_supported_query_types(config)
+        .await
+        .map_err(|e| anyhow!("unable to invoke provider: {:?}", e))?;
+    output_json(&query_types)
+}
+
+fn handle_config_schema_command(args: ConfigSchemaArguments) -> Result<()> {
+    let runtime = load_runtime(&args.provider_path)?;
+    let schema = runtime
+        .get_config_schema()
+        .map_err(|e| anyhow!("unable to invoke provider: {:?}", e))?;
+
+    let config = match args.validate {
+        Some(config) => config,
+        None => return output_json(&schema),
+    };
+    let config: serde_json::Value =
+        serde_json::from_str(&config).context("unable to deserialize config")?;
+    let errors = schema::validate_config(&serde_json::to_value(&schema)?, &config);
+    if errors.is_empty() {
+        info!("Config is valid");
+        Ok(())
+    } else {
+        bail!("Config is invalid:\n  {}", errors.join("\n  "))
+    }
+}
+
+async fn handle_status_command(args: StatusArguments) -> Result<()> {
+    let config = parse_config(&args.provider_config).context("unable to deserialize config")?;
+    let runtime = load_runtime(&args.provider_path)?;
+
+    let request = ProviderRequest::builder()
+        .query_type(STATUS_QUERY_TYPE.to_string())
+        .query_data(
+            Blob::builder()
+                .data(Vec::new())
+                .mime_type("application/x-www-form-urlencoded".to_string())
+                .build(),
+        )
+        .config(config)
+        .build();
+
+    match runtime.invoke2(request).await {
+        Ok(Ok(blob)) => {
+            info!("Status: {}", String::from_utf8_lossy(blob.data.as_ref()));
+            Ok(())
+        }
+        Ok(Err(err)) => bail!("Provider is not available: {:?}", err),
+        Err(e) => bail!("unable to invoke provider: {:?}", e),
+    }
+}
+
+fn handle_create_cells_command(args: CreateCellsArguments) -> Result<()> {
+    let runtime = load_runtime(&args.provider_path)?;
+    let data = std::fs::read(&args.response)
+        .with_context(|| format!("unable to read {}", args.response.display()))?;
+    let response = Blob::builder().data(data).mime_type(args.mime_type).build();
+
+    match runtime.create_cells(args.query_type, response) {
+        Ok(Ok(cells)) => output_json(&cells),
+        Ok(Err(err)) => bail!("Provider failed: {:?}", err),
+        Err(e) => bail!("unable to invoke provider: {:?}", e),
+    }
+}
+
+/// Load the provider from its WASM module
+pub(crate) fn load_runtime(path: &Path) -> Result<Runtime> {
+    let wasm_module =
+        std::fs::read(path).map_err(|e| anyhow!("unable to read wasm module: {:?}", e))?;
+
+    Runtime::new(wasm_module).map_err(|e| anyhow!("unable to create runtime: {:?}", e))
+}
+
+fn parse_config(json: &str) -> Result<ProviderConfig> {
+    serde_json::from_str(json).map_err(serde_json::Error::into)
+}
@@ -0,0 +1,217 @@
+//! Encoding of queries for providers, based on the schema of the query type.
+//!
+//! Like the config schema, the query schema is handled as JSON.
+
+use anyhow::{anyhow, bail, Context, Result};
+use serde_json::Value;
+use std::path::Path;
+use time::format_description::well_known::Rfc3339;
+use time::OffsetDateTime;
+use url::form_urlencoded;
+
+/// Read a JSON or TOML file, depending on its extension
+pub(crate) fn read_json_or_toml(path: &Path) -> Result<Value> {
+    let content = std::fs::read_to_string(path)
+        .with_context(|| format!("unable to read {}", path.display()))?;
+    if path.extension().map_or(false, |ext| ext == "toml") {
+        toml::from_str(&content).with_context(|| format!("invalid TOML in {}", path.display()))
+    } else {
+        serde_json::from_str(&content)
+            .with_context(|| format!("invalid JSON in {}", path.display()))
+    }
+}
+
+/// Turn an object into query fields. Arrays result in a field for each of
+/// their items.
+pub(crate) fn fields_from_value(value: &Value) -> Result<Vec<(String, String)>> {
+    let object = value
+        .as_object()
+        .ok_or_else(|| anyhow!("query should be an object"))?;
+
+    let mut fields = Vec::new();
+    for (name, value) in object {
+        let values = match value {
+            Value::Array(values) => values.iter().collect(),
+            value => vec![value],
+        };
+        for value in values {
+            let value = match value {
+                Value::String(value) => value.clone(),
+                Value::Null => continue,
+                value => value.to_string(),
+            };
+            fields.push((name.clone(), value));
+        }
+    }
+    Ok(fields)
+}
+
+/// Check the fields against the schema of the query type and encode them as
+/// `application/x-www-form-urlencoded`
+pub(crate) fn encode_query(schema: &Value, fields: &[(String, String)]) -> Result<String> {
+    let schema_fields = schema
+        .as_array()
+        .ok_or_else(|| anyhow!("query schema is not a list of fields"))?;
+
+    if let Some((name, _)) = fields.iter().find(|(name, _)| {
+        !schema_fields
+            .iter()
+            .any(|field| field.get("name").and_then(Value::as_str) == Some(name))
+    }) {
+        bail!("unknown query field `{}`", name);
+    }
+
+    let mut serializer = form_urlencoded::Serializer::new(String::new());
+    for field in schema_fields {
+        let name = match field.get("name").and_then(Value::as_str) {
+            Some(name) => name,
+            None => continue,
+        };
+        let values: Vec<&str> = fields
+            .iter()
+            .filter(|(field_name, _)| field_name == name)
+            .map(|(_, value)| value.as_str())
+            .collect();
+
+        if values.is_empty() {
+            if field.get("required").and_then(Value::as_bool) == Some(true) {
+                bail!("missing required query field `{}`", name);
+            }
+            continue;
+        }
+        let multiple = field.get("multiple").and_then(Value::as_bool) == Some(true);
+        if values.len() > 1 && !multiple {
+            bail!("query field `{}` can only be given once", name);
+        }
+
+        for value in values {
+            if let Some(value) = encode_value(field, name, value)? {
+                serializer.append_pair(name, &value);
+            }
+        }
+    }
+
+    Ok(serializer.finish())
+}
+
+/// Check a single value against its field, and return how it's encoded.
+/// Checkboxes that are not checked are left out.
+fn encode_value(field: &Value, name: &str, value: &str) -> Result<Option<String>> {
+    let field_type = field
+        .get("type")
+        .and_then(Value::as_str)
+        .unwrap_or_default();
+    match field_type {
+        "checkbox" => {
+            let checked = match value {
+                "" | "true" | "yes" | "1" => true,
+                "false" | "no" | "0" => false,
+                _ => bail!("query field `{}` should be true or false", name),
+            };
+            let checked_value = field.get("value").and_then(Value::as_str).unwrap_or("true");
+            Ok(checked.then(|| checked_value.to_string()))
+        }
+        "integer" => {
+            let number: i64 = value
+                .parse()
+                .map_err(|_| anyhow!("query field `{}` should be an integer", name))?;
+            if let Some(min) = field.get("min").and_then(Value::as_i64) {
+                if number < min {
+                    bail!("query field `{}` should be at least {}", name, min);
+                }
+            }
+            if let Some(max) = field.get("max").and_then(Value::as_i64) {
+                if number > max {
+                    bail!("query field `{}` should be at most {}", name, max);
+                }
+            }
+            Ok(Some(number.to_string()))
+        }
+        "select" => {
+            let options = field.get("options").and_then(Value::as_array);
+            if matches!(options, Some(options) if !options.iter().any(|option| option == value)) {
+                bail!("query field `{}` can't be {:?}", name, value);
+            }
+            Ok(Some(value.to_string()))
+        }
+        // Time ranges are sent as two RFC 3339 timestamps separated by a
+        // space, but `from..to` is accepted as well
+        "date_time_range" => {
+            let (from, to) = value
+                .split_once("..")
+                .or_else(|| value.split_once(' '))
+                .ok_or_else(|| anyhow!("query field `{}` should be a range: <from>..<to>", name))?;
+            for timestamp in [from, to] {
+                OffsetDateTime::parse(timestamp.trim(), &Rfc3339).with_context(|| {
+                    format!("query field `{name}` should contain RFC 3339 timestamps")
+                })?;
+            }
+            Ok(Some(format!("{} {}", from.trim(), to.trim())))
+        }
+        _ => Ok(Some(value.to_string())),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn fields(fields: &[(&str, &str)]) -> Vec<(String, String)> {
+        fields
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn encodes_query() {
+        let schema = json!([
+            { "type": "text", "name": "query", "required": true },
+            { "type": "date_time_range", "name": "time_range" },
+            { "type": "checkbox", "name": "live", "value": "on" },
+            { "type": "integer", "name": "limit", "max": 100 },
+            { "type": "select", "name": "labels", "options": ["job", "instance"], "multiple": true },
+        ]);
+
+        assert_eq!(
+            encode_query(
+                &schema,
+                &fields(&[
+                    ("query", "rate(http_requests[5m])"),
+                    ("time_range", "2022-10-19T12:00:00Z..2022-10-19T13:00:00Z"),
+                    ("live", "yes"),
+                    ("labels", "job"),
+                    ("labels", "instance"),
+                ])
+            )
+            .unwrap(),
+            "query=rate%28http_requests%5B5m%5D%29\
+                &time_range=2022-10-19T12%3A00%3A00Z+2022-10-19T13%3A00%3A00Z\
+                &live=on&labels=job&labels=instance"
+        );
+
+        assert!(encode_query(&schema, &fields(&[("limit", "5")])).is_err());
+        assert!(encode_query(&schema, &fields(&[("query", "up"), ("limit", "500")])).is_err());
+        assert!(encode_query(&schema, &fields(&[("query", "up"), ("step", "15s")])).is_err());
+        assert!(encode_query(&schema, &fields(&[("query", "up"), ("labels", "pod")])).is_err());
+        assert_eq!(
+            encode_query(&schema, &fields(&[("query", "up"), ("live", "false")])).unwrap(),
+            "query=up"
+        );
+    }
+
+    #[test]
+    fn converts_objects_to_fields() {
+        assert_eq!(
+            fields_from_value(&json!({ "query": "up", "limit": 10, "labels": ["job", "pod"] }))
+                .unwrap(),
+            fields(&[
+                ("labels", "job"),
+                ("labels", "pod"),
+                ("limit", "10"),
+                ("query", "up"),
+            ])
+        );
+    }
+}
@@ -0,0 +1,431 @@
+//! Rendering of provider results in the terminal.
+//!
+//! Results are rendered based on their MIME type: timeseries are shown as
+//! sparklines, events as a list that is coloured by severity, and other lists
+//! of records as a table. Anything else is printed as JSON, or as it is when
+//! it isn't JSON or MessagePack.
+
+use anyhow::{bail, Context, Result};
+use clap::ValueEnum;
+use crossterm::style::{Color, Stylize};
+use crossterm::tty::IsTty;
+use fiberplane::provider_runtime::spec::types::Blob;
+use serde_json::Value;
+use std::io::Write;
+
+const TIMESERIES_MIME_TYPE: &str = "application/vnd.fiberplane.timeseries";
+const EVENTS_MIME_TYPE: &str = "application/vnd.fiberplane.events";
+
+const SPARKLINE_BARS: [char; 8] = ['▁', '▂', '▃', '▄', '▅', '▆', '▇', '█'];
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BlobOutput {
+    /// Render the result based on its type
+    Pretty,
+
+    /// Output the result exactly as the provider returned it
+    Raw,
+
+    /// Output the result as JSON
+    Json,
+
+    /// Output the result as MessagePack
+    Msgpack,
+}
+
+/// Write the result of a provider to stdout
+pub(crate) fn output_blob(blob: &Blob, output: BlobOutput) -> Result<()> {
+    let data: &[u8] = blob.data.as_ref();
+    let mut stdout = std::io::stdout();
+    match output {
+        BlobOutput::Raw => stdout.write_all(data)?,
+        BlobOutput::Json => match decode(blob)? {
+            Some(value) => writeln!(stdout, "{}", serde_json::to_string_pretty(&value)?)?,
+            None => bail!("result of type {} can't be shown as JSON", blob.mime_type),
+        },
+        BlobOutput::Msgpack => {
+            if blob.mime_type.ends_with("msgpack") {
+                stdout.write_all(data)?;
+            } else {
+                match decode(blob)? {
+                    Some(value) => stdout.write_all(&rmp_serde::to_vec_named(&value)?)?,
+                    None => bail!(
+                        "result of type {} can't be shown as MessagePack",
+                        blob.mime_type
+                    ),
+                }
+            }
+        }
+        BlobOutput::Pretty => {
+            let value = match decode(blob)? {
+                Some(value) => value,
+                None => {
+                    match std::str::from_utf8(data) {
+                        Ok(text) => writeln!(stdout, "{text}")?,
+                        Err(_) => writeln!(stdout, "{}", base64::encode(data))?,
+                    }
+                    return Ok(());
+                }
+            };
+
+            let colors = stdout.is_tty();
+            let width = crossterm::terminal::size()
+                .map(|(cols, _)| cols as usize)
+                .unwrap_or(80);
+            let rendered = if blob.mime_type.starts_with(TIMESERIES_MIME_TYPE) {
+                render_timeseries(&value, width)
+            } else if blob.mime_type.starts_with(EVENTS_MIME_TYPE) {
+                render_events(&value, colors)
+            } else {
+                table_from_value(&value).map(|(columns, rows)| render_table(&columns, &rows))
+            };
+            match rendered {
+                Some(rendered) => write!(stdout, "{rendered}")?,
+                None => writeln!(stdout, "{}", serde_json::to_string_pretty(&value)?)?,
+            }
+        }
+    }
+    stdout.flush()?;
+    Ok(())
+}
+
+/// Decode the blob if it's JSON or MessagePack
+pub(super) fn decode(blob: &Blob) -> Result<Option<Value>> {
+    let data: &[u8] = blob.data.as_ref();
+    if blob.mime_type.ends_with("json") {
+        Ok(Some(
+            serde_json::from_slice(data).context("Result is not valid JSON")?,
+        ))
+    } else if blob.mime_type.ends_with("msgpack") {
+        Ok(Some(
+            rmp_serde::from_slice(data).context("Unable to transcode MessagePack to JSON")?,
+        ))
+    } else {
+        Ok(None)
+    }
+}
+
+/// Render each series with its name and labels, followed by a sparkline of
+/// its values
+fn render_timeseries(value: &Value, width: usize) -> Option<String> {
+    let mut output = String::new();
+    for series in value.as_array()? {
+        let name = series
+            .get("name")
+            .and_then(Value::as_str)
+            .unwrap_or_default();
+        let labels = series
+            .get("labels")
+            .and_then(Value::as_object)
+            .map(|labels| {
+                labels
+                    .iter()
+                    .map(|(key, value)| format!("{}={}", key, cell_text(value)))
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            })
+            .unwrap_or_default();
+        let values: Vec<f64> = series
+            .get("metrics")
+            .and_then(Value::as_array)
+            .map(|metrics| {
+                metrics
+                    .iter()
+                    .map(|metric| {
+                        metric
+                            .get("value")
+                            .and_then(Value::as_f64)
+                            .unwrap_or(f64::NAN)
+                    })
+                    .collect()
+            })
+            .unwrap_or_default();
+
+        if labels.is_empty() {
+            output.push_str(&format!("{name}\n"));
+        } else {
+            output.push_str(&format!("{name}{{{labels}}}\n"));
+        }
+        output.push_str(&format!(
+            "  {}\n",
+            sparkline(&values, width.saturating_sub(2))
+        ));
+
+        let numbers = values.iter().copied().filter(|value| !value.is_nan());
+        if let (Some(min), Some(max), Some(last)) = (
+            numbers.clone().reduce(f64::min),
+            numbers.clone().reduce(f64::max),
+            numbers.last(),
+        ) {
+            output.push_str(&format!("  min {min}  max {max}  last {last}\n"));
+        }
+    }
+    Some(output)
+}
+
+/// Draw the values with block characters, averaging them into buckets if
+/// there are more values than fit the width
+fn sparkline(values: &[f64], width: usize) -> String {
+    let width = width.max(1);
+    let values: Vec<f64> = if values.len() > width {
+        (0..width)
+            .map(|i| {
+                let bucket = &values[i * values.len() / width..(i + 1) * values.len() / width];
+                let numbers: Vec<f64> = bucket.iter().copied().filter(|v| !v.is_nan()).collect();
+                if numbers.is_empty() {
+                    f64::NAN
+                } else {
+                    numbers.iter().sum::<f64>() / numbers.len() as f64
+                }
+            })
+            .collect()
+    } else {
+        values.to_vec()
+    };
+
+    let numbers = values.iter().copied().filter(|value| !value.is_nan());
+    let min = numbers.clone().fold(f64::INFINITY, f64::min);
+    let max = numbers.fold(f64::NEG_INFINITY, f64::max);
+    values
+        .iter()
+        .map(|value| {
+            if value.is_nan() {
+                ' '
+            } else if max > min {
+                let index = ((value - min) / (max - min) * (SPARKLINE_BARS.len() - 1) as f64)
+                    .round() as usize;
+                SPARKLINE_BARS[index]
+            } else {
+                SPARKLINE_BARS[0]
+            }
+        })
+        .collect()
+}
+
+/// Render each event on its own line with its time, severity and title,
+/// followed by its labels and description
+fn render_events(value: &Value, colors: bool) -> Option<String> {
+    let mut output = String::new();
+    for event in value.as_array()? {
+        let time = event
+            .get("time")
+            .and_then(Value::as_str)
+            .unwrap_or_default();
+        let title = event
+            .get("title")
+            .and_then(Value::as_str)
+            .unwrap_or_default();
+        let severity = event
+            .get("severity")
+            .or_else(|| event.pointer("/otel/severityText"))
+            .and_then(Value::as_str)
+            .unwrap_or_default();
+        let severity = format!("{:<5}", severity.to_uppercase());
+        let severity = match severity_color(&severity) {
+            Some(color) if colors => severity.with(color).to_string(),
+            _ => severity,
+        };
+
+        let time = if colors {
+            time.with(Color::DarkGrey).to_string()
+        } else {
+            time.to_string()
+        };
+        output.push_str(&format!("{time}  {severity}  {title}\n"));
+
+        if let Some(labels) = event.get("labels").and_then(Value::as_object) {
+            if !labels.is_empty() {
+                let labels = labels
+                    .iter()
+                    .map(|(key, value)| format!("{}={}", key, cell_text(value)))
+                    .collect::<Vec<_>>()
+                    .join(" ");
+                if colors {
+                    output.push_str(&format!("    {}\n", labels.with(Color::DarkGrey)));
+                } else {
+                    output.push_str(&format!("    {labels}\n"));
+                }
+            }
+        }
+        if let Some(description) = event.get("description").and_then(Value::as_str) {
+            for line in description.lines() {
+                output.push_str(&format!("    {line}\n"));
+            }
+        }
+    }
+    Some(output)
+}
+
+fn severity_color(severity: &str) -> Option<Color> {
+    let severity = severity.trim().to_lowercase();
+    if ["fatal", "crit", "err", "alert", "emerg"]
+        .iter()
+        .any(|prefix| severity.starts_with(prefix))
+    {
+        Some(Color::Red)
+    } else if severity.starts_with("warn") {
+        Some(Color::Yellow)
+    } else if severity.starts_with("info") {
+        Some(Color::Green)
+    } else if severity.starts_with("debug") || severity.starts_with("trace") {
+        Some(Color::DarkGrey)
+    } else {
+        None
+    }
+}
+
+/// Get the columns and rows from either a list of records or an object with
+/// `columns` and `rows`
+fn table_from_value(value: &Value) -> Option<(Vec<String>, Vec<Vec<String>>)> {
+    if let (Some(columns), Some(rows)) = (
+        value.get("columns").and_then(Value::as_array),
+        value.get("rows").and_then(Value::as_array),
+    ) {
+        let columns = columns.iter().map(cell_text).collect();
+        let rows = rows
+            .iter()
+            .map(|row| {
+                row.as_array()
+                    .map(|cells| cells.iter().map(cell_text).collect())
+                    .unwrap_or_default()
+            })
+            .collect();
+        return Some((columns, rows));
+    }
+
+    let records = value.as_array()?;
+    if records.is_empty() || !records.iter().all(Value::is_object) {
+        return None;
+    }
+
+    let mut columns: Vec<String> = Vec::new();
+    for record in records {
+        for key in record.as_object()?.keys() {
+            if !columns.contains(key) {
+                columns.push(key.clone());
+            }
+        }
+    }
+    let rows = records
+        .iter()
+        .map(|record| {
+            columns
+                .iter()
+                .map(|column| record.get(column).map(cell_text).unwrap_or_default())
+                .collect()
+        })
+        .collect();
+    Some((columns, rows))
+}
+
+/// Align the cells in columns. Columns that only contain numbers are aligned
+/// to the right.
+pub(super) fn render_table(columns: &[String], rows: &[Vec<String>]) -> String {
+    let widths: Vec<usize> = columns
+        .iter()
+        .enumerate()
+        .map(|(i, column)| {
+            rows.iter()
+                .filter_map(|row| row.get(i))
+                .chain(std::iter::once(column))
+                .map(|cell| cell.chars().count())
+                .max()
+                .unwrap_or_default()
+        })
+        .collect();
+    let numeric: Vec<bool> = (0..columns.len())
+        .map(|i| {
+            rows.iter()
+                .filter_map(|row| row.get(i))
+                .all(|cell| cell.is_empty() || cell.parse::<f64>().is_ok())
+        })
+        .collect();
+
+    let mut output = String::new();
+    let header = std::iter::once((true, columns));
+    for (is_header, row) in header.chain(rows.iter().map(|row| (false, row.as_slice()))) {
+        let line = widths
+            .iter()
+            .enumerate()
+            .map(|(i, width)| {
+                let cell = row.get(i).map(String::as_str).unwrap_or_default();
+                if numeric[i] && !is_header {
+                    format!("{cell:>width$}")
+                } else {
+                    format!("{cell:<width$}")
+                }
+            })
+            .collect::<Vec<_>>()
+            .join("  ");
+        output.push_str(line.trim_end());
+        output.push('\n');
+    }
+    output
+}
+
+fn cell_text(value: &Value) -> String {
+    match value {
+        Value::String(text) => text.clone(),
+        Value::Null => String::new(),
+        value => value.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn renders_tables() {
+        let (columns, rows) = table_from_value(&json!([
+            { "name": "api", "requests": 1200 },
+            { "name": "frontend", "requests": 15, "errors": 2 },
+        ]))
+        .unwrap();
+        assert_eq!(
+            render_table(&columns, &rows),
+            "name      requests  errors\n\
+             api           1200\n\
+             frontend        15       2\n"
+        );
+    }
+
+    #[test]
+    fn renders_timeseries() {
+        assert_eq!(
+            sparkline(&[0.0, 1.0, 2.0, 3.0, 4.0, 5.0, 6.0, 7.0], 80),
+            "▁▂▃▄▅▆▇█"
+        );
+        assert_eq!(sparkline(&[0.0, 0.0, 7.0, 7.0], 2), "▁█");
+        assert_eq!(sparkline(&[3.0, f64::NAN, 3.0], 80), "▁ ▁");
+
+        let timeseries = json!([{
+            "name": "up",
+            "labels": { "job": "api" },
+            "metrics": [
+                { "time": "2022-10-19T12:00:00Z", "value": 1.0 },
+                { "time": "2022-10-19T12:01:00Z", "value": 0.0 },
+            ],
+        }]);
+        assert_eq!(
+            render_timeseries(&timeseries, 80).unwrap(),
+            "up{job=api}\n  █▁\n  min 0  max 1  last 0\n"
+        );
+    }
+
+    #[test]
+    fn renders_events() {
+        let events = json!([{
+            "time": "2022-10-19T12:00:00Z",
+            "title": "Deployment failed",
+            "description": "Image not found",
+            "severity": "error",
+            "labels": { "service": "api" },
+        }]);
+        assert_eq!(
+            render_events(&events, false).unwrap(),
+            "2022-10-19T12:00:00Z  ERROR  Deployment failed\n    service=api\n    Image not found\n"
+        );
+    }
+}
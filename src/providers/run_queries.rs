@@ -0,0 +1,461 @@
+//! Running the queries of a notebook with local providers:
+//! `fp notebooks run-queries` invokes the provider of every provider cell and
+//! adds the results to the notebook, or prints them.
+//!
+//! The intent of a provider cell is `<provider type>,<query type>`, such as
+//! `prometheus,timeseries`. The provider is loaded from the provider directory
+//! and gets the config of the data source the notebook selected for the
+//! provider type, or of the only data source of that type in the workspace.
+//!
+//! Results are appended below a heading with the time of the run, as the cells
+//! the provider creates for them. The original cells are left untouched, and
+//! so are the results of earlier runs: every run appends a new section.
+
+use super::load_runtime;
+use super::query::read_json_or_toml;
+use super::render::{decode, output_blob, BlobOutput};
+use crate::config::api_client_configuration;
+use crate::fp_urls::NotebookUrlBuilder;
+use crate::interactive::{notebook_picker, workspace_picker};
+use crate::output::output_json;
+use crate::KeyValueArgument;
+use anyhow::{anyhow, bail, Context, Result};
+use clap::{Parser, ValueEnum, ValueHint};
+use fiberplane::api_client::{data_source_list, notebook_cells_append, notebook_get};
+use fiberplane::base64uuid::Base64Uuid;
+use fiberplane::models::data_sources::DataSource;
+use fiberplane::models::notebooks::{Cell, HeadingCell, HeadingType, ProviderCell, TextCell};
+use fiberplane::provider_runtime::spec::types::{Blob, ProviderConfig, ProviderRequest};
+use fiberplane::provider_runtime::spec::Runtime;
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use time::format_description::well_known::Rfc3339;
+use time::OffsetDateTime;
+use tracing::{info, warn};
+use url::Url;
+
+const FORM_MIME_TYPE: &str = "application/x-www-form-urlencoded";
+
+#[derive(Parser)]
+pub struct Arguments {
+    /// ID of the notebook to run the queries of
+    notebook_id: Option<Base64Uuid>,
+
+    /// Directory with the provider WASM files, named after their provider
+    /// type (such as `prometheus.wasm` or `prometheus_provider.wasm`)
+    #[clap(long, value_hint = ValueHint::DirPath)]
+    provider_dir: PathBuf,
+
+    /// Config to use for a provider type instead of the config of its data
+    /// source, as a JSON or TOML file (you can specify multiple configs)
+    #[clap(long, value_name = "TYPE=FILE")]
+    provider_config: Vec<KeyValueArgument>,
+
+    /// Only run the query of this cell (you can specify multiple cells)
+    #[clap(long)]
+    cell_id: Vec<String>,
+
+    /// Where to write the results
+    #[clap(long, short, default_value = "notebook", value_enum)]
+    output: RunQueriesOutput,
+
+    #[clap(from_global)]
+    workspace_id: Option<Base64Uuid>,
+
+    #[clap(from_global)]
+    base_url: Url,
+
+    #[clap(from_global)]
+    config: Option<PathBuf>,
+}
+
+#[derive(ValueEnum, Clone, PartialEq)]
+enum RunQueriesOutput {
+    /// Append the results to the notebook, below the results of earlier runs
+    Notebook,
+
+    /// Show the results in the terminal
+    Print,
+
+    /// Output the results as JSON
+    Json,
+}
+
+#[derive(Debug, PartialEq)]
+struct Intent<'a> {
+    provider_type: &'a str,
+    query_type: &'a str,
+}
+
+/// Outcome of running the query of a provider cell
+struct QueryResult<'a> {
+    cell: &'a ProviderCell,
+    query_type: &'a str,
+    result: Result<Blob>,
+}
+
+pub async fn handle_command(args: Arguments) -> Result<()> {
+    let client = api_client_configuration(args.config, args.base_url.clone()).await?;
+    let workspace_id = workspace_picker(&client, args.workspace_id).await?;
+    let notebook_id = notebook_picker(&client, args.notebook_id, Some(workspace_id)).await?;
+    let notebook = notebook_get(&client, notebook_id).await?;
+
+    let cells: Vec<&ProviderCell> = notebook
+        .cells
+        .iter()
+        .filter_map(|cell| match cell {
+            Cell::Provider(cell) => Some(cell),
+            _ => None,
+        })
+        .filter(|cell| args.cell_id.is_empty() || args.cell_id.contains(&cell.id))
+        .collect();
+    if cells.is_empty() {
+        info!("The notebook has no provider cells");
+        return Ok(());
+    }
+
+    let mut configs = HashMap::new();
+    for kv in &args.provider_config {
+        let config = read_json_or_toml(Path::new(&kv.value))?;
+        configs.insert(kv.key.clone(), config);
+    }
+    let data_sources = data_source_list(&client, workspace_id).await?;
+
+    let mut runtimes: HashMap<String, Runtime> = HashMap::new();
+    let mut results = Vec::with_capacity(cells.len());
+    for cell in cells {
+        let intent = match parse_intent(&cell.intent) {
+            Ok(intent) => intent,
+            Err(err) => {
+                warn!("Skipping cell {}: {:#}", cell.id, err);
+                continue;
+            }
+        };
+
+        let selected = notebook
+            .selected_data_sources
+            .get(intent.provider_type)
+            .map(|selected| selected.name.to_string());
+        let result = match provider_config(
+            &configs,
+            &data_sources,
+            intent.provider_type,
+            selected.as_deref(),
+        ) {
+            Ok(config) => run_query(&args.provider_dir, &mut runtimes, config, cell, &intent).await,
+            Err(err) => Err(err),
+        };
+
+        if let Err(err) = &result {
+            warn!("Query of cell {} failed: {:#}", cell.id, err);
+        }
+        results.push(QueryResult {
+            cell,
+            query_type: intent.query_type,
+            result,
+        });
+    }
+
+    let failed = results.iter().filter(|r| r.result.is_err()).count();
+    match args.output {
+        RunQueriesOutput::Print => {
+            for result in &results {
+                if let Ok(blob) = &result.result {
+                    info!("{}", cell_name(result.cell));
+                    output_blob(blob, BlobOutput::Pretty)?;
+                }
+            }
+        }
+        RunQueriesOutput::Json => {
+            let output: Vec<Value> = results
+                .iter()
+                .map(|result| match &result.result {
+                    Ok(blob) => json!({
+                        "cell_id": result.cell.id,
+                        "intent": result.cell.intent,
+                        "mime_type": blob.mime_type,
+                        "result": decode(blob).ok().flatten(),
+                    }),
+                    Err(err) => json!({
+                        "cell_id": result.cell.id,
+                        "intent": result.cell.intent,
+                        "error": format!("{:#}", err),
+                    }),
+                })
+                .collect();
+            output_json(&output)?;
+        }
+        RunQueriesOutput::Notebook if failed < results.len() => {
+            let mut cells = vec![Cell::Heading(
+                HeadingCell::builder()
+                    .id(String::new())
+                    .heading_type(HeadingType::H3)
+                    .content(format!(
+                        "Query results of {}",
+                        OffsetDateTime::now_utc()
+                            .replace_nanosecond(0)?
+                            .format(&Rfc3339)?
+                    ))
+                    .build(),
+            )];
+            for result in &results {
+                cells.extend(result_cells(&runtimes, result)?);
+            }
+            notebook_cells_append(&client, notebook_id, None, None, cells).await?;
+
+            let url = NotebookUrlBuilder::new(workspace_id, notebook_id)
+                .base_url(args.base_url)
+                .url()?;
+            info!(
+                "Added the results of {} queries to the notebook: {}",
+                results.len() - failed,
+                url
+            );
+        }
+        RunQueriesOutput::Notebook => {}
+    }
+
+    if failed > 0 {
+        bail!("{} of {} queries failed", failed, results.len());
+    }
+    Ok(())
+}
+
+/// Get the config from the `--provider-config` files, or from the data source
+fn provider_config(
+    configs: &HashMap<String, Value>,
+    data_sources: &[DataSource],
+    provider_type: &str,
+    selected: Option<&str>,
+) -> Result<ProviderConfig> {
+    let config = match configs.get(provider_type) {
+        Some(config) => config.clone(),
+        None => data_source_config(data_sources, provider_type, selected)?,
+    };
+    serde_json::from_value(config).context("unable to deserialize config")
+}
+
+/// Invoke the provider with the query of the cell, loading the provider if
+/// it wasn't loaded for an earlier cell
+async fn run_query(
+    provider_dir: &Path,
+    runtimes: &mut HashMap<String, Runtime>,
+    config: ProviderConfig,
+    cell: &ProviderCell,
+    intent: &Intent<'_>,
+) -> Result<Blob> {
+    if !runtimes.contains_key(intent.provider_type) {
+        let path = provider_path(provider_dir, intent.provider_type)?;
+        runtimes.insert(intent.provider_type.to_string(), load_runtime(&path)?);
+    }
+    let runtime = &runtimes[intent.provider_type];
+
+    let (mime_type, data) = parse_query_data(cell.query_data.as_deref().unwrap_or(""));
+    let request = ProviderRequest::builder()
+        .query_type(intent.query_type.to_string())
+        .query_data(
+            Blob::builder()
+                .data(data.as_bytes().to_vec())
+                .mime_type(mime_type.to_string())
+                .build(),
+        )
+        .config(config)
+        .build();
+    match runtime.invoke2(request).await {
+        Ok(Ok(blob)) => Ok(blob),
+        Ok(Err(err)) => bail!("Provider failed: {:?}", err),
+        Err(e) => bail!("unable to invoke provider: {:?}", e),
+    }
+}
+
+/// Cells showing the result of a query: a line saying which cell it's for,
+/// followed by the cells the provider creates for the result
+fn result_cells(runtimes: &HashMap<String, Runtime>, result: &QueryResult) -> Result<Vec<Cell>> {
+    let blob = match &result.result {
+        Ok(blob) => blob,
+        Err(_) => return Ok(Vec::new()),
+    };
+    let mut cells = vec![text_cell(format!("Results of {}", cell_name(result.cell)))];
+
+    let provider_type = parse_intent(&result.cell.intent)?.provider_type;
+    match runtimes[provider_type].create_cells(result.query_type.to_string(), blob.clone()) {
+        Ok(Ok(output)) => {
+            for cell in output {
+                // The cells of the provider are converted to the cells of the API
+                let cell: Cell = serde_json::from_value(serde_json::to_value(cell)?)?;
+                cells.push(cell.with_id(""));
+            }
+        }
+        Ok(Err(err)) => cells.push(text_cell(format!("Unable to show the results: {:?}", err))),
+        Err(e) => cells.push(text_cell(format!("Unable to show the results: {:?}", e))),
+    }
+    Ok(cells)
+}
+
+fn text_cell(content: String) -> Cell {
+    Cell::Text(
+        TextCell::builder()
+            .id(String::new())
+            .content(content)
+            .build(),
+    )
+}
+
+fn cell_name(cell: &ProviderCell) -> String {
+    if cell.title.is_empty() {
+        format!("`{}` (cell {})", cell.intent, cell.id)
+    } else {
+        format!("{} (cell {})", cell.title, cell.id)
+    }
+}
+
+fn parse_intent(intent: &str) -> Result<Intent> {
+    match intent.split_once(',') {
+        Some((provider_type, query_type))
+            if !provider_type.is_empty() && !query_type.is_empty() =>
+        {
+            Ok(Intent {
+                provider_type,
+                query_type,
+            })
+        }
+        _ => bail!(
+            "invalid intent `{}`, expected <provider type>,<query type>",
+            intent
+        ),
+    }
+}
+
+/// Split the query data of a provider cell, which is stored as
+/// `<mime type>,<data>`
+fn parse_query_data(query_data: &str) -> (&str, &str) {
+    match query_data.split_once(',') {
+        Some((mime_type, data)) if mime_type.contains('/') => (mime_type, data),
+        _ => (FORM_MIME_TYPE, query_data),
+    }
+}
+
+fn provider_path(provider_dir: &Path, provider_type: &str) -> Result<PathBuf> {
+    let candidates = [
+        format!("{provider_type}.wasm"),
+        format!("{provider_type}_provider.wasm"),
+        format!("{provider_type}-provider.wasm"),
+    ];
+    candidates
+        .iter()
+        .map(|name| provider_dir.join(name))
+        .find(|path| path.is_file())
+        .ok_or_else(|| {
+            anyhow!(
+                "no provider for `{}` in {} (looked for {})",
+                provider_type,
+                provider_dir.display(),
+                candidates.join(", ")
+            )
+        })
+}
+
+fn data_source_config(
+    data_sources: &[DataSource],
+    provider_type: &str,
+    selected: Option<&str>,
+) -> Result<Value> {
+    let candidates: Vec<(String, &str)> = data_sources
+        .iter()
+        .map(|data_source| {
+            (
+                data_source.name.to_string(),
+                data_source.provider_type.as_str(),
+            )
+        })
+        .collect();
+    let index = pick_data_source(&candidates, provider_type, selected)?;
+    let data_source = &data_sources[index];
+    match &data_source.config {
+        Some(config) => Ok(Value::Object(config.clone())),
+        None => bail!(
+            "config of data source {} is not available (it might be provided by a daemon), use --provider-config {}=<file>",
+            data_source.name,
+            provider_type
+        ),
+    }
+}
+
+/// Pick the data source the notebook selected for the provider type, or the
+/// only data source of that type
+fn pick_data_source(
+    data_sources: &[(String, &str)],
+    provider_type: &str,
+    selected: Option<&str>,
+) -> Result<usize> {
+    let mut matching = data_sources
+        .iter()
+        .enumerate()
+        .filter(|(_, (_, data_source_type))| *data_source_type == provider_type);
+
+    if let Some(selected) = selected {
+        return matching
+            .find(|(_, (name, _))| name == selected)
+            .map(|(index, _)| index)
+            .ok_or_else(|| anyhow!("data source {} was not found", selected));
+    }
+
+    match (matching.next(), matching.next()) {
+        (Some((index, _)), None) => Ok(index),
+        (None, _) => bail!(
+            "no data source for `{}`, use --provider-config {}=<file>",
+            provider_type,
+            provider_type
+        ),
+        (Some(_), Some(_)) => bail!(
+            "multiple data sources for `{}`, select one in the notebook or use --provider-config {}=<file>",
+            provider_type,
+            provider_type
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_cells() {
+        assert_eq!(
+            parse_intent("prometheus,timeseries").unwrap(),
+            Intent {
+                provider_type: "prometheus",
+                query_type: "timeseries"
+            }
+        );
+        assert!(parse_intent("prometheus").is_err());
+        assert!(parse_intent(",timeseries").is_err());
+
+        assert_eq!(
+            parse_query_data("application/x-www-form-urlencoded,query=sum(up)"),
+            (FORM_MIME_TYPE, "query=sum(up)")
+        );
+        assert_eq!(parse_query_data("query=a,b"), (FORM_MIME_TYPE, "query=a,b"));
+    }
+
+    #[test]
+    fn picks_data_sources() {
+        let data_sources = vec![
+            ("prod-prometheus".to_string(), "prometheus"),
+            ("elasticsearch".to_string(), "elasticsearch"),
+            ("dev-prometheus".to_string(), "prometheus"),
+        ];
+        assert_eq!(
+            pick_data_source(&data_sources, "elasticsearch", None).unwrap(),
+            1
+        );
+        assert_eq!(
+            pick_data_source(&data_sources, "prometheus", Some("dev-prometheus")).unwrap(),
+            2
+        );
+        assert!(pick_data_source(&data_sources, "prometheus", None).is_err());
+        assert!(pick_data_source(&data_sources, "loki", None).is_err());
+        assert!(pick_data_source(&data_sources, "elasticsearch", Some("dev-prometheus")).is_err());
+    }
+}
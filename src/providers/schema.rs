@@ -0,0 +1,123 @@
+//! Validation of provider configs against the schema the provider advertises.
+//!
+//! The schema is handled as JSON, so fields of types that are added to the
+//! protocol later are accepted rather than rejected.
+
+use serde_json::Value;
+
+/// Check the config against the schema and return a message for each
+/// problem that was found
+pub(crate) fn validate_config(schema: &Value, config: &Value) -> Vec<String> {
+    let fields = match schema.as_array() {
+        Some(fields) => fields,
+        None => return vec!["Schema is not a list of fields".to_string()],
+    };
+    let config = match config.as_object() {
+        Some(config) => config,
+        None => return vec!["Config is not a JSON object".to_string()],
+    };
+
+    let mut errors = Vec::new();
+    for field in fields {
+        let name = match field.get("name").and_then(Value::as_str) {
+            Some(name) => name,
+            None => continue,
+        };
+        let required = field
+            .get("required")
+            .and_then(Value::as_bool)
+            .unwrap_or(false);
+
+        let value = match config.get(name) {
+            Some(Value::Null) | None => {
+                if required {
+                    errors.push(format!("Missing required field `{name}`"));
+                }
+                continue;
+            }
+            Some(value) => value,
+        };
+
+        match field
+            .get("type")
+            .and_then(Value::as_str)
+            .unwrap_or_default()
+        {
+            "checkbox" if !value.is_boolean() => {
+                errors.push(format!("Field `{name}` should be true or false"))
+            }
+            "integer" => match value.as_i64() {
+                Some(number) => {
+                    if let Some(min) = field.get("min").and_then(Value::as_i64) {
+                        if number < min {
+                            errors.push(format!("Field `{name}` should be at least {min}"));
+                        }
+                    }
+                    if let Some(max) = field.get("max").and_then(Value::as_i64) {
+                        if number > max {
+                            errors.push(format!("Field `{name}` should be at most {max}"));
+                        }
+                    }
+                }
+                None => errors.push(format!("Field `{name}` should be an integer")),
+            },
+            "text" if !value.is_string() => {
+                errors.push(format!("Field `{name}` should be a string"))
+            }
+            "select" => {
+                let options = field.get("options").and_then(Value::as_array);
+                let values = match value {
+                    Value::Array(values) => values.iter().collect(),
+                    value => vec![value],
+                };
+                for value in values {
+                    if matches!(options, Some(options) if !options.contains(value)) {
+                        errors.push(format!("Field `{name}` can't be {value}"));
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+
+    errors
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn validates_config() {
+        let schema = json!([
+            { "type": "text", "name": "url", "required": true },
+            { "type": "checkbox", "name": "insecure" },
+            { "type": "integer", "name": "timeout", "min": 1, "max": 60 },
+            { "type": "select", "name": "method", "options": ["GET", "POST"] },
+            { "type": "date_time_range", "name": "range" },
+        ]);
+
+        assert_eq!(
+            validate_config(&schema, &json!({ "url": "http://localhost:9090" })),
+            Vec::<String>::new()
+        );
+        assert_eq!(
+            validate_config(
+                &schema,
+                &json!({
+                    "insecure": "yes",
+                    "timeout": 90,
+                    "method": "PUT",
+                    "range": "whatever",
+                })
+            ),
+            vec![
+                "Missing required field `url`",
+                "Field `insecure` should be true or false",
+                "Field `timeout` should be at most 60",
+                "Field `method` can't be \"PUT\"",
+            ]
+        );
+    }
+}
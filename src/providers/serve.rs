@@ -0,0 +1,434 @@
+//! A development server for providers: `fp providers serve` loads a provider,
+//! reloads it whenever the WASM file is rebuilt, and lets it be called over
+//! HTTP or from a prompt in the terminal.
+//!
+//! The HTTP endpoints mirror the calls of the provider protocol:
+//!
+//! - `POST /invoke` with a JSON or MessagePack encoded `ProviderRequest`
+//!   returns the blob the provider responded with
+//! - `POST /query-types` with a JSON encoded config returns the supported
+//!   query types
+//! - `GET /config-schema` returns the schema of the config
+//! - `POST /create-cells?query_type=<type>` with a response of the provider
+//!   returns the cells created for it
+//!
+//! Errors returned by the provider are sent as JSON with status 422, and
+//! errors invoking the provider at all (such as traps) with status 500.
+//!
+//! These routes only exist for this development server, and are named after
+//! the calls of the provider runtime. They are not the protocol of the
+//! Fiberplane daemon (`fpd`): the daemon doesn't serve providers over HTTP, it
+//! receives the calls from Fiberplane over its own connection. So the server is
+//! meant for trying out a provider with tools such as `curl`, and can't be
+//! used as a data source in Fiberplane.
+
+use super::render::{output_blob, BlobOutput};
+use super::{load_runtime, parse_config};
+use anyhow::{anyhow, Context, Result};
+use clap::{Parser, ValueHint};
+use fiberplane::provider_runtime::spec::types::{
+    Blob, Error as ProviderError, ProviderConfig, ProviderRequest,
+};
+use fiberplane::provider_runtime::spec::Runtime;
+use hyper::header::{self, HeaderValue};
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Method, Request, Response, Server, StatusCode};
+use qstring::QString;
+use serde::Serialize;
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+use tokio::io::{AsyncBufReadExt, BufReader};
+use tokio::signal;
+use tokio::sync::{mpsc, oneshot};
+use tracing::{error, info, warn};
+
+/// How often the WASM file is checked for changes
+const WATCH_INTERVAL: Duration = Duration::from_millis(500);
+
+const FORM_MIME_TYPE: &str = "application/x-www-form-urlencoded";
+const JSON_MIME_TYPE: &str = "application/json";
+
+const REPL_HELP: &str = "Commands:
+  query-types                List the query types the provider supports
+  schema                     Show the schema of the provider config
+  invoke <type> [<query>]    Invoke the provider, with the query encoded as
+                             application/x-www-form-urlencoded (a=1&b=2)
+  help                       Show this help
+  exit                       Stop the server";
+
+#[derive(Parser)]
+pub struct Arguments {
+    /// Path to the provider WASM file
+    #[clap(long, short, value_hint = ValueHint::FilePath)]
+    provider_path: PathBuf,
+
+    /// Address to serve the provider on
+    #[clap(long, short, default_value = "127.0.0.1:3030")]
+    listen: SocketAddr,
+
+    /// JSON encoded config that is used for calls from the prompt
+    #[clap(long, default_value = "{}", value_name = "JSON")]
+    provider_config: String,
+}
+
+/// A line that was entered at the prompt
+enum ReplCommand {
+    /// Call the provider
+    Call(Call),
+    /// Show text in the terminal, such as the help
+    Print(&'static str),
+    Exit,
+    /// An empty line
+    Nothing,
+}
+
+/// A call to the provider, from either the HTTP server or the prompt
+enum Call {
+    Invoke(ProviderRequest),
+    QueryTypes(ProviderConfig),
+    ConfigSchema,
+    CreateCells { query_type: String, response: Blob },
+}
+
+#[derive(Debug)]
+enum CallError {
+    /// The provider returned an error
+    Provider(ProviderError),
+    /// The provider could not be called, for example because it trapped
+    Runtime(String),
+}
+
+type CallResult = Result<Blob, CallError>;
+
+struct Job {
+    call: Call,
+    reply: oneshot::Sender<CallResult>,
+}
+
+/// The provider that is served, which is replaced when the file changes
+struct Provider {
+    path: PathBuf,
+    modified: Option<SystemTime>,
+    runtime: Runtime,
+}
+
+impl Provider {
+    fn load(path: PathBuf) -> Result<Self> {
+        let modified = modified_time(&path);
+        let runtime = load_runtime(&path)?;
+        Ok(Self {
+            path,
+            modified,
+            runtime,
+        })
+    }
+
+    /// Load the provider again if the file changed since it was loaded. If
+    /// it can't be loaded, for example because it is still being written,
+    /// the previous version is kept and we try again on the next check.
+    fn reload_if_changed(&mut self) {
+        let modified = modified_time(&self.path);
+        if modified == self.modified {
+            return;
+        }
+
+        match load_runtime(&self.path) {
+            Ok(runtime) => {
+                self.runtime = runtime;
+                self.modified = modified;
+                info!("Reloaded {}", self.path.display());
+            }
+            Err(err) => warn!("Unable to reload {}: {:?}", self.path.display(), err),
+        }
+    }
+
+    async fn call(&self, call: Call) -> CallResult {
+        match call {
+            Call::Invoke(request) => match self.runtime.invoke2(request).await {
+                Ok(Ok(blob)) => Ok(blob),
+                Ok(Err(err)) => Err(CallError::Provider(err)),
+                Err(err) => Err(CallError::Runtime(format!("{err:?}"))),
+            },
+            Call::QueryTypes(config) => {
+                let query_types = self
+                    .runtime
+                    .get_supported_query_types(config)
+                    .await
+                    .map_err(|err| CallError::Runtime(format!("{err:?}")))?;
+                json_blob(&query_types)
+            }
+            Call::ConfigSchema => {
+                let schema = self
+                    .runtime
+                    .get_config_schema()
+                    .map_err(|err| CallError::Runtime(format!("{err:?}")))?;
+                json_blob(&schema)
+            }
+            Call::CreateCells {
+                query_type,
+                response,
+            } => match self.runtime.create_cells(query_type, response) {
+                Ok(Ok(cells)) => json_blob(&cells),
+                Ok(Err(err)) => Err(CallError::Provider(err)),
+                Err(err) => Err(CallError::Runtime(format!("{err:?}"))),
+            },
+        }
+    }
+}
+
+fn modified_time(path: &Path) -> Option<SystemTime> {
+    std::fs::metadata(path)
+        .and_then(|metadata| metadata.modified())
+        .ok()
+}
+
+fn json_blob(value: &impl Serialize) -> CallResult {
+    let data = serde_json::to_vec(value).map_err(|err| CallError::Runtime(err.to_string()))?;
+    Ok(Blob::builder()
+        .data(data)
+        .mime_type(JSON_MIME_TYPE.to_string())
+        .build())
+}
+
+pub async fn handle_command(args: Arguments) -> Result<()> {
+    let config = parse_config(&args.provider_config).context("unable to deserialize config")?;
+    let mut provider = Provider::load(args.provider_path)?;
+
+    // The runtime is only used from this task, the server sends it jobs
+    let (jobs, mut job_receiver) = mpsc::channel::<Job>(16);
+    let make_service = make_service_fn(move |_| {
+        let jobs = jobs.clone();
+        async move { Ok::<_, Infallible>(service_fn(move |req| handle_request(req, jobs.clone()))) }
+    });
+    let server = Server::try_bind(&args.listen)
+        .with_context(|| format!("Unable to listen on {}", args.listen))?
+        .serve(make_service);
+    info!(
+        "Serving {} on http://{}",
+        provider.path.display(),
+        server.local_addr()
+    );
+    info!("{}", REPL_HELP);
+    let server = tokio::spawn(server);
+
+    let mut lines = BufReader::new(tokio::io::stdin()).lines();
+    let mut interval = tokio::time::interval(WATCH_INTERVAL);
+    loop {
+        tokio::select! {
+            Some(job) = job_receiver.recv() => {
+                provider.reload_if_changed();
+                let _ = job.reply.send(provider.call(job.call).await);
+            }
+            line = lines.next_line() => {
+                let line = match line? {
+                    Some(line) => line,
+                    None => break,
+                };
+                provider.reload_if_changed();
+                match parse_repl_command(&line, &config) {
+                    Ok(ReplCommand::Call(call)) => match provider.call(call).await {
+                        Ok(blob) => output_blob(&blob, BlobOutput::Pretty)?,
+                        Err(err) => error!("{}", call_error_message(&err)),
+                    },
+                    Ok(ReplCommand::Print(text)) => println!("{text}"),
+                    Ok(ReplCommand::Exit) => break,
+                    Ok(ReplCommand::Nothing) => {}
+                    Err(err) => error!("{:?}", err),
+                }
+            }
+            _ = interval.tick() => provider.reload_if_changed(),
+            _ = signal::ctrl_c() => break,
+        }
+    }
+
+    server.abort();
+    Ok(())
+}
+
+/// Parse a line that was entered at the prompt
+fn parse_repl_command(line: &str, config: &ProviderConfig) -> Result<ReplCommand> {
+    let mut parts = line.trim().splitn(3, char::is_whitespace);
+    match parts.next().unwrap_or_default() {
+        "" => Ok(ReplCommand::Nothing),
+        "exit" => Ok(ReplCommand::Exit),
+        "help" => Ok(ReplCommand::Print(REPL_HELP)),
+        "query-types" => Ok(ReplCommand::Call(Call::QueryTypes(config.clone()))),
+        "schema" => Ok(ReplCommand::Call(Call::ConfigSchema)),
+        "invoke" => {
+            let query_type = parts
+                .next()
+                .ok_or_else(|| anyhow!("Usage: invoke <type> [<query>]"))?;
+            let query_data = parts.next().unwrap_or_default().trim();
+            let request = ProviderRequest::builder()
+                .query_type(query_type.to_string())
+                .query_data(
+                    Blob::builder()
+                        .data(query_data.as_bytes().to_vec())
+                        .mime_type(FORM_MIME_TYPE.to_string())
+                        .build(),
+                )
+                .config(config.clone())
+                .build();
+            Ok(ReplCommand::Call(Call::Invoke(request)))
+        }
+        command => Err(anyhow!(
+            "Unknown command: {}. Type `help` to see the commands.",
+            command
+        )),
+    }
+}
+
+fn call_error_message(err: &CallError) -> String {
+    match err {
+        CallError::Provider(err) => format!("Provider returned an error: {err:?}"),
+        CallError::Runtime(err) => format!("Unable to call the provider: {err}"),
+    }
+}
+
+async fn handle_request(
+    req: Request<Body>,
+    jobs: mpsc::Sender<Job>,
+) -> Result<Response<Body>, Infallible> {
+    let response = match parse_request(req).await {
+        Ok(call) => {
+            let (reply, result) = oneshot::channel();
+            if jobs.send(Job { call, reply }).await.is_err() {
+                return Ok(error_response(
+                    StatusCode::SERVICE_UNAVAILABLE,
+                    "The server is shutting down".to_string(),
+                ));
+            }
+            match result.await {
+                Ok(Ok(blob)) => match HeaderValue::from_str(&blob.mime_type) {
+                    Ok(content_type) => response(StatusCode::OK, content_type, blob.data.to_vec()),
+                    Err(_) => error_response(
+                        StatusCode::INTERNAL_SERVER_ERROR,
+                        format!(
+                            "The provider returned an invalid MIME type: {:?}",
+                            blob.mime_type
+                        ),
+                    ),
+                },
+                Ok(Err(CallError::Provider(err))) => response(
+                    StatusCode::UNPROCESSABLE_ENTITY,
+                    HeaderValue::from_static(JSON_MIME_TYPE),
+                    serde_json::to_vec(&err).unwrap_or_default(),
+                ),
+                Ok(Err(err @ CallError::Runtime(_))) => {
+                    error_response(StatusCode::INTERNAL_SERVER_ERROR, call_error_message(&err))
+                }
+                Err(_) => error_response(
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    "The call was dropped".to_string(),
+                ),
+            }
+        }
+        Err((status, message)) => error_response(status, message),
+    };
+    Ok(response)
+}
+
+/// Decode an HTTP request into a call to the provider
+async fn parse_request(req: Request<Body>) -> Result<Call, (StatusCode, String)> {
+    let bad_request = |err: &dyn std::fmt::Display| (StatusCode::BAD_REQUEST, err.to_string());
+
+    let method = req.method().clone();
+    let path = req.uri().path().to_string();
+    let query = QString::from(req.uri().query().unwrap_or_default());
+    let content_type = req
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .unwrap_or(JSON_MIME_TYPE)
+        .to_string();
+    let body = hyper::body::to_bytes(req.into_body())
+        .await
+        .map_err(|err| bad_request(&err))?;
+
+    match (method, path.as_str()) {
+        (Method::POST, "/invoke") => {
+            let request = if content_type.ends_with("msgpack") {
+                rmp_serde::from_slice(&body).map_err(|err| bad_request(&err))?
+            } else {
+                serde_json::from_slice(&body).map_err(|err| bad_request(&err))?
+            };
+            Ok(Call::Invoke(request))
+        }
+        (Method::POST, "/query-types") => {
+            let config = serde_json::from_slice(&body).map_err(|err| bad_request(&err))?;
+            Ok(Call::QueryTypes(config))
+        }
+        (Method::GET, "/config-schema") => Ok(Call::ConfigSchema),
+        (Method::POST, "/create-cells") => {
+            let query_type = query
+                .get("query_type")
+                .ok_or_else(|| bad_request(&"Missing query_type parameter"))?;
+            Ok(Call::CreateCells {
+                query_type: query_type.to_string(),
+                response: Blob::builder()
+                    .data(body.to_vec())
+                    .mime_type(content_type)
+                    .build(),
+            })
+        }
+        _ => Err((StatusCode::NOT_FOUND, format!("Not found: {path}"))),
+    }
+}
+
+fn response(status: StatusCode, content_type: HeaderValue, body: Vec<u8>) -> Response<Body> {
+    let mut response = Response::new(Body::from(body));
+    *response.status_mut() = status;
+    response
+        .headers_mut()
+        .insert(header::CONTENT_TYPE, content_type);
+    response
+}
+
+fn error_response(status: StatusCode, message: String) -> Response<Body> {
+    response(
+        status,
+        HeaderValue::from_static(JSON_MIME_TYPE),
+        serde_json::json!({ "message": message })
+            .to_string()
+            .into_bytes(),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_repl_commands() {
+        let config: ProviderConfig = serde_json::from_str("{}").unwrap();
+        assert!(matches!(
+            parse_repl_command("query-types", &config),
+            Ok(ReplCommand::Call(Call::QueryTypes(_)))
+        ));
+        assert!(matches!(
+            parse_repl_command("help", &config),
+            Ok(ReplCommand::Print(REPL_HELP))
+        ));
+        assert!(matches!(
+            parse_repl_command("exit", &config),
+            Ok(ReplCommand::Exit)
+        ));
+        assert!(matches!(
+            parse_repl_command("  ", &config),
+            Ok(ReplCommand::Nothing)
+        ));
+        assert!(parse_repl_command("invoke", &config).is_err());
+        assert!(parse_repl_command("frobnicate", &config).is_err());
+
+        match parse_repl_command("invoke timeseries query=up&step=15s", &config) {
+            Ok(ReplCommand::Call(Call::Invoke(request))) => {
+                assert_eq!(request.query_type, "timeseries");
+                assert_eq!(&request.query_data.data[..], b"query=up&step=15s");
+                assert_eq!(request.query_data.mime_type, FORM_MIME_TYPE);
+            }
+            _ => panic!("expected an invocation"),
+        }
+    }
+}
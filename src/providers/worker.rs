@@ -0,0 +1,42 @@
+//! Running providers in a separate `fp` process.
+//!
+//! A worker is started with the same arguments as the current process, and
+//! with an environment variable that tells it which part of the command to
+//! run. It writes its results to its standard output, so the parent process
+//! can read them, while its logs are shown as usual.
+//!
+//! This way, the memory a provider uses can be measured without the memory of
+//! other providers that were loaded before, and a provider that doesn't return
+//! can be stopped, even if it's stuck in a loop that never yields. When the
+//! requests of the provider are recorded or replayed, the proxy is only set in
+//! the environment of the worker.
+
+use super::cassette::Cassette;
+use anyhow::{Context, Result};
+use std::process::Stdio;
+use tokio::process::{Child, Command};
+
+const WORKER_ENV_VAR: &str = "__FP_PROVIDER_WORKER";
+
+/// The task this process was started for, if it is a worker
+pub(super) fn task() -> Option<String> {
+    std::env::var(WORKER_ENV_VAR).ok()
+}
+
+/// Start a worker for the given task. Its standard input and output are piped,
+/// and it is killed when the returned child is dropped.
+pub(super) fn spawn(task: &str, cassette: Option<&Cassette>) -> Result<Child> {
+    let mut command = Command::new(std::env::current_exe()?);
+    command
+        .args(std::env::args_os().skip(1))
+        .env(WORKER_ENV_VAR, task)
+        .env("DISABLE_VERSION_CHECK", "true")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .kill_on_drop(true);
+    if let Some(cassette) = cassette {
+        cassette.set_proxy(&mut command);
+    }
+
+    command.spawn().context("unable to start a worker process")
+}
@@ -1,15 +1,28 @@
-use super::parse_logs::{contains_logs, parse_logs};
-use anyhow::{anyhow, Context, Result};
+use super::parse_logs::{contains_logs, parse_logs, ParseOptions};
+use super::truncate::{truncate_events, truncate_text, OutputLimits};
+use crate::ansi::{redact_escaped, strip_escape_sequences, StyledText};
+use crate::redact::Redactor;
+use crate::spool::{new_cell_id, Destination, Operation};
+use anyhow::Result;
 use bytes::Bytes;
-use fiberplane::api_client::clients::ApiClient;
-use fiberplane::api_client::notebook_cells_append;
-use fiberplane::base64uuid::Base64Uuid;
 use fiberplane::models::notebooks;
 use fiberplane::models::notebooks::{Cell, CodeCell, LogCell, TextCell};
+use fiberplane::models::providers::ProviderEvent;
 use std::env::current_dir;
 use std::vec;
 use time::{format_description::well_known::Rfc3339, OffsetDateTime};
-use tracing::debug;
+use tracing::{debug, info};
+
+const FULL_OUTPUT_FILE_NAME: &str = "output.txt";
+
+/// How the output of the command is written to the notebook
+pub struct OutputOptions {
+    pub limits: OutputLimits,
+    /// Upload the complete output as a file when it needs to be truncated
+    pub attach_full_output: bool,
+    /// Keep the colours and text styles of the output as formatting
+    pub colors: bool,
+}
 
 #[derive(Debug, Clone, Copy, PartialEq)]
 enum CellType {
@@ -19,11 +32,15 @@ enum CellType {
 }
 
 pub struct CellWriter {
-    notebook_id: Base64Uuid,
     command: Vec<String>,
-    client: ApiClient,
+    /// Cells are sent through the spool, so they're retried if sending them
+    /// fails, or written to a local notebook
+    destination: Destination,
     cell: Option<notebooks::Cell>,
     buffer: Vec<u8>,
+    parse_options: ParseOptions,
+    redactor: Redactor,
+    output_options: OutputOptions,
     /// At first, we don't know what type of cell we're writing to.
     /// We'll try to parse the data we get as a log and if it fails
     /// we'll assume we should write to a code cell.
@@ -31,13 +48,21 @@ pub struct CellWriter {
 }
 
 impl CellWriter {
-    pub fn new(config: ApiClient, notebook_id: Base64Uuid, command: Vec<String>) -> Self {
+    pub fn new(
+        destination: Destination,
+        command: Vec<String>,
+        parse_options: ParseOptions,
+        redactor: Redactor,
+        output_options: OutputOptions,
+    ) -> Self {
         Self {
-            notebook_id,
             command,
-            client: config,
+            destination,
             cell: None,
             buffer: Vec::new(),
+            parse_options,
+            redactor,
+            output_options,
             cell_type: CellType::Unknown,
         }
     }
@@ -57,46 +82,80 @@ impl CellWriter {
         self.detect_cell_type();
 
         let output = String::from_utf8_lossy(&self.buffer).to_string();
+        let limits = &self.output_options.limits;
 
-        match self.cell_type {
+        // The complete output, which is uploaded as a file if it had to be
+        // truncated and the user asked for it
+        let full_output = match self.cell_type {
             CellType::Log => {
                 // Prepend a text cell with the "title":
+                let prompt_line = self.prompt_line();
                 let cell = Cell::Text(
                     TextCell::builder()
-                        .id(String::new())
-                        .content(self.prompt_line())
+                        .id(new_cell_id())
+                        .content(self.redactor.redact(&prompt_line))
                         .build(),
                 );
                 self.append_cell(cell).await?;
 
-                // Followed by the log cell itself:
-                let data = parse_logs(&output);
-                let data_link = format!(
-                    "data:application/vnd.fiberplane.events+json,{}",
-                    serde_json::to_string(&data).expect("Could not serialize log records")
-                );
-
-                let cell = Cell::Log(
-                    LogCell::builder()
-                        .id(String::new())
-                        .data_links(vec![data_link])
-                        .read_only(true)
-                        .build(),
-                );
-                let cell = self.append_cell(cell).await?;
+                // Followed by the log cell itself. The output is redacted
+                // once, and used both for the records and for the attachment:
+                let output = self.redactor.redact(&strip_escape_sequences(&output));
+                let data = parse_logs(&output, &self.parse_options);
+                let truncated = truncate_events(&data, limits);
+                let cell = log_cell(truncated.as_deref().unwrap_or(&data)).with_id(&new_cell_id());
+                self.append_cell(cell.clone()).await?;
                 self.cell = Some(cell);
+
+                truncated.map(|_| output)
             }
-            // Create a new code cell
+            // Create a new code cell, or a text cell if the output is styled
             CellType::Code | CellType::Unknown => {
-                let content = format!("{}\n{}", self.prompt_line(), output);
-                let cell = Cell::Code(
-                    CodeCell::builder()
-                        .id(String::new())
-                        .content(content)
-                        .build(),
-                );
-                let cell = self.append_cell(cell).await?;
+                let prompt_line = self.redactor.redact(&self.prompt_line());
+                let output = redact_escaped(&output, &mut self.redactor);
+                let truncated = truncate_text(&output, limits);
+                let styled = StyledText::parse(&format!(
+                    "{}\n{}",
+                    prompt_line,
+                    truncated.as_deref().unwrap_or(&output)
+                ));
+                let cell = if self.output_options.colors && !styled.formatting.is_empty() {
+                    let formatting = styled.code_formatting();
+                    Cell::Text(
+                        TextCell::builder()
+                            .id(new_cell_id())
+                            .content(styled.text)
+                            .formatting(formatting)
+                            .build(),
+                    )
+                } else {
+                    Cell::Code(
+                        CodeCell::builder()
+                            .id(new_cell_id())
+                            .content(styled.text)
+                            .build(),
+                    )
+                };
+                self.append_cell(cell.clone()).await?;
                 self.cell = Some(cell);
+
+                truncated.map(|_| strip_escape_sequences(&output))
+            }
+        };
+
+        if let Some(full_output) = full_output {
+            if self.output_options.attach_full_output {
+                self.destination
+                    .push(Operation::AttachFile {
+                        file_name: FULL_OUTPUT_FILE_NAME.to_string(),
+                        mime_type: "text/plain".to_string(),
+                        content: full_output.into_bytes(),
+                        link_prefix: "Output was truncated, see the ".to_string(),
+                        link_text: "full output".to_string(),
+                    })
+                    .await?;
+            } else {
+                info!("Output was truncated before uploading. Use --attach-full-output to upload the complete output as a file.");
             }
         }
 
@@ -105,8 +164,20 @@ impl CellWriter {
         Ok(())
     }
 
+    pub fn redactor(&self) -> &Redactor {
+        &self.redactor
+    }
+
+    /// Wait for the cells to be sent. If that doesn't work out, they are left
+    /// for `fp sync`.
+    pub async fn finish(&mut self) -> Result<()> {
+        self.destination.finish().await
+    }
+
     pub fn into_output_cell(self) -> Option<notebooks::Cell> {
+        let destination = self.destination;
         self.cell
+            .map(|cell| cell.with_id(destination.cell_id(cell.id())))
     }
 
     /// Try to parse the buffered data as a log and if it fails
@@ -114,7 +185,7 @@ impl CellWriter {
     fn detect_cell_type(&mut self) {
         if self.cell_type == CellType::Unknown {
             if let Ok(string) = std::str::from_utf8(&self.buffer) {
-                if contains_logs(string) {
+                if contains_logs(&strip_escape_sequences(string)) {
                     self.cell_type = CellType::Log;
                     debug!("Detected logs");
                 } else {
@@ -127,14 +198,10 @@ impl CellWriter {
         }
     }
 
-    async fn append_cell(&self, cell: Cell) -> Result<Cell> {
-        let cell = notebook_cells_append(&self.client, self.notebook_id, None, None, vec![cell])
+    async fn append_cell(&mut self, cell: Cell) -> Result<()> {
+        self.destination
+            .push(Operation::AppendCells { cells: vec![cell] })
             .await
-            .with_context(|| "Error appending cell to notebook")?
-            .pop()
-            .ok_or_else(|| anyhow!("No cells returned"))?;
-
-        Ok(cell)
     }
 
     fn prompt_line(&self) -> String {
@@ -145,3 +212,19 @@ impl CellWriter {
         format!("{}\n{} \u{276f} {}", timestamp, cwd, self.command.join(" "),)
     }
 }
+
+/// Create a read-only log cell that embeds the given log records as a data link
+pub(crate) fn log_cell(data: &[ProviderEvent]) -> Cell {
+    let data_link = format!(
+        "data:application/vnd.fiberplane.events+json,{}",
+        serde_json::to_string(data).expect("Could not serialize log records")
+    );
+
+    Cell::Log(
+        LogCell::builder()
+            .id(String::new())
+            .data_links(vec![data_link])
+            .read_only(true)
+            .build(),
+    )
+}
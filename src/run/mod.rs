@@ -1,12 +1,18 @@
-use self::cell_writer::CellWriter;
+use self::cell_writer::{CellWriter, OutputOptions};
+use self::parse_logs::ParseOptions;
+use self::truncate::OutputLimits;
+use crate::local_notebook::{LocalFormat, LocalNotebook};
 use crate::output::{output_details, output_json, GenericKeyValue};
+use crate::redact::Redactor;
 use crate::shell::shell_type::ShellType;
+use crate::spool::{Destination, Spool};
 use crate::{config::api_client_configuration, fp_urls::NotebookUrlBuilder, interactive};
 use anyhow::Result;
 use clap::{Parser, ValueEnum, ValueHint};
 use fiberplane::base64uuid::Base64Uuid;
 use fiberplane::models::notebooks::Cell;
 use futures::StreamExt;
+use regex::Regex;
 use std::io::ErrorKind;
 use std::{env, path::PathBuf, process::Stdio};
 use tokio::io::{self, AsyncWriteExt};
@@ -16,8 +22,9 @@ use tracing::{debug, info};
 use url::Url;
 
 pub mod cell_writer;
-mod parse_logs;
+pub mod parse_logs;
 mod timestamp;
+pub mod truncate;
 
 #[derive(Parser, Clone)]
 pub struct Arguments {
@@ -25,6 +32,58 @@ pub struct Arguments {
     #[clap(long, short, env)]
     notebook_id: Option<Base64Uuid>,
 
+    /// Regular expression that matches the first line of a log record
+    /// (you can specify multiple patterns).
+    ///
+    /// Lines that don't match any of the patterns are appended to the previous
+    /// record, which keeps multi-line records such as stack traces together.
+    /// If none are given, indented lines and common stack trace lines are
+    /// treated as continuations.
+    #[clap(long, value_name = "REGEX")]
+    record_start: Vec<Regex>,
+
+    /// Don't redact secrets and personal information from the output
+    #[clap(long)]
+    no_redact: bool,
+
+    /// Maximum number of bytes of output to upload. Longer output is
+    /// truncated, keeping its start and end. For logs, this limits both the
+    /// body of each record and the size of all records together.
+    #[clap(long, default_value = "1000000")]
+    max_bytes: usize,
+
+    /// Maximum number of lines of output to upload. Longer output is
+    /// truncated, keeping its start and end.
+    #[clap(long, default_value = "10000")]
+    max_lines: usize,
+
+    /// Maximum number of log records to upload. Additional records are
+    /// omitted, keeping the first and last ones.
+    #[clap(long, default_value = "10000")]
+    max_events: usize,
+
+    /// Upload the complete output as a file attached to the notebook when it
+    /// was truncated, and link to it from the notebook
+    #[clap(long)]
+    attach_full_output: bool,
+
+    /// Don't keep the colours and text styles of the output. By default,
+    /// styled output is written to a text cell where the styles are kept as
+    /// formatting, and colours are shown as highlights. All colours get the
+    /// same highlight, since notebooks don't support colours.
+    #[clap(long)]
+    no_colors: bool,
+
+    /// Write the output to a notebook file in this directory instead of
+    /// sending it to Fiberplane. Use `fp notebooks import` to upload it
+    /// later.
+    #[clap(long, value_name = "DIR", value_hint = ValueHint::DirPath, conflicts_with = "notebook_id")]
+    local: Option<PathBuf>,
+
+    /// Format of the notebook file that is written with `--local`
+    #[clap(long, value_enum, default_value = "json", requires = "local")]
+    local_format: LocalFormat,
+
     /// The command to run
     #[clap(value_hint = ValueHint::CommandWithArguments, num_args = 1..)]
     command: Vec<String>,
@@ -56,12 +115,29 @@ enum ExecOutput {
 }
 
 pub async fn handle_command(args: Arguments) -> Result<()> {
-    let client = api_client_configuration(args.config.clone(), args.base_url.clone()).await?;
+    let redactor = Redactor::load(args.config.clone(), args.no_redact).await?;
     let command = args.command.join(" ");
 
-    let workspace_id = interactive::workspace_picker(&client, args.workspace_id).await?;
-    let notebook_id =
-        interactive::notebook_picker(&client, args.notebook_id, Some(workspace_id)).await?;
+    // The notebook the output is sent to, unless it's written to a local file
+    let (destination, notebook) = match &args.local {
+        Some(dir) => {
+            let notebook =
+                LocalNotebook::create(dir, "run", command.clone(), args.local_format).await?;
+            (Destination::Local(notebook), None)
+        }
+        None => {
+            let client =
+                api_client_configuration(args.config.clone(), args.base_url.clone()).await?;
+            let workspace_id = interactive::workspace_picker(&client, args.workspace_id).await?;
+            let notebook_id =
+                interactive::notebook_picker(&client, args.notebook_id, Some(workspace_id)).await?;
+            let spool = Spool::create(client, notebook_id).await?;
+            (
+                Destination::Notebook(spool),
+                Some((workspace_id, notebook_id)),
+            )
+        }
+    };
 
     let (shell_type, shell_path) = ShellType::auto_detect();
     debug!("Using {:?} to run command: \"{}\"", shell_type, &command);
@@ -87,7 +163,25 @@ pub async fn handle_command(args: Arguments) -> Result<()> {
     let mut stdout = io::stdout();
     let mut stderr = io::stderr();
 
-    let mut cell_writer = CellWriter::new(client, notebook_id, args.command);
+    let parse_options = ParseOptions {
+        record_start_patterns: args.record_start,
+    };
+    let output_options = OutputOptions {
+        limits: OutputLimits {
+            max_bytes: args.max_bytes,
+            max_lines: args.max_lines,
+            max_events: args.max_events,
+        },
+        attach_full_output: args.attach_full_output,
+        colors: !args.no_colors,
+    };
+    let mut cell_writer = CellWriter::new(
+        destination,
+        args.command,
+        parse_options,
+        redactor,
+        output_options,
+    );
 
     loop {
         tokio::select! {
@@ -121,17 +215,21 @@ pub async fn handle_command(args: Arguments) -> Result<()> {
     }
 
     cell_writer.flush().await?;
+    cell_writer.finish().await?;
+    cell_writer.redactor().report();
 
     if let Some(cell) = cell_writer.into_output_cell() {
-        let url = NotebookUrlBuilder::new(workspace_id, notebook_id)
-            .base_url(args.base_url)
-            .cell_id(cell.id())
-            .url()?;
-
         let cell: Cell = serde_json::from_value(serde_json::to_value(cell)?)?;
         match args.output {
             ExecOutput::Command => {
-                info!("\n   --> Created cell: {}", url);
+                // The local notebook already reported where it was saved
+                if let Some((workspace_id, notebook_id)) = notebook {
+                    let url = NotebookUrlBuilder::new(workspace_id, notebook_id)
+                        .base_url(args.base_url)
+                        .cell_id(cell.id())
+                        .url()?;
+                    info!("\n   --> Created cell: {}", url);
+                }
                 Ok(())
             }
             ExecOutput::Table => {
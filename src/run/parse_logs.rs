@@ -2,6 +2,7 @@ use super::timestamp::AnyTimestamp;
 use fiberplane::models::providers::{OtelMetadata, OtelSpanId, OtelTraceId, ProviderEvent};
 use grok::{Grok, Pattern};
 use once_cell::sync::Lazy;
+use regex::Regex;
 use serde_json::{Map, Value};
 use std::collections::BTreeMap;
 use std::convert::TryInto;
@@ -33,22 +34,97 @@ static GITHUB_ACTION_PATTERN: Lazy<Pattern> = Lazy::new(|| {
     Grok::default().compile(pattern, true).unwrap()
 });
 
+// Lines that look like part of a stack trace or backtrace, such as Java's
+// `at com.example.Foo(Foo.java:12)`, `Caused by: ...` and `... 12 more`, or
+// the indented, numbered frames of a Rust backtrace
+// (`   0: std::panicking::begin_panic`)
+static CONTINUATION_PATTERN: Lazy<Regex> = Lazy::new(|| {
+    Regex::new(r"^(at |Caused by:|Suppressed:|\.\.\. \d+ (more|common frames omitted)|\s+\d+: |stack backtrace:)")
+        .unwrap()
+});
+
+/// Options that control how lines are grouped into log records.
+#[derive(Clone, Debug, Default)]
+pub struct ParseOptions {
+    /// Patterns that mark the start of a new record. If any are set, every
+    /// line that does not match one of them is treated as a continuation of
+    /// the previous record. If none are set, continuation lines are detected
+    /// by their indentation and by common stack trace prefixes.
+    pub record_start_patterns: Vec<Regex>,
+}
+
+impl ParseOptions {
+    fn is_continuation(&self, line: &str) -> bool {
+        if self.record_start_patterns.is_empty() {
+            line.starts_with(char::is_whitespace) || CONTINUATION_PATTERN.is_match(line)
+        } else {
+            !self
+                .record_start_patterns
+                .iter()
+                .any(|pattern| pattern.is_match(line))
+        }
+    }
+
+    /// Whether the line should be merged into the previous record. Lines that
+    /// parse as a record by themselves are only merged into the previous
+    /// record if the user explicitly told us what the start of a record looks
+    /// like.
+    fn continues_record(&self, raw_line: &str, is_record: bool) -> bool {
+        (!is_record || !self.record_start_patterns.is_empty()) && self.is_continuation(raw_line)
+    }
+}
+
+/// Byte offset of the line that starts the last record in the output.
+///
+/// When logs are read while they are being written, more continuation lines
+/// (such as the rest of a stack trace) could still follow that record.
+pub fn last_record_start(output: &str, options: &ParseOptions) -> Option<usize> {
+    let mut offset = 0;
+    let mut last_start = None;
+    for raw_line in output.split_inclusive('\n') {
+        let line = raw_line.trim();
+        if !line.is_empty() && !options.continues_record(raw_line, parse_log(line).is_some()) {
+            last_start = Some(offset);
+        }
+        offset += raw_line.len();
+    }
+    last_start
+}
+
 /// Parse logs from each line of the string.
 /// This handles JSON-encoded log lines as well as a variety of other log formats.
-pub fn parse_logs(output: &str) -> Vec<ProviderEvent> {
-    let mut logs = Vec::new();
+/// Continuation lines (such as the frames of a stack trace) are merged into the
+/// body of the preceding record.
+pub fn parse_logs(output: &str, options: &ParseOptions) -> Vec<ProviderEvent> {
+    let mut logs: Vec<ProviderEvent> = Vec::new();
     // Keep track of the most recent timestamp in case later log lines do not have a timestamp
     let mut most_recent_timestamp = None;
     // Keep track of lines without timestamps so we can add them to a later entry with a timestamp
-    let mut lines_without_timestamps = Vec::new();
+    let mut lines_without_timestamps: Vec<String> = Vec::new();
 
-    for line in output.lines() {
-        let line = line.trim();
+    for raw_line in output.lines() {
+        let line = raw_line.trim();
         if line.is_empty() {
             continue;
         }
 
-        match parse_log(line) {
+        let record = parse_log(line);
+
+        if options.continues_record(raw_line, record.is_some()) {
+            // Lines without a timestamp are always more recent than the last
+            // record, since they are drained as soon as a record shows up
+            let previous_body = match lines_without_timestamps.last_mut() {
+                Some(previous) => Some(previous),
+                None => logs.last_mut().map(|event| &mut event.title),
+            };
+            if let Some(body) = previous_body {
+                body.push('\n');
+                body.push_str(raw_line.trim_end());
+                continue;
+            }
+        }
+
+        match record {
             Some(record) => {
                 // If we had lines before that didn't have timestamps, add them
                 // under this timestamp:
@@ -233,7 +309,7 @@ mod tests {
     fn json_logs() {
         let logs = r#"{"ts": "2018-01-01T00:00:00.000Z", "body": "test"}
         {"timestamp": "1657619253", "message": "hello", "trace_id": "1234567890123456", "thing": 1, "host.name": "blah"}"#;
-        let logs = parse_logs(logs);
+        let logs = parse_logs(logs, &ParseOptions::default());
         assert_eq!(logs.len(), 2);
 
         assert_eq!(logs[0].title, "test");
@@ -263,7 +339,7 @@ mod tests {
         let logs = r#"
 192.0.7.128 - - [11/Jul/2022:13:04:26 +0000] "GET / HTTP/1.1" 200 472 "-" "ELB-HealthChecker/2.0" "-"
 192.0.6.198 - - [11/Jul/2022:13:04:27 +0000] "GET / HTTP/1.1" 200 472 "-" "ELB-HealthChecker/2.0" "-""#;
-        let logs = parse_logs(logs);
+        let logs = parse_logs(logs, &ParseOptions::default());
         assert_eq!(logs.len(), 2);
 
         let mut attributes = BTreeMap::from_iter(
@@ -294,7 +370,7 @@ mod tests {
 build   Set up job      2022-07-11T15:12:28.2324317Z Packages: write
 build   Set up job      2022-07-11T15:12:28.2324660Z Pages: write
 build   Set up job      2022-07-11T15:12:28.2325020Z PullRequests: write";
-        let logs = parse_logs(logs);
+        let logs = parse_logs(logs, &ParseOptions::default());
         assert_eq!(logs.len(), 3);
 
         assert_eq!(
@@ -312,8 +388,65 @@ build   Set up job      2022-07-11T15:12:28.2325020Z PullRequests: write";
 /docker-entrypoint.sh: Launching /docker-entrypoint.d/20-envsubst-on-templates.sh
 /docker-entrypoint.sh: Launching /docker-entrypoint.d/30-tune-worker-processes.sh
 /docker-entrypoint.sh: Configuration complete; ready for start up"#;
-        let logs = parse_logs(logs);
+        let logs = parse_logs(logs, &ParseOptions::default());
+        assert_eq!(logs.len(), 3);
+    }
+
+    #[test]
+    fn java_stack_trace() {
+        let logs = [
+            r#"{"ts": "2018-01-01T00:00:00.000Z", "body": "Request failed"}"#,
+            "java.lang.IllegalStateException: boom",
+            "\tat com.example.Handler.handle(Handler.java:42)",
+            "\tat com.example.Server.run(Server.java:12)",
+            "Caused by: java.io.IOException: broken pipe",
+            "\t... 2 more",
+            r#"{"ts": "2018-01-01T00:00:01.000Z", "body": "Recovered"}"#,
+        ]
+        .join("\n");
+        let logs = parse_logs(&logs, &ParseOptions::default());
         assert_eq!(logs.len(), 3);
+
+        assert_eq!(logs[0].title, "Request failed");
+        assert_eq!(
+            logs[1].title,
+            "java.lang.IllegalStateException: boom\n\
+             \tat com.example.Handler.handle(Handler.java:42)\n\
+             \tat com.example.Server.run(Server.java:12)\n\
+             Caused by: java.io.IOException: broken pipe\n\
+             \t... 2 more"
+        );
+        assert_eq!(logs[2].title, "Recovered");
+    }
+
+    #[test]
+    fn rust_backtrace() {
+        let logs = "2022-07-11T15:12:28Z ERROR thread 'main' panicked at 'oops', src/main.rs:2:5
+stack backtrace:
+   0: std::panicking::begin_panic
+             at /rustc/library/std/src/panicking.rs:616:12
+   1: app::main
+2022-07-11T15:12:29Z INFO shutting down";
+        let options = ParseOptions {
+            record_start_patterns: vec![Regex::new(r"^\d{4}-\d{2}-\d{2}T").unwrap()],
+        };
+        let logs = parse_logs(logs, &options);
+        assert_eq!(logs.len(), 2);
+
+        assert!(logs[0].title.starts_with("2022-07-11T15:12:28Z ERROR"));
+        assert!(logs[0].title.ends_with("\n   1: app::main"));
+        assert_eq!(logs[0].title.lines().count(), 5);
+        assert_eq!(logs[1].title, "2022-07-11T15:12:29Z INFO shutting down");
+    }
+
+    #[test]
+    fn numbered_lines_are_not_continuations() {
+        let logs = "2022-07-11T15:12:28Z ERROR request failed
+404: not found";
+        let logs = parse_logs(logs, &ParseOptions::default());
+        assert_eq!(logs.len(), 2);
+        assert_eq!(logs[0].title, "2022-07-11T15:12:28Z ERROR request failed");
+        assert_eq!(logs[1].title, "404: not found");
     }
 
     #[test]
@@ -324,7 +457,7 @@ build   Set up job      2022-07-11T15:12:28.2325020Z PullRequests: write";
 /docker-entrypoint.sh: Configuration complete; ready for start up
 192.0.7.128 - - [11/Jul/2022:13:04:26 +0000] "GET / HTTP/1.1" 200 472 "-" "ELB-HealthChecker/2.0" "-"
 192.0.6.198 - - [11/Jul/2022:13:04:26 +0000] "GET / HTTP/1.1" 200 472 "-" "ELB-HealthChecker/2.0" "-""#;
-        let logs = parse_logs(logs);
+        let logs = parse_logs(logs, &ParseOptions::default());
         assert_eq!(logs.len(), 5);
 
         assert_eq!(
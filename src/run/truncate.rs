@@ -0,0 +1,273 @@
+use fiberplane::models::providers::ProviderEvent;
+
+/// Limits for the output that is uploaded to a single cell. Output that
+/// exceeds these limits is truncated to its head and tail.
+#[derive(Clone, Debug)]
+pub struct OutputLimits {
+    pub max_bytes: usize,
+    pub max_lines: usize,
+    pub max_events: usize,
+}
+
+/// Truncate the text so it stays within the line and byte limits, keeping
+/// the start and the end of the text and replacing the middle with a marker.
+/// Returns `None` if the text is within the limits.
+pub fn truncate_text(text: &str, limits: &OutputLimits) -> Option<String> {
+    let line_count = text.lines().count();
+    let mut truncated = None;
+
+    if line_count > limits.max_lines {
+        let head_lines = limits.max_lines / 2;
+        let tail_lines = limits.max_lines - head_lines;
+        let lines: Vec<_> = text.lines().collect();
+
+        let mut output = lines[..head_lines].join("\n");
+        output.push_str(&marker(line_count - limits.max_lines, "lines"));
+        output.push_str(&lines[line_count - tail_lines..].join("\n"));
+        truncated = Some(output);
+    }
+
+    let text = truncated.as_deref().unwrap_or(text);
+    if text.len() > limits.max_bytes {
+        let head_end = floor_char_boundary(text, limits.max_bytes / 2);
+        let tail_start = ceil_char_boundary(text, text.len() - (limits.max_bytes - head_end));
+
+        let mut output = text[..head_end].to_string();
+        output.push_str(&marker(tail_start - head_end, "bytes"));
+        output.push_str(&text[tail_start..]);
+        truncated = Some(output);
+    }
+
+    truncated
+}
+
+/// Truncate the log records to the maximum number of records, keeping the
+/// first and the last ones and adding a record that marks the omitted ones.
+/// The body of each record is truncated to the line and byte limits, and
+/// records are omitted until the serialized records fit in the byte limit as
+/// well. Returns `None` if the records are within the limits.
+pub fn truncate_events(
+    events: &[ProviderEvent],
+    limits: &OutputLimits,
+) -> Option<Vec<ProviderEvent>> {
+    let mut truncated = None;
+    for (index, event) in events.iter().enumerate() {
+        if let Some(title) = truncate_text(&event.title, limits) {
+            truncated.get_or_insert_with(|| events.to_vec())[index].title = title;
+        }
+    }
+
+    omit_events(truncated.as_deref().unwrap_or(events), limits).or(truncated)
+}
+
+fn omit_events(events: &[ProviderEvent], limits: &OutputLimits) -> Option<Vec<ProviderEvent>> {
+    let sizes: Vec<_> = events.iter().map(serialized_len).collect();
+    // Each record is followed by a separator, except the last one, and the
+    // list is enclosed in brackets
+    if events.len() <= limits.max_events && sizes.iter().sum::<usize>() < limits.max_bytes {
+        return None;
+    }
+
+    // Take records from the start and the end in turns, for as long as they
+    // fit next to the marker record. The first record is always kept, even if
+    // its body alone takes up the whole limit.
+    let max_marker = omitted_marker(&events[0], events.len());
+    let mut budget = limits
+        .max_bytes
+        .saturating_sub(serialized_len(&max_marker) + 1);
+    let (mut head, mut tail) = (0, 0);
+    while head + tail < limits.max_events {
+        let index = if head <= tail {
+            head
+        } else {
+            events.len() - 1 - tail
+        };
+        if sizes[index] > budget && head > 0 {
+            break;
+        }
+
+        budget = budget.saturating_sub(sizes[index]);
+        if head <= tail {
+            head += 1;
+        } else {
+            tail += 1;
+        }
+    }
+
+    if head + tail == events.len() {
+        return None;
+    }
+
+    let mut truncated = Vec::with_capacity(head + tail + 1);
+    truncated.extend_from_slice(&events[..head]);
+    truncated.push(omitted_marker(&events[head], events.len() - head - tail));
+    truncated.extend_from_slice(&events[events.len() - tail..]);
+    Some(truncated)
+}
+
+fn omitted_marker(next: &ProviderEvent, omitted: usize) -> ProviderEvent {
+    ProviderEvent::builder()
+        .time(next.time.0.into())
+        .title(marker(omitted, "records").trim().to_string())
+        .build()
+}
+
+/// The length of the record in a serialized list, including its separator
+fn serialized_len(event: &ProviderEvent) -> usize {
+    serde_json::to_string(event).map_or(0, |json| json.len()) + 1
+}
+
+fn marker(omitted: usize, unit: &str) -> String {
+    format!("\n[... {omitted} {unit} omitted ...]\n")
+}
+
+fn floor_char_boundary(text: &str, mut index: usize) -> usize {
+    while !text.is_char_boundary(index) {
+        index -= 1;
+    }
+    index
+}
+
+fn ceil_char_boundary(text: &str, mut index: usize) -> usize {
+    while !text.is_char_boundary(index) {
+        index += 1;
+    }
+    index
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use time::OffsetDateTime;
+
+    const LIMITS: OutputLimits = OutputLimits {
+        max_bytes: 1000,
+        max_lines: 4,
+        max_events: 4,
+    };
+
+    #[test]
+    fn within_limits() {
+        assert_eq!(truncate_text("one\ntwo\nthree\n", &LIMITS), None);
+    }
+
+    #[test]
+    fn truncate_lines() {
+        let text = "one\ntwo\nthree\nfour\nfive\nsix";
+        assert_eq!(
+            truncate_text(text, &LIMITS).unwrap(),
+            "one\ntwo\n[... 2 lines omitted ...]\nfive\nsix"
+        );
+    }
+
+    #[test]
+    fn truncate_bytes() {
+        let limits = OutputLimits {
+            max_bytes: 10,
+            ..LIMITS
+        };
+        // Multi-byte characters are never split
+        assert_eq!(
+            truncate_text("ééééé-ééééé", &limits).unwrap(),
+            "éé\n[... 11 bytes omitted ...]\nééé"
+        );
+    }
+
+    #[test]
+    fn truncate_records() {
+        let events: Vec<_> = (0..10)
+            .map(|i| {
+                ProviderEvent::builder()
+                    .time(OffsetDateTime::now_utc().into())
+                    .title(format!("record {i}"))
+                    .build()
+            })
+            .collect();
+        let titles: Vec<_> = truncate_events(&events, &LIMITS)
+            .unwrap()
+            .into_iter()
+            .map(|event| event.title)
+            .collect();
+        assert_eq!(
+            titles,
+            vec![
+                "record 0",
+                "record 1",
+                "[... 6 records omitted ...]",
+                "record 8",
+                "record 9"
+            ]
+        );
+    }
+
+    #[test]
+    fn truncate_long_records() {
+        let limits = OutputLimits {
+            max_bytes: 10,
+            ..LIMITS
+        };
+        let events = vec![ProviderEvent::builder()
+            .time(OffsetDateTime::now_utc().into())
+            .title("x".repeat(100))
+            .build()];
+        let truncated = truncate_events(&events, &limits).unwrap();
+        assert_eq!(truncated.len(), 1);
+        assert_eq!(
+            truncated[0].title,
+            "xxxxx\n[... 90 bytes omitted ...]\nxxxxx"
+        );
+
+        assert_eq!(truncate_events(&events[..0], &limits), None);
+    }
+
+    #[test]
+    fn truncate_records_to_bytes() {
+        let events: Vec<_> = (0..10)
+            .map(|i| {
+                ProviderEvent::builder()
+                    .time(OffsetDateTime::now_utc().into())
+                    .title(format!("record {i}"))
+                    .build()
+            })
+            .collect();
+        let limits = OutputLimits {
+            max_bytes: 5 * serialized_len(&events[0]),
+            max_events: 100,
+            ..LIMITS
+        };
+        let truncated = truncate_events(&events, &limits).unwrap();
+        let titles: Vec<_> = truncated.iter().map(|event| event.title.as_str()).collect();
+        assert_eq!(
+            titles,
+            vec![
+                "record 0",
+                "record 1",
+                "[... 7 records omitted ...]",
+                "record 9"
+            ]
+        );
+        assert!(serde_json::to_string(&truncated).unwrap().len() <= limits.max_bytes);
+    }
+
+    #[test]
+    fn truncate_to_single_record() {
+        let events: Vec<_> = (0..3)
+            .map(|i| {
+                ProviderEvent::builder()
+                    .time(OffsetDateTime::now_utc().into())
+                    .title(format!("record {i}"))
+                    .build()
+            })
+            .collect();
+        let limits = OutputLimits {
+            max_events: 1,
+            ..LIMITS
+        };
+        let titles: Vec<_> = truncate_events(&events, &limits)
+            .unwrap()
+            .into_iter()
+            .map(|event| event.title)
+            .collect();
+        assert_eq!(titles, vec!["record 0", "[... 2 records omitted ...]"]);
+    }
+}
@@ -0,0 +1,234 @@
+//! Recording and replaying of terminal sessions in the asciicast v2 format:
+//! https://github.com/asciinema/asciinema/blob/develop/doc/asciicast-v2.md
+
+use super::terminal_extractor::PtyOutput;
+use super::terminal_renderer::get_styled_bytes;
+use crate::redact::{LineBuffer, Redactor};
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::time::{Duration, Instant};
+use time::OffsetDateTime;
+use tokio::fs::File;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::signal;
+
+pub const MIME_TYPE: &str = "application/x-asciicast";
+
+#[derive(Serialize, Deserialize, Debug)]
+struct Header {
+    version: u8,
+    width: u16,
+    height: u16,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    timestamp: Option<i64>,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    env: BTreeMap<String, String>,
+}
+
+/// An event in the recording: the time in seconds since the start of the
+/// recording, the event type and the data of the event
+type Event = (f64, String, String);
+
+/// Writes the raw output of the PTY, including programs that use the alternate
+/// screen, together with its timing
+pub struct AsciicastWriter<W: AsyncWriteExt> {
+    writer: W,
+    redactor: Redactor,
+    start: Instant,
+    /// Trailing bytes of an incomplete UTF-8 sequence
+    partial_char: Vec<u8>,
+    /// The last line of the output, which is only redacted and written once
+    /// it's complete, in case a secret in it is split across reads
+    pending: LineBuffer,
+}
+
+impl<W: AsyncWriteExt + Unpin> AsciicastWriter<W> {
+    pub async fn new(mut writer: W, redactor: Redactor, width: u16, height: u16) -> Result<Self> {
+        let env = ["SHELL", "TERM"]
+            .iter()
+            .filter_map(|name| Some((name.to_string(), std::env::var(name).ok()?)))
+            .collect();
+        let header = Header {
+            version: 2,
+            width,
+            height,
+            timestamp: Some(OffsetDateTime::now_utc().unix_timestamp()),
+            env,
+        };
+        writer
+            .write_all(format!("{}\n", serde_json::to_string(&header)?).as_bytes())
+            .await?;
+
+        Ok(Self {
+            writer,
+            redactor,
+            start: Instant::now(),
+            partial_char: Vec::new(),
+            pending: LineBuffer::default(),
+        })
+    }
+
+    pub async fn handle_pty_output<'a>(&mut self, output: &'a PtyOutput<'a>) -> Result<()> {
+        match output {
+            PtyOutput::Data(data) => self.on_data(data).await,
+            // Record what the user saw in the terminal, so the cursor
+            // positions that the shell uses still line up
+            PtyOutput::PromptStart => self.on_data(get_styled_bytes()).await,
+            PtyOutput::PromptEnd => Ok(()),
+        }
+    }
+
+    async fn on_data(&mut self, data: &[u8]) -> Result<()> {
+        self.partial_char.extend_from_slice(data);
+
+        // Events need to be valid UTF-8, so hold on to a character that is
+        // split across reads until we have the rest of it
+        let complete_len = match std::str::from_utf8(&self.partial_char) {
+            Ok(_) => self.partial_char.len(),
+            Err(err) if err.error_len().is_none() => err.valid_up_to(),
+            Err(_) => self.partial_char.len(),
+        };
+        let rest = self.partial_char.split_off(complete_len);
+        let data = std::mem::replace(&mut self.partial_char, rest);
+        let data = self.pending.push(&String::from_utf8_lossy(&data));
+        self.write_event(&data).await
+    }
+
+    async fn write_event(&mut self, data: &str) -> Result<()> {
+        if data.is_empty() {
+            return Ok(());
+        }
+
+        let event: Event = (
+            self.start.elapsed().as_secs_f64(),
+            "o".to_string(),
+            self.redactor.redact(data),
+        );
+        self.writer
+            .write_all(format!("{}\n", serde_json::to_string(&event)?).as_bytes())
+            .await?;
+        Ok(())
+    }
+
+    pub async fn finish(&mut self) -> Result<()> {
+        let partial_char = std::mem::take(&mut self.partial_char);
+        let data = self.pending.take() + &String::from_utf8_lossy(&partial_char);
+        self.write_event(&data).await?;
+        self.writer.flush().await?;
+        Ok(())
+    }
+}
+
+/// Play back a recording in the terminal. Pauses between events are divided
+/// by `speed` and capped at `max_idle`.
+pub async fn replay(path: &Path, speed: f64, max_idle: Option<Duration>) -> Result<()> {
+    let file = File::open(path)
+        .await
+        .with_context(|| format!("Error opening recording: {}", path.display()))?;
+    let mut lines = BufReader::new(file).lines();
+
+    let header: Header = match lines.next_line().await? {
+        Some(line) => serde_json::from_str(&line).context("Invalid asciicast header")?,
+        None => bail!("Recording is empty"),
+    };
+    if header.version != 2 {
+        bail!("Unsupported asciicast version: {}", header.version);
+    }
+
+    let mut stdout = tokio::io::stdout();
+    let mut previous_time = 0.0;
+    loop {
+        let line = tokio::select! {
+            _ = signal::ctrl_c() => break,
+            line = lines.next_line() => match line? {
+                Some(line) => line,
+                None => break,
+            },
+        };
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        let (time, event_type, data): Event =
+            serde_json::from_str(&line).context("Invalid asciicast event")?;
+        let mut delay = Duration::from_secs_f64((time - previous_time).max(0.0) / speed);
+        if let Some(max_idle) = max_idle {
+            delay = delay.min(max_idle);
+        }
+        previous_time = time;
+
+        tokio::select! {
+            _ = signal::ctrl_c() => break,
+            _ = tokio::time::sleep(delay) => {}
+        }
+
+        // Input and resize events are not played back
+        if event_type == "o" {
+            stdout.write_all(data.as_bytes()).await?;
+            stdout.flush().await?;
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn record_session() {
+        let mut buf = vec![];
+        let mut writer = AsciicastWriter::new(&mut buf, Redactor::disabled(), 80, 24)
+            .await
+            .unwrap();
+        let data = "héllo\n".as_bytes();
+        // Split the é across two reads
+        writer
+            .handle_pty_output(&PtyOutput::Data(&data[..2]))
+            .await
+            .unwrap();
+        writer
+            .handle_pty_output(&PtyOutput::Data(&data[2..]))
+            .await
+            .unwrap();
+        writer.finish().await.unwrap();
+
+        let output = String::from_utf8(buf).unwrap();
+        let mut lines = output.lines();
+        let header: Header = serde_json::from_str(lines.next().unwrap()).unwrap();
+        assert_eq!((header.version, header.width, header.height), (2, 80, 24));
+
+        let events: Vec<Event> = lines
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        let data: Vec<_> = events.iter().map(|(_, _, data)| data.as_str()).collect();
+        assert_eq!(data, vec!["héllo\n"]);
+    }
+
+    #[tokio::test]
+    async fn redact_secret_across_reads() {
+        let mut buf = vec![];
+        let mut writer = AsciicastWriter::new(&mut buf, Redactor::new(&[]).unwrap(), 80, 24)
+            .await
+            .unwrap();
+        for data in ["$ echo AKIAIOSF", "ODNN7EXAMPLE\r\n", "$ "] {
+            writer
+                .handle_pty_output(&PtyOutput::Data(data.as_bytes()))
+                .await
+                .unwrap();
+        }
+        writer.finish().await.unwrap();
+
+        let output = String::from_utf8(buf).unwrap();
+        let events: Vec<Event> = output
+            .lines()
+            .skip(1)
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        let data: Vec<_> = events.iter().map(|(_, _, data)| data.as_str()).collect();
+        assert_eq!(data, vec!["$ echo [REDACTED:aws-access-key]\r\n", "$ "]);
+    }
+}
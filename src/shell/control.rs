@@ -0,0 +1,175 @@
+use abort_on_drop::ChildTask;
+use anyhow::{anyhow, bail, Context, Result};
+use rand::distributions::Alphanumeric;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc;
+use tracing::debug;
+
+/// Environment variable that tells commands inside the session how to reach
+/// the recording session. It contains the address of the control socket and
+/// the token that needs to be sent along with every message.
+pub const CONTROL_ENV_VAR_NAME: &str = "__FP_SHELL_CONTROL";
+
+const TOKEN_LENGTH: usize = 32;
+
+/// Messages that can be sent from inside the session to control the recording
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ControlMessage {
+    /// Insert a heading into the notebook
+    Mark {
+        text: String,
+    },
+    /// Stop sending anything to the notebook until the recording is resumed
+    Pause,
+    Resume,
+}
+
+#[derive(Serialize, Deserialize)]
+struct ControlRequest {
+    token: String,
+    message: ControlMessage,
+}
+
+/// Listens on a local socket for control messages from commands that are run
+/// inside the recorded session
+pub struct ControlServer {
+    env_value: String,
+    receiver: mpsc::Receiver<ControlMessage>,
+    _accept_task: ChildTask<()>,
+}
+
+impl ControlServer {
+    pub async fn bind() -> Result<Self> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let token = generate_token();
+        let env_value = format!("{} {}", listener.local_addr()?, token);
+
+        let (sender, receiver) = mpsc::channel(16);
+        let accept_task = tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                let sender = sender.clone();
+                let token = token.clone();
+                tokio::spawn(async move {
+                    if let Err(err) = handle_connection(stream, &token, sender).await {
+                        debug!(%err, "Error handling control message");
+                    }
+                });
+            }
+        });
+
+        Ok(Self {
+            env_value,
+            receiver,
+            _accept_task: ChildTask::from(accept_task),
+        })
+    }
+
+    /// The value for the `CONTROL_ENV_VAR_NAME` environment variable
+    pub fn env_value(&self) -> &str {
+        &self.env_value
+    }
+
+    pub async fn recv(&mut self) -> Option<ControlMessage> {
+        self.receiver.recv().await
+    }
+}
+
+/// Generate a random token that clients need to send to authenticate
+pub(super) fn generate_token() -> String {
+    rand::thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(TOKEN_LENGTH)
+        .map(char::from)
+        .collect()
+}
+
+async fn handle_connection(
+    stream: TcpStream,
+    token: &str,
+    sender: mpsc::Sender<ControlMessage>,
+) -> Result<()> {
+    let (reader, mut writer) = stream.into_split();
+    let mut lines = BufReader::new(reader).lines();
+
+    let response = match lines.next_line().await? {
+        Some(line) => match serde_json::from_str::<ControlRequest>(&line) {
+            Ok(request) if request.token == token => {
+                sender.send(request.message).await?;
+                "ok".to_string()
+            }
+            Ok(_) => "error: invalid token".to_string(),
+            Err(err) => format!("error: {err}"),
+        },
+        None => return Ok(()),
+    };
+
+    writer.write_all(format!("{response}\n").as_bytes()).await?;
+    Ok(())
+}
+
+/// Send a message to the recording session this command is running in
+pub async fn send(message: ControlMessage) -> Result<()> {
+    let env_value = std::env::var(CONTROL_ENV_VAR_NAME)
+        .map_err(|_| anyhow!("This command can only be used inside an `fp shell` session"))?;
+    let (address, token) = env_value
+        .split_once(' ')
+        .ok_or_else(|| anyhow!("Invalid value for {}", CONTROL_ENV_VAR_NAME))?;
+
+    let stream = TcpStream::connect(address)
+        .await
+        .context("Unable to connect to the recording session")?;
+    let (reader, mut writer) = stream.into_split();
+
+    let request = ControlRequest {
+        token: token.to_string(),
+        message,
+    };
+    writer
+        .write_all(format!("{}\n", serde_json::to_string(&request)?).as_bytes())
+        .await?;
+
+    match BufReader::new(reader).lines().next_line().await? {
+        Some(response) if response == "ok" => Ok(()),
+        Some(response) => bail!(
+            "Recording session returned an error: {}",
+            response.trim_start_matches("error: ")
+        ),
+        None => bail!("Recording session closed the connection"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn send_messages() {
+        let mut server = ControlServer::bind().await.unwrap();
+        std::env::set_var(CONTROL_ENV_VAR_NAME, server.env_value());
+
+        let message = ControlMessage::Mark {
+            text: "found root cause".to_string(),
+        };
+        let (result, received) = tokio::join!(send(message), server.recv());
+        result.unwrap();
+        assert_eq!(
+            received,
+            Some(ControlMessage::Mark {
+                text: "found root cause".to_string()
+            })
+        );
+
+        // Messages with the wrong token are rejected
+        let address = server.env_value().split_once(' ').unwrap().0.to_string();
+        std::env::set_var(CONTROL_ENV_VAR_NAME, format!("{address} wrong"));
+        let err = send(ControlMessage::Pause).await.unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Recording session returned an error: invalid token"
+        );
+    }
+}
@@ -1,20 +1,36 @@
+use self::asciicast::AsciicastWriter;
+use self::control::{ControlMessage, ControlServer};
 use self::notebook_writer::NotebookWriter;
 use self::pty_terminal::PtyTerminal;
+use self::share::ShareServer;
 use self::shell_launcher::{ShellLauncher, NESTED_SHELL_SESSION_ENV_VAR_NAME};
 use self::terminal_extractor::{PtyOutput, TerminalExtractor};
 use self::terminal_renderer::TerminalRenderer;
 use self::text_renderer::TextRenderer;
 use crate::config::api_client_configuration;
+use crate::fp_urls::NotebookUrlBuilder;
 use crate::interactive;
-use anyhow::Result;
-use clap::Parser;
+use crate::local_notebook::{LocalFormat, LocalNotebook};
+use crate::redact::Redactor;
+use crate::spool::{Destination, Spool};
+use anyhow::{Context, Result};
+use clap::{Parser, ValueHint};
+use crossterm::terminal;
+use fiberplane::api_client::{notebook_get, profile_get};
 use fiberplane::base64uuid::Base64Uuid;
+use std::net::SocketAddr;
 use std::path::PathBuf;
 use std::time::Duration;
-use tracing::instrument;
+use time::OffsetDateTime;
+use tokio::fs::{self, File};
+use tokio::io::BufWriter;
+use tracing::{info, instrument};
 
+pub(crate) mod asciicast;
+mod control;
 mod notebook_writer;
 mod pty_terminal;
+mod share;
 mod shell_launcher;
 pub mod shell_type;
 mod terminal_extractor;
@@ -22,11 +38,83 @@ mod terminal_renderer;
 mod text_renderer;
 
 #[derive(Parser)]
+#[clap(args_conflicts_with_subcommands = true)]
 pub struct Arguments {
+    #[clap(subcommand)]
+    sub_command: Option<SubCommand>,
+
     // ID of the notebook
     #[clap(long, short, env)]
     notebook_id: Option<Base64Uuid>,
 
+    /// Don't redact secrets and personal information from the recording
+    #[clap(long)]
+    no_redact: bool,
+
+    /// Write the output of commands that looks like logs to log cells. The
+    /// output of each command is then only added once the command finishes.
+    #[clap(long)]
+    log_cells: bool,
+
+    /// Write the output to plain code cells, without its colours and text
+    /// styles. By default, the output is written to text cells where the
+    /// styles are kept as formatting, and colours are shown as highlights.
+    /// All colours get the same highlight, since notebooks don't support
+    /// colours.
+    #[clap(long)]
+    no_colors: bool,
+
+    /// Path to the shell to launch. By default, the shell that `fp` is run
+    /// from is used.
+    #[clap(long, value_name = "PATH")]
+    shell: Option<PathBuf>,
+
+    /// Save a recording of the session, including programs such as `vim` or
+    /// `htop`, to this file in the asciicast v2 format
+    #[clap(long, value_name = "FILE")]
+    record: Option<PathBuf>,
+
+    /// Attach a recording of the session to the notebook
+    #[clap(long)]
+    attach_recording: bool,
+
+    /// Let other terminals watch the session live with `fp shell attach`.
+    /// The session is shared on the given address, or on a random port on
+    /// localhost if none is given. Note that the output is shared as is,
+    /// without redacting it.
+    #[clap(
+        long,
+        value_name = "ADDRESS",
+        num_args = 0..=1,
+        default_missing_value = "127.0.0.1:0"
+    )]
+    share: Option<SocketAddr>,
+
+    /// Token that terminals need to provide to attach to the shared session.
+    /// By default, a random token is generated.
+    #[clap(long, value_name = "TOKEN", requires = "share")]
+    share_token: Option<String>,
+
+    /// Allow terminals that attached to the shared session to type into it
+    #[clap(long, requires = "share")]
+    share_writable: bool,
+
+    /// Allow sharing the session on an address other than localhost. The
+    /// connection isn't encrypted, so anyone on the network can read the
+    /// output and the token.
+    #[clap(long, requires = "share")]
+    share_insecure: bool,
+
+    /// Write the session to a notebook file in this directory instead of
+    /// sending it to Fiberplane. Use `fp notebooks import` to upload it
+    /// later.
+    #[clap(long, value_name = "DIR", value_hint = ValueHint::DirPath, conflicts_with = "notebook_id")]
+    local: Option<PathBuf>,
+
+    /// Format of the notebook file that is written with `--local`
+    #[clap(long, value_enum, default_value = "json", requires = "local")]
+    local_format: LocalFormat,
+
     #[clap(from_global)]
     base_url: url::Url,
 
@@ -34,36 +122,214 @@ pub struct Arguments {
     config: Option<PathBuf>,
 }
 
+#[derive(Parser)]
+enum SubCommand {
+    /// Play back a recording of a shell session in the terminal
+    Replay(ReplayArguments),
+
+    /// Insert a heading into the notebook of the current recording session
+    Mark(MarkArguments),
+
+    /// Stop sending the current recording session to the notebook, for example
+    /// while typing sensitive information
+    Pause,
+
+    /// Continue sending the current recording session to the notebook
+    Resume,
+
+    /// Watch a session that is shared with `fp shell --share`
+    Attach(AttachArguments),
+}
+
+#[derive(Parser)]
+struct ReplayArguments {
+    /// The asciicast file that was recorded with `fp shell --record`
+    file: PathBuf,
+
+    /// Playback speed, where 2 plays the session back twice as fast
+    #[clap(long, short, default_value = "1")]
+    speed: f64,
+
+    /// Limit pauses in the recording to this number of seconds
+    #[clap(long, value_name = "SECONDS")]
+    max_idle: Option<f64>,
+}
+
+#[derive(Parser)]
+struct MarkArguments {
+    /// Text of the heading
+    #[clap(required = true, num_args = 1..)]
+    text: Vec<String>,
+}
+
+#[derive(Parser)]
+struct AttachArguments {
+    /// Address of the shared session, as shown by `fp shell --share`
+    address: String,
+
+    /// Token of the shared session
+    #[clap(long, short, env = "FP_SHELL_SHARE_TOKEN")]
+    token: Option<String>,
+}
+
 const TEXT_BUF_SIZE: usize = 256;
 
-#[instrument(err, skip_all)]
+const RECORDING_FILE_NAME: &str = "session.cast";
+
 pub(crate) async fn handle_command(args: Arguments) -> Result<()> {
+    match args.sub_command {
+        Some(SubCommand::Replay(args)) => handle_replay_command(args).await,
+        Some(SubCommand::Mark(args)) => {
+            control::send(ControlMessage::Mark {
+                text: args.text.join(" "),
+            })
+            .await
+        }
+        Some(SubCommand::Pause) => control::send(ControlMessage::Pause).await,
+        Some(SubCommand::Resume) => control::send(ControlMessage::Resume).await,
+        Some(SubCommand::Attach(args)) => share::attach(&args.address, args.token).await,
+        None => handle_record_command(args).await,
+    }
+}
+
+async fn handle_replay_command(args: ReplayArguments) -> Result<()> {
+    if args.speed <= 0.0 {
+        return Err(anyhow::anyhow!("Speed must be greater than 0"));
+    }
+
+    let max_idle = args.max_idle.map(Duration::from_secs_f64);
+    asciicast::replay(&args.file, args.speed, max_idle).await
+}
+
+#[instrument(err, skip_all)]
+async fn handle_record_command(args: Arguments) -> Result<()> {
     if std::env::var(NESTED_SHELL_SESSION_ENV_VAR_NAME).is_ok() {
         return Err(anyhow::anyhow!(
             "Can't start recording inside an existing recording session"
         ));
     }
 
-    let client = api_client_configuration(args.config, args.base_url).await?;
-    let notebook_id = interactive::notebook_picker(&client, args.notebook_id, None).await?;
+    let redactor = Redactor::load(args.config.clone(), args.no_redact).await?;
+    let (destination, user, notebook_id, notebook_url) = match &args.local {
+        Some(dir) => {
+            let notebook =
+                LocalNotebook::create(dir, "shell", "Shell session".to_string(), args.local_format)
+                    .await?;
+            (Destination::Local(notebook), None, None, None)
+        }
+        None => {
+            let client =
+                api_client_configuration(args.config.clone(), args.base_url.clone()).await?;
+            let notebook_id = interactive::notebook_picker(&client, args.notebook_id, None).await?;
+            let user = profile_get(&client).await?;
+            // The session summary links to the cells of the commands
+            let notebook = notebook_get(&client, notebook_id).await?;
+            let notebook_url = NotebookUrlBuilder::new(
+                Base64Uuid::parse_str(&notebook.workspace_id)?,
+                notebook_id,
+            )
+            .base_url(args.base_url.clone())
+            .url()?;
+            let spool = Spool::create(client, notebook_id).await?;
+            (
+                Destination::Notebook(spool),
+                Some(user),
+                Some(notebook_id),
+                Some(notebook_url),
+            )
+        }
+    };
 
-    let launcher = ShellLauncher::new(notebook_id.into());
+    let mut control_server = ControlServer::bind().await?;
+    let launcher = ShellLauncher::new(
+        notebook_id.map(Into::into),
+        control_server.env_value().to_string(),
+        args.shell.clone(),
+    );
     let mut term_renderer = TerminalRenderer::new(tokio::io::stdout());
-    let mut initialized = false;
+
+    let mut share_server = match args.share {
+        Some(address) => {
+            let token = args
+                .share_token
+                .clone()
+                .unwrap_or_else(control::generate_token);
+            let server = ShareServer::bind(
+                address,
+                token.clone(),
+                args.share_writable,
+                args.share_insecure,
+                terminal::size()?,
+            )
+            .await?;
+            info!(
+                "Sharing this session, attach to it with: fp shell attach {} --token {}",
+                server.address(),
+                token
+            );
+            Some(server)
+        }
+        None => None,
+    };
+    let remote_input = share_server.as_mut().and_then(ShareServer::take_input);
+    // Attached terminals get the same output as this one
+    let mut share_renderer = share_server
+        .as_ref()
+        .map(|server| TerminalRenderer::new(server.writer()));
+    // Without prompt markers we can't tell when the shell is initialized, so
+    // everything gets recorded
+    let mut initialized = !launcher.shell_type().supports_prompt_markers();
     let mut interval = tokio::time::interval(Duration::from_millis(250));
+    // Set while the user paused the recording
+    let mut paused_at: Option<OffsetDateTime> = None;
 
-    let (notebook_writer, (mut terminal, pty_reader)) = tokio::try_join!(
-        NotebookWriter::new(client, notebook_id),
-        PtyTerminal::new(launcher)
+    let (mut notebook_writer, (mut terminal, pty_reader)) = tokio::try_join!(
+        NotebookWriter::new(
+            destination,
+            user,
+            notebook_url,
+            redactor,
+            args.log_cells,
+            !args.no_colors
+        ),
+        PtyTerminal::new(launcher, remote_input)
     )?;
 
     let mut term_extractor = TerminalExtractor::new(pty_reader)?;
-    let mut text_renderer = TextRenderer::new(Vec::with_capacity(TEXT_BUF_SIZE));
+    let (columns, rows) = terminal::size()?;
+    let mut text_renderer = TextRenderer::new(
+        Vec::with_capacity(TEXT_BUF_SIZE),
+        columns as usize,
+        rows as usize,
+    );
+
+    // The recording is written to a temporary file if it only needs to be attached
+    let recording_path = match &args.record {
+        Some(path) => Some(path.clone()),
+        None if args.attach_recording => {
+            Some(std::env::temp_dir().join(format!("fp-shell-{}.cast", std::process::id())))
+        }
+        None => None,
+    };
+    let mut recorder = match &recording_path {
+        Some(path) => {
+            let file = File::create(path)
+                .await
+                .with_context(|| format!("Error creating recording: {}", path.display()))?;
+            let redactor = Redactor::load(args.config.clone(), args.no_redact).await?;
+            let (width, height) = terminal::size()?;
+            Some(AsciicastWriter::new(BufWriter::new(file), redactor, width, height).await?)
+        }
+        None => None,
+    };
 
     // Worker loop that drives the reading of the shell output and forwards it to the
     // terminal and text renders.
     // The text render in turn writes its output to the notebook which internally buffers
     // the text and gets sent to the server on each `flush` on a 250ms interval.
+    // Output that can't be sent is kept in the spool and retried on that interval.
+    // Every command the user enters is written to its own cell, which is finished
+    // when the next prompt is shown.
     loop {
         tokio::select! {
             biased;
@@ -85,25 +351,120 @@ pub(crate) async fn handle_command(args: Arguments) -> Result<()> {
                     term_renderer.handle_pty_output(&output),
                     text_renderer.handle_pty_output(&output)
                 )?;
+                if let (Some(recorder), None) = (&mut recorder, paused_at) {
+                    recorder.handle_pty_output(&output).await?;
+                }
+                if let (Some(share_renderer), None) = (&mut share_renderer, paused_at) {
+                    share_renderer.handle_pty_output(&output).await?;
+                }
+
+                // The prompt reports how the previous command exited, which
+                // might come in before the command itself was written
+                if let (Some(status), None) = (text_renderer.take_exit_status(), paused_at) {
+                    write_to_notebook(&mut text_renderer, &mut notebook_writer).await?;
+                    notebook_writer.set_exit_status(status);
+                }
+
+                if output == PtyOutput::PromptStart {
+                    if paused_at.is_some() {
+                        discard_text(&mut text_renderer);
+                    } else {
+                        write_to_notebook(&mut text_renderer, &mut notebook_writer).await?;
+                        notebook_writer.finish_command().await?;
+                    }
+                }
+            }
+            Some(message) = control_server.recv() => {
+                match message {
+                    ControlMessage::Mark { text } => {
+                        if paused_at.is_none() {
+                            write_to_notebook(&mut text_renderer, &mut notebook_writer).await?;
+                        }
+                        notebook_writer.mark(&text).await?;
+                    }
+                    ControlMessage::Pause => {
+                        if paused_at.is_none() {
+                            write_to_notebook(&mut text_renderer, &mut notebook_writer).await?;
+                            notebook_writer.finish_command().await?;
+                            paused_at = Some(OffsetDateTime::now_utc());
+                        }
+                    }
+                    ControlMessage::Resume => {
+                        if let Some(paused_at) = paused_at.take() {
+                            discard_text(&mut text_renderer);
+                            notebook_writer.note_pause(paused_at).await?;
+                        }
+                    }
+                }
             }
             _ = interval.tick() => {
-                let inner = text_renderer.inner_mut();
-                if inner.is_empty() {
-                    continue;
+                if paused_at.is_some() {
+                    discard_text(&mut text_renderer);
+                } else {
+                    write_to_notebook(&mut text_renderer, &mut notebook_writer).await?;
                 }
-
-                let buffer = std::mem::replace(inner, Vec::with_capacity(TEXT_BUF_SIZE));
-                notebook_writer.write(buffer).await?;
+                // Retry output that could not be sent before
+                notebook_writer.sync().await?;
             }
         }
     }
 
     text_renderer.flush().await?;
 
-    notebook_writer
-        .write(std::mem::take(text_renderer.inner_mut()))
-        .await?;
+    match paused_at {
+        Some(paused_at) => {
+            discard_text(&mut text_renderer);
+            notebook_writer.note_pause(paused_at).await?;
+        }
+        None => write_to_notebook(&mut text_renderer, &mut notebook_writer).await?,
+    }
     notebook_writer.close().await?;
 
+    if let (Some(mut recorder), Some(path)) = (recorder, recording_path) {
+        recorder.finish().await?;
+        if args.attach_recording {
+            let content = fs::read(&path).await?;
+            notebook_writer
+                .attach_recording(RECORDING_FILE_NAME, asciicast::MIME_TYPE, content)
+                .await?;
+            if args.record.is_none() {
+                fs::remove_file(&path).await?;
+            }
+        }
+    }
+    notebook_writer.finish().await?;
+
+    // Leave raw mode before letting the user know about any redactions
+    drop(terminal);
+    notebook_writer.redactor().report();
+    if let Some(path) = &args.record {
+        info!("Saved recording to {}", path.display());
+    }
+
+    Ok(())
+}
+
+/// Send the command the user entered and the text that was rendered since the
+/// last time to the notebook
+async fn write_to_notebook(
+    text_renderer: &mut TextRenderer<Vec<u8>>,
+    notebook_writer: &mut NotebookWriter,
+) -> Result<()> {
+    if let Some(command_line) = text_renderer.take_command_line() {
+        notebook_writer.start_command(command_line).await?;
+    }
+
+    let inner = text_renderer.inner_mut();
+    if !inner.is_empty() {
+        let buffer = std::mem::replace(inner, Vec::with_capacity(TEXT_BUF_SIZE));
+        notebook_writer.write(buffer).await?;
+    }
+
     Ok(())
 }
+
+/// Drop the text that was rendered while the recording was paused
+fn discard_text(text_renderer: &mut TextRenderer<Vec<u8>>) {
+    text_renderer.take_command_line();
+    text_renderer.inner_mut().clear();
+}
@@ -1,138 +1,552 @@
-use anyhow::{anyhow, Result};
-use fiberplane::api_client::clients::ApiClient;
-use fiberplane::api_client::{notebook_cell_append_text, notebook_cells_append, profile_get};
-use fiberplane::base64uuid::Base64Uuid;
+use super::text_renderer::CommandLine;
+use crate::ansi::{redact_escaped, strip_escape_sequences, StyledText};
+use crate::redact::{LineBuffer, Redactor};
+use crate::run::cell_writer::log_cell;
+use crate::run::parse_logs::{contains_logs, parse_logs, ParseOptions};
+use crate::spool::{new_cell_id, Destination, Operation};
+use anyhow::Result;
 use fiberplane::models::formatting::{Annotation, AnnotationWithOffset, Formatting, Mention};
-use fiberplane::models::notebooks::operations::CellAppendText;
-use fiberplane::models::notebooks::{Cell, CodeCell, HeadingCell, HeadingType};
+use fiberplane::models::notebooks::{Cell, CodeCell, HeadingCell, HeadingType, TextCell};
+use fiberplane::models::users::Profile;
 use fiberplane::models::utils::char_count;
-use time::{format_description::well_known::Rfc3339, OffsetDateTime};
+use time::{format_description::well_known::Rfc3339, Duration, OffsetDateTime};
+use url::Url;
 
 pub struct NotebookWriter {
-    config: ApiClient,
-    notebook_id: Base64Uuid,
-    code_cell_id: String,
+    /// Everything is sent through the spool, so the session continues when
+    /// the connection is lost for a while, unless it's written to a local
+    /// notebook
+    destination: Destination,
     heading_cell_id: String,
+    redactor: Redactor,
+    /// Buffer the output of each command until it finishes, so output that
+    /// looks like logs can be written to a log cell
+    log_cells: bool,
+    /// Write the output to text cells that keep its styles as formatting,
+    /// rather than to code cells
+    colors: bool,
+    command: Option<Command>,
+    started_at: OffsetDateTime,
+    /// Used to link to the cell of each command from the session summary
+    notebook_url: Option<Url>,
+    /// The commands the user entered, for the session summary
+    commands: Vec<CommandSummary>,
 }
 
-impl NotebookWriter {
-    pub async fn new(config: ApiClient, notebook_id: Base64Uuid) -> Result<Self> {
-        let user = profile_get(&config).await?;
+/// A command the user entered during the session, which gets its own cell
+struct Command {
+    /// Timestamp of when the command was entered, followed by the prompt and
+    /// the command itself. Like the output, this can contain escape sequences
+    /// that set the text style.
+    header: String,
+    /// The cell that the output is streamed to
+    cell_id: Option<String>,
+    /// The buffered output if we're writing logs to log cells
+    output: String,
+    /// The output that isn't redacted yet, because its last line may continue
+    /// in the next write
+    pending: LineBuffer,
+    /// Index in the session summary, for commands the user entered
+    summary_index: Option<usize>,
+}
+
+/// How a command the user entered turned out
+#[derive(Debug)]
+struct CommandSummary {
+    command: String,
+    cell_id: Option<String>,
+    /// Only known if the prompt of the shell reports it
+    exit_status: Option<i32>,
+}
 
+impl NotebookWriter {
+    /// Create a writer that starts with a heading for the session. The user
+    /// is mentioned in it if we know who they are, which we don't for local
+    /// notebooks, and so is the notebook URL.
+    pub async fn new(
+        mut destination: Destination,
+        user: Option<Profile>,
+        notebook_url: Option<Url>,
+        redactor: Redactor,
+        log_cells: bool,
+        colors: bool,
+    ) -> Result<Self> {
         let raw_timestamp = OffsetDateTime::now_utc();
         let timestamp = raw_timestamp.format(&Rfc3339).unwrap();
 
-        let content = format!(
-            "@{}'s shell session\n🟢 Started at:\t{}",
-            user.name, timestamp
-        );
-        let timestamp_offset = char_count(&content) - char_count(&timestamp);
-        let header_cell = notebook_cells_append(
-            &config,
-            notebook_id,
-            None,
-            None,
-            vec![Cell::Heading(
-                HeadingCell::builder()
-                    .id(String::new())
-                    .heading_type(HeadingType::H3)
-                    .content(content)
-                    .formatting(vec![
-                        AnnotationWithOffset::new(
-                            0,
-                            Annotation::Mention(
-                                Mention::builder()
-                                    .name(user.name)
-                                    .user_id(user.id.to_string())
-                                    .build(),
-                            ),
-                        ),
-                        AnnotationWithOffset::new(
-                            timestamp_offset,
-                            Annotation::Timestamp {
-                                timestamp: raw_timestamp,
-                            },
-                        ),
-                    ])
-                    .read_only(true)
-                    .build(),
-            )],
-        )
-        .await?
-        .pop()
-        .ok_or_else(|| anyhow!("No cells returned"))?;
-
-        let code_cell = notebook_cells_append(
-            &config,
-            notebook_id,
-            None,
-            None,
-            vec![Cell::Code(
-                CodeCell::builder()
-                    .id(String::new())
-                    .content(String::new())
-                    .read_only(true)
-                    .build(),
-            )],
-        )
-        .await?
-        .pop()
-        .ok_or_else(|| anyhow!("No cells returned"))?;
-
-        let code_cell_id = match code_cell {
-            Cell::Code(CodeCell { id, .. }) => id,
-            _ => unreachable!(),
+        let mut formatting = Formatting::new();
+        let title = match user {
+            Some(user) => {
+                let title = format!("@{}'s shell session", user.name);
+                formatting.push(AnnotationWithOffset::new(
+                    0,
+                    Annotation::Mention(
+                        Mention::builder()
+                            .name(user.name)
+                            .user_id(user.id.to_string())
+                            .build(),
+                    ),
+                ));
+                title
+            }
+            None => "Shell session".to_string(),
         };
+        let content = format!("{title}\n🟢 Started at:\t{timestamp}");
+        formatting.push(AnnotationWithOffset::new(
+            char_count(&content) - char_count(&timestamp),
+            Annotation::Timestamp {
+                timestamp: raw_timestamp,
+            },
+        ));
 
-        let heading_cell_id = match header_cell {
-            Cell::Heading(HeadingCell { id, .. }) => id,
-            _ => unreachable!(),
-        };
+        let heading_cell_id = new_cell_id();
+        destination
+            .push(Operation::AppendCells {
+                cells: vec![Cell::Heading(
+                    HeadingCell::builder()
+                        .id(heading_cell_id.clone())
+                        .heading_type(HeadingType::H3)
+                        .content(content)
+                        .formatting(formatting)
+                        .read_only(true)
+                        .build(),
+                )],
+            })
+            .await?;
 
         Ok(Self {
-            config,
-            notebook_id,
-            code_cell_id,
+            destination,
             heading_cell_id,
+            redactor,
+            log_cells,
+            colors,
+            command: None,
+            started_at: raw_timestamp,
+            notebook_url,
+            commands: Vec::new(),
         })
     }
 
-    pub async fn write(&self, buffer: Vec<u8>) -> Result<()> {
+    /// Start a new cell for the command the user entered. The prompt is
+    /// included so the cell looks like it does in the terminal.
+    pub async fn start_command(&mut self, command_line: CommandLine) -> Result<()> {
+        self.open_command(command_line.text).await?;
+
+        let command = self.command.as_mut().unwrap();
+        command.summary_index = Some(self.commands.len());
+        self.commands.push(CommandSummary {
+            command: self.redactor.redact(&command_line.command),
+            cell_id: command.cell_id.clone(),
+            exit_status: None,
+        });
+        Ok(())
+    }
+
+    /// Record the exit status of the last command the user entered, unless
+    /// it is known already. Prompts without a command report the status of
+    /// the command before them again.
+    pub fn set_exit_status(&mut self, status: i32) {
+        if let Some(command) = self.commands.last_mut() {
+            command.exit_status.get_or_insert(status);
+        }
+    }
+
+    /// Start a new cell for output, with the prompt and command line in front
+    async fn open_command(&mut self, command_line: String) -> Result<()> {
+        self.finish_command().await?;
+
+        let timestamp = OffsetDateTime::now_utc().format(&Rfc3339).unwrap();
+        let header = format!(
+            "{}\n{}",
+            timestamp,
+            redact_escaped(&command_line, &mut self.redactor)
+        );
+        let cell_id = if self.log_cells {
+            None
+        } else {
+            Some(self.append_output_cell(&header).await?)
+        };
+
+        self.command = Some(Command {
+            header,
+            cell_id,
+            output: String::new(),
+            pending: LineBuffer::default(),
+            summary_index: None,
+        });
+        Ok(())
+    }
+
+    /// Write the output of the current command. The last line is held back
+    /// until it's complete, so a secret in it is redacted even if it's written
+    /// in parts.
+    pub async fn write(&mut self, buffer: Vec<u8>) -> Result<()> {
         let content = String::from_utf8(buffer)?;
 
-        notebook_cell_append_text(
-            &self.config,
-            self.notebook_id,
-            &self.code_cell_id,
-            CellAppendText::builder()
-                .content(content)
-                .formatting(Formatting::new())
-                .build(),
-        )
-        .await?;
+        // Output that doesn't belong to a command the user entered, such as
+        // messages from the shell itself, still gets a cell
+        if self.command.is_none() {
+            self.open_command(String::new()).await?;
+        }
+        let command = self.command.as_mut().unwrap();
+
+        let content = command.pending.push(&content);
+        self.write_output(&content).await
+    }
+
+    /// Redact and write output of the current command
+    async fn write_output(&mut self, content: &str) -> Result<()> {
+        let command = match self.command.as_mut() {
+            Some(command) if !content.is_empty() => command,
+            _ => return Ok(()),
+        };
+
+        let styled = StyledText::parse(content).redact(&mut self.redactor);
+        match &command.cell_id {
+            Some(cell_id) => {
+                let formatting = if self.colors {
+                    styled.code_formatting()
+                } else {
+                    Formatting::new()
+                };
+                self.destination
+                    .push(Operation::AppendText {
+                        cell_id: cell_id.clone(),
+                        content: styled.text,
+                        formatting,
+                    })
+                    .await?;
+            }
+            None => command.output.push_str(&styled.to_escaped()),
+        }
 
         Ok(())
     }
 
-    pub async fn close(&self) -> Result<()> {
+    /// Finish the cell of the current command. If the output is buffered,
+    /// this is when it's written to the notebook.
+    pub async fn finish_command(&mut self) -> Result<()> {
+        if let Some(command) = self.command.as_mut() {
+            let content = command.pending.take();
+            self.write_output(&content).await?;
+        }
+
+        let command = match self.command.take() {
+            Some(command) if command.cell_id.is_none() => command,
+            _ => return Ok(()),
+        };
+
+        let output = strip_escape_sequences(&command.output);
+        let cell_id = if contains_logs(&output) {
+            let cell_id = new_cell_id();
+            let cell = Cell::Text(
+                TextCell::builder()
+                    .id(cell_id.clone())
+                    .content(strip_escape_sequences(command.header.trim_end()))
+                    .read_only(true)
+                    .build(),
+            );
+            let events = parse_logs(&output, &ParseOptions::default());
+            self.append_cells(vec![cell, log_cell(&events)]).await?;
+            cell_id
+        } else {
+            self.append_output_cell(&format!("{}{}", command.header, command.output))
+                .await?
+        };
+
+        if let Some(index) = command.summary_index {
+            self.commands[index].cell_id = Some(cell_id);
+        }
+        Ok(())
+    }
+
+    pub fn redactor(&self) -> &Redactor {
+        &self.redactor
+    }
+
+    pub async fn close(&mut self) -> Result<()> {
+        self.finish_command().await?;
+
         let now = OffsetDateTime::now_utc();
         let timestamp = now.format(&Rfc3339).unwrap();
         let content = format!("\n🔴 Ended at: \t{timestamp}");
 
-        notebook_cell_append_text(
-            &self.config,
-            self.notebook_id,
-            &self.heading_cell_id,
-            CellAppendText::builder()
-                .content(content)
-                .formatting(vec![AnnotationWithOffset::new(
+        self.destination
+            .push(Operation::AppendText {
+                cell_id: self.heading_cell_id.clone(),
+                content,
+                formatting: vec![AnnotationWithOffset::new(
                     0,
                     Annotation::Timestamp { timestamp: now },
-                )])
+                )],
+            })
+            .await?;
+
+        let heading = Cell::Heading(
+            HeadingCell::builder()
+                .id(String::new())
+                .heading_type(HeadingType::H3)
+                .content("Session summary".to_string())
+                .read_only(true)
                 .build(),
-        )
-        .await?;
+        );
+        let summary = session_summary(&self.commands, now - self.started_at, |cell_id| {
+            let mut url = self.notebook_url.clone()?;
+            url.set_fragment(Some(self.destination.cell_id(cell_id)));
+            Some(url)
+        });
+        self.append_cells(vec![heading, summary]).await
+    }
+
+    /// Retry sending the output that could not be sent before
+    pub async fn sync(&mut self) -> Result<()> {
+        self.destination.flush().await
+    }
+
+    /// Wait for the remaining output to be sent. If that doesn't work out,
+    /// it is left for `fp sync`.
+    pub async fn finish(&mut self) -> Result<()> {
+        self.destination.finish().await
+    }
+
+    /// Insert a heading so the user can find this point in the session again
+    pub async fn mark(&mut self, text: &str) -> Result<()> {
+        self.finish_command().await?;
 
+        let cell = Cell::Heading(
+            HeadingCell::builder()
+                .id(String::new())
+                .heading_type(HeadingType::H3)
+                .content(format!("📍 {}", self.redactor.redact(text)))
+                .read_only(true)
+                .build(),
+        );
+        self.append_cells(vec![cell]).await?;
         Ok(())
     }
+
+    /// Let readers of the notebook know that part of the session was left out
+    pub async fn note_pause(&mut self, paused_at: OffsetDateTime) -> Result<()> {
+        self.finish_command().await?;
+
+        let now = OffsetDateTime::now_utc();
+        let paused = paused_at.format(&Rfc3339).unwrap();
+        let resumed = now.format(&Rfc3339).unwrap();
+        let prefix = "⏸️ Recording was paused from ";
+        let content = format!("{prefix}{paused} to {resumed}");
+        let paused_offset = char_count(prefix);
+        let resumed_offset = char_count(&content) - char_count(&resumed);
+
+        let cell = Cell::Text(
+            TextCell::builder()
+                .id(String::new())
+                .content(content)
+                .formatting(vec![
+                    AnnotationWithOffset::new(
+                        paused_offset,
+                        Annotation::Timestamp {
+                            timestamp: paused_at,
+                        },
+                    ),
+                    AnnotationWithOffset::new(
+                        resumed_offset,
+                        Annotation::Timestamp { timestamp: now },
+                    ),
+                ])
+                .read_only(true)
+                .build(),
+        );
+        self.append_cells(vec![cell]).await?;
+        Ok(())
+    }
+
+    /// Attach the recording of the session to the notebook and link to it
+    pub async fn attach_recording(
+        &mut self,
+        file_name: &str,
+        mime_type: &str,
+        content: Vec<u8>,
+    ) -> Result<()> {
+        self.destination
+            .push(Operation::AttachFile {
+                file_name: file_name.to_string(),
+                mime_type: mime_type.to_string(),
+                content,
+                link_prefix: "Recording of this session: ".to_string(),
+                link_text: file_name.to_string(),
+            })
+            .await
+    }
+
+    /// Append a cell for the output of a command, which can contain escape
+    /// sequences
+    async fn append_output_cell(&mut self, content: &str) -> Result<String> {
+        let id = new_cell_id();
+        let styled = StyledText::parse(content);
+        let cell = if self.colors {
+            let formatting = styled.code_formatting();
+            Cell::Text(
+                TextCell::builder()
+                    .id(id.clone())
+                    .content(styled.text)
+                    .formatting(formatting)
+                    .read_only(true)
+                    .build(),
+            )
+        } else {
+            Cell::Code(
+                CodeCell::builder()
+                    .id(id.clone())
+                    .content(styled.text)
+                    .read_only(true)
+                    .build(),
+            )
+        };
+        self.append_cells(vec![cell]).await?;
+        Ok(id)
+    }
+
+    async fn append_cells(&mut self, cells: Vec<Cell>) -> Result<()> {
+        self.destination
+            .push(Operation::AppendCells { cells })
+            .await
+    }
+}
+
+/// Create a text cell that lists the commands of the session with their exit
+/// status, linking to the cell of each command
+fn session_summary(
+    commands: &[CommandSummary],
+    duration: Duration,
+    cell_url: impl Fn(&str) -> Option<Url>,
+) -> Cell {
+    let failed = commands
+        .iter()
+        .filter(|command| matches!(command.exit_status, Some(status) if status != 0))
+        .count();
+    let mut content = format!(
+        "Duration: {}\nCommands: {}",
+        format_duration(duration),
+        commands.len()
+    );
+    if failed > 0 {
+        content.push_str(&format!(" ({failed} failed)"));
+    }
+
+    let mut formatting = Formatting::new();
+    for command in commands {
+        let icon = match command.exit_status {
+            Some(0) => "✅",
+            Some(_) => "❌",
+            None => "•",
+        };
+        content.push_str(&format!("\n{icon} "));
+
+        let start = char_count(&content);
+        content.push_str(&command.command);
+        if let Some(url) = command.cell_id.as_deref().and_then(&cell_url) {
+            formatting.push(AnnotationWithOffset::new(
+                start,
+                Annotation::StartLink {
+                    url: url.to_string(),
+                },
+            ));
+            formatting.push(AnnotationWithOffset::new(
+                char_count(&content),
+                Annotation::EndLink,
+            ));
+        }
+
+        match command.exit_status {
+            Some(status) if status != 0 => content.push_str(&format!(" (exit status {status})")),
+            _ => {}
+        }
+    }
+
+    Cell::Text(
+        TextCell::builder()
+            .id(String::new())
+            .content(content)
+            .formatting(formatting)
+            .read_only(true)
+            .build(),
+    )
+}
+
+/// Format the duration as hours, minutes and seconds, such as `1h 5m 30s`
+fn format_duration(duration: Duration) -> String {
+    let seconds = duration.whole_seconds().max(0);
+    let (hours, minutes, seconds) = (seconds / 3600, seconds / 60 % 60, seconds % 60);
+    if hours > 0 {
+        format!("{hours}h {minutes}m {seconds}s")
+    } else if minutes > 0 {
+        format!("{minutes}m {seconds}s")
+    } else {
+        format!("{seconds}s")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn summarizes_session() {
+        let commands = vec![
+            CommandSummary {
+                command: "ls".to_string(),
+                cell_id: Some("a".to_string()),
+                exit_status: Some(0),
+            },
+            CommandSummary {
+                command: "cargo test".to_string(),
+                cell_id: Some("b".to_string()),
+                exit_status: Some(101),
+            },
+            CommandSummary {
+                command: "vim".to_string(),
+                cell_id: None,
+                exit_status: None,
+            },
+        ];
+        let cell = session_summary(&commands, Duration::seconds(3723), |cell_id| {
+            Url::parse(&format!("https://studio.fiberplane.com/n#{cell_id}")).ok()
+        });
+
+        match cell {
+            Cell::Text(cell) => {
+                assert_eq!(
+                    cell.content,
+                    "Duration: 1h 2m 3s\nCommands: 3 (1 failed)\n✅ ls\n❌ cargo test (exit status 101)\n• vim"
+                );
+                assert_eq!(
+                    cell.formatting,
+                    vec![
+                        AnnotationWithOffset::new(
+                            44,
+                            Annotation::StartLink {
+                                url: "https://studio.fiberplane.com/n#a".to_string()
+                            }
+                        ),
+                        AnnotationWithOffset::new(46, Annotation::EndLink),
+                        AnnotationWithOffset::new(
+                            49,
+                            Annotation::StartLink {
+                                url: "https://studio.fiberplane.com/n#b".to_string()
+                            }
+                        ),
+                        AnnotationWithOffset::new(59, Annotation::EndLink),
+                    ]
+                );
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn formats_duration() {
+        assert_eq!(format_duration(Duration::seconds(42)), "42s");
+        assert_eq!(format_duration(Duration::seconds(330)), "5m 30s");
+        assert_eq!(format_duration(Duration::seconds(3600)), "1h 0m 0s");
+    }
 }
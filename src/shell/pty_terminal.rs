@@ -2,10 +2,12 @@ use super::shell_launcher::ShellLauncher;
 use abort_on_drop::ChildTask;
 use anyhow::Result;
 use blocking::{unblock, Task, Unblock};
+use bytes::Bytes;
 use crossterm::terminal;
 use futures::future::Fuse;
 use futures::{AsyncWriteExt, FutureExt};
 use portable_pty::{native_pty_system, ExitStatus, MasterPty, PtySize};
+use tokio::sync::mpsc;
 use tokio_util::compat::{FuturesAsyncReadCompatExt, FuturesAsyncWriteCompatExt};
 use tracing::trace;
 
@@ -41,7 +43,8 @@ impl Drop for RawGuard {
 
 /// Helper that launches the child process under a pseudo terminal (PTY)
 /// https://en.wikipedia.org/wiki/Pseudoterminal
-/// And forwards resizing as well as stdin to the child process
+/// And forwards resizing as well as stdin to the child process.
+/// Input can also come from terminals that attached to a shared session.
 pub struct PtyTerminal {
     child_waiter: Fuse<Task<Result<ExitStatus, std::io::Error>>>,
     stdin_task: Fuse<ChildTask<Result<()>>>,
@@ -52,6 +55,7 @@ pub struct PtyTerminal {
 impl PtyTerminal {
     pub async fn new(
         launcher: ShellLauncher,
+        remote_input: Option<mpsc::Receiver<Bytes>>,
     ) -> Result<(Self, impl tokio::io::AsyncReadExt + Send)> {
         let guard = RawGuard::new();
         let (cols, rows) = terminal::size()?;
@@ -75,6 +79,7 @@ impl PtyTerminal {
                 stdin_task: ChildTask::from(tokio::spawn(Self::forward_stdin(
                     Unblock::new(pty.master.try_clone_writer()?),
                     launcher,
+                    remote_input,
                 )))
                 .fuse(),
                 resize_task: ChildTask::from(tokio::spawn(Self::forward_resize(pty.master))).fuse(),
@@ -132,13 +137,37 @@ impl PtyTerminal {
     async fn forward_stdin(
         mut writer: impl AsyncWriteExt + Unpin,
         launcher: ShellLauncher,
+        remote_input: Option<mpsc::Receiver<Bytes>>,
     ) -> Result<()> {
+        use tokio::io::{AsyncReadExt as _, AsyncWriteExt as _};
+
         launcher.initialize_shell(&mut writer).await?;
 
         let mut stdin = Unblock::new(std::io::stdin()).compat();
         let mut writer = writer.compat_write();
 
-        tokio::io::copy(&mut stdin, &mut writer).await?;
+        let mut remote_input = match remote_input {
+            Some(remote_input) => remote_input,
+            None => {
+                tokio::io::copy(&mut stdin, &mut writer).await?;
+                return Ok(());
+            }
+        };
+
+        let mut buf = vec![0; 1024];
+        loop {
+            tokio::select! {
+                read = stdin.read(&mut buf) => {
+                    let read = read?;
+                    if read == 0 {
+                        break;
+                    }
+                    writer.write_all(&buf[..read]).await?;
+                }
+                Some(input) = remote_input.recv() => writer.write_all(&input).await?,
+            }
+            writer.flush().await?;
+        }
 
         Ok(())
     }
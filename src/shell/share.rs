@@ -0,0 +1,403 @@
+//! Sharing a live session with other terminals: `fp shell --share` streams the
+//! output of the PTY to everyone that attached with `fp shell attach`
+
+use super::pty_terminal::RawGuard;
+use abort_on_drop::ChildTask;
+use anyhow::{bail, Context, Result};
+use blocking::Unblock;
+use bytes::Bytes;
+use serde::{Deserialize, Serialize};
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::task::{Context as TaskContext, Poll};
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::signal;
+use tokio::sync::{broadcast, mpsc};
+use tokio_util::compat::FuturesAsyncReadCompatExt;
+use tracing::{debug, info, warn};
+
+/// Number of chunks of output that are kept for attached terminals that can't
+/// keep up. Terminals that fall further behind skip the output they missed.
+const OUTPUT_CAPACITY: usize = 1024;
+
+/// Pressing Ctrl+] detaches from a session that accepts input, since Ctrl+C
+/// is sent to the shell
+const DETACH_KEY: u8 = 0x1d;
+
+/// Attach requests are read before the terminal is authenticated, so they
+/// are limited in size and have to arrive in time
+const MAX_REQUEST_SIZE: u64 = 1024;
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Serialize, Deserialize)]
+struct AttachRequest {
+    token: String,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "result", rename_all = "snake_case")]
+enum AttachResponse {
+    Ok {
+        width: u16,
+        height: u16,
+        writable: bool,
+    },
+    Error {
+        message: String,
+    },
+}
+
+/// Accepts terminals that want to attach to the session and sends them the
+/// output of the session
+pub struct ShareServer {
+    address: SocketAddr,
+    output: broadcast::Sender<Bytes>,
+    /// What the attached terminals type, if they're allowed to
+    input: Option<mpsc::Receiver<Bytes>>,
+    _accept_task: ChildTask<()>,
+}
+
+impl ShareServer {
+    /// Start accepting terminals on the given address. The connection isn't
+    /// encrypted, so only loopback addresses are allowed, unless `insecure`
+    /// is set.
+    pub async fn bind(
+        address: SocketAddr,
+        token: String,
+        writable: bool,
+        insecure: bool,
+        (width, height): (u16, u16),
+    ) -> Result<Self> {
+        if !address.ip().is_loopback() {
+            if !insecure {
+                bail!(
+                    "Not sharing the session on {address}: the connection isn't encrypted, so \
+                    the output and the token could be read by others on the network. Use \
+                    --share-insecure to share it anyway."
+                );
+            }
+            warn!("Sharing the session on {address} over an unencrypted connection");
+        }
+
+        let listener = TcpListener::bind(address)
+            .await
+            .with_context(|| format!("Unable to share the session on {address}"))?;
+        let address = listener.local_addr()?;
+
+        let (output, _) = broadcast::channel(OUTPUT_CAPACITY);
+        let (input_sender, input) = if writable {
+            let (sender, receiver) = mpsc::channel(16);
+            (Some(sender), Some(receiver))
+        } else {
+            (None, None)
+        };
+
+        let output_sender = output.clone();
+        let accept_task = tokio::spawn(async move {
+            while let Ok((stream, peer)) = listener.accept().await {
+                let response = AttachResponse::Ok {
+                    width,
+                    height,
+                    writable,
+                };
+                let output = output_sender.subscribe();
+                let input = input_sender.clone();
+                let token = token.clone();
+                tokio::spawn(async move {
+                    if let Err(err) =
+                        handle_connection(stream, &token, response, output, input).await
+                    {
+                        debug!(%err, %peer, "Error sending the session to attached terminal");
+                    }
+                });
+            }
+        });
+
+        Ok(Self {
+            address,
+            output,
+            input,
+            _accept_task: ChildTask::from(accept_task),
+        })
+    }
+
+    pub fn address(&self) -> SocketAddr {
+        self.address
+    }
+
+    /// A writer that sends everything written to it to the attached terminals
+    pub fn writer(&self) -> ShareWriter {
+        ShareWriter {
+            output: self.output.clone(),
+        }
+    }
+
+    /// Take the receiver of the input of the attached terminals, if they are
+    /// allowed to type into the session
+    pub fn take_input(&mut self) -> Option<mpsc::Receiver<Bytes>> {
+        self.input.take()
+    }
+}
+
+async fn handle_connection(
+    stream: TcpStream,
+    token: &str,
+    response: AttachResponse,
+    mut output: broadcast::Receiver<Bytes>,
+    input: Option<mpsc::Sender<Bytes>>,
+) -> Result<()> {
+    let (reader, mut writer) = stream.into_split();
+    let mut reader = BufReader::new(reader);
+
+    let mut line = String::new();
+    let mut request = (&mut reader).take(MAX_REQUEST_SIZE);
+    match tokio::time::timeout(REQUEST_TIMEOUT, request.read_line(&mut line)).await {
+        Ok(read) => read?,
+        Err(_) => {
+            debug!("Attached terminal did not send a request in time");
+            return Ok(());
+        }
+    };
+    let response = match serde_json::from_str::<AttachRequest>(&line) {
+        Ok(request) if tokens_match(&request.token, token) => response,
+        Ok(_) => AttachResponse::Error {
+            message: "invalid token".to_string(),
+        },
+        Err(err) => AttachResponse::Error {
+            message: err.to_string(),
+        },
+    };
+    let authenticated = matches!(response, AttachResponse::Ok { .. });
+    writer
+        .write_all(format!("{}\n", serde_json::to_string(&response)?).as_bytes())
+        .await?;
+    if !authenticated {
+        return Ok(());
+    }
+
+    let mut buf = vec![0; 1024];
+    loop {
+        tokio::select! {
+            data = output.recv() => match data {
+                Ok(data) => writer.write_all(&data).await?,
+                Err(broadcast::error::RecvError::Lagged(count)) => {
+                    debug!("Attached terminal skipped {} chunks of output", count);
+                }
+                Err(broadcast::error::RecvError::Closed) => break,
+            },
+            read = reader.read(&mut buf) => {
+                let read = read?;
+                if read == 0 {
+                    break;
+                }
+                // Input from terminals that are only allowed to watch is ignored
+                if let Some(input) = &input {
+                    input.send(Bytes::copy_from_slice(&buf[..read])).await?;
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Sends everything that is written to it to the attached terminals
+pub struct ShareWriter {
+    output: broadcast::Sender<Bytes>,
+}
+
+impl AsyncWrite for ShareWriter {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        _cx: &mut TaskContext<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        // This only fails if no terminals are attached at the moment
+        let _ = self.output.send(Bytes::copy_from_slice(buf));
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut TaskContext<'_>) -> Poll<std::io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut TaskContext<'_>) -> Poll<std::io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+}
+
+/// Attach to a session that was shared with `fp shell --share` and show its
+/// output until the session ends or the user detaches
+pub async fn attach(address: &str, token: Option<String>) -> Result<()> {
+    let stream = TcpStream::connect(address)
+        .await
+        .with_context(|| format!("Unable to connect to the shared session at {address}"))?;
+    let (reader, mut writer) = stream.into_split();
+    let mut reader = BufReader::new(reader);
+
+    let request = AttachRequest {
+        token: token.unwrap_or_default(),
+    };
+    writer
+        .write_all(format!("{}\n", serde_json::to_string(&request)?).as_bytes())
+        .await?;
+
+    let mut line = String::new();
+    reader.read_line(&mut line).await?;
+    let response: AttachResponse =
+        serde_json::from_str(&line).context("Invalid response from the shared session")?;
+    let (width, height, writable) = match response {
+        AttachResponse::Ok {
+            width,
+            height,
+            writable,
+        } => (width, height, writable),
+        AttachResponse::Error { message } => bail!("Unable to attach to the session: {message}"),
+    };
+
+    let (cols, rows) = crossterm::terminal::size()?;
+    if cols < width || rows < height {
+        warn!(
+            "The shared terminal is {}x{}, which is larger than this one. The output might look garbled.",
+            width, height
+        );
+    }
+
+    if writable {
+        info!("Attached to the session. Press Ctrl+] to detach.");
+        // Raw mode makes sure keys such as Ctrl+C are sent to the shell
+        let _guard = RawGuard::new();
+        tokio::select! {
+            result = show_output(reader) => result,
+            result = forward_input(writer) => result,
+        }
+    } else {
+        info!("Attached to the session (read-only). Press Ctrl+C to detach.");
+        tokio::select! {
+            result = show_output(reader) => result,
+            _ = signal::ctrl_c() => Ok(()),
+        }
+    }
+}
+
+async fn show_output(mut reader: impl AsyncReadExt + Unpin) -> Result<()> {
+    let mut stdout = tokio::io::stdout();
+    let mut buf = vec![0; 4096];
+    loop {
+        let read = reader.read(&mut buf).await?;
+        if read == 0 {
+            return Ok(());
+        }
+        stdout.write_all(&buf[..read]).await?;
+        stdout.flush().await?;
+    }
+}
+
+async fn forward_input(mut writer: impl AsyncWriteExt + Unpin) -> Result<()> {
+    let mut stdin = Unblock::new(std::io::stdin()).compat();
+    let mut buf = vec![0; 1024];
+    loop {
+        let read = stdin.read(&mut buf).await?;
+        if read == 0 {
+            return Ok(());
+        }
+        match buf[..read].iter().position(|byte| *byte == DETACH_KEY) {
+            Some(position) => {
+                writer.write_all(&buf[..position]).await?;
+                return Ok(());
+            }
+            None => writer.write_all(&buf[..read]).await?,
+        }
+    }
+}
+
+/// Compare the tokens in constant time, so the time it takes to turn away a
+/// terminal doesn't reveal how much of its token was right
+fn tokens_match(given: &str, expected: &str) -> bool {
+    given.len() == expected.len()
+        && given
+            .bytes()
+            .zip(expected.bytes())
+            .fold(0, |difference, (a, b)| difference | (a ^ b))
+            == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn share_output_and_input() {
+        let mut server = ShareServer::bind(
+            "127.0.0.1:0".parse().unwrap(),
+            "secret".to_string(),
+            true,
+            false,
+            (80, 24),
+        )
+        .await
+        .unwrap();
+        let mut input = server.take_input().unwrap();
+
+        let stream = TcpStream::connect(server.address()).await.unwrap();
+        let (reader, mut writer) = stream.into_split();
+        let mut reader = BufReader::new(reader);
+        writer.write_all(b"{\"token\":\"secret\"}\n").await.unwrap();
+        let mut line = String::new();
+        reader.read_line(&mut line).await.unwrap();
+        assert_eq!(
+            line,
+            "{\"result\":\"ok\",\"width\":80,\"height\":24,\"writable\":true}\n"
+        );
+
+        server.writer().write_all(b"$ ls\r\n").await.unwrap();
+        let mut output = [0; 6];
+        reader.read_exact(&mut output).await.unwrap();
+        assert_eq!(&output, b"$ ls\r\n");
+
+        writer.write_all(b"pwd\r").await.unwrap();
+        assert_eq!(input.recv().await.unwrap(), Bytes::from_static(b"pwd\r"));
+
+        // Terminals with the wrong token are turned away
+        let stream = TcpStream::connect(server.address()).await.unwrap();
+        let (reader, mut writer) = stream.into_split();
+        writer.write_all(b"{\"token\":\"guess\"}\n").await.unwrap();
+        let mut line = String::new();
+        BufReader::new(reader).read_line(&mut line).await.unwrap();
+        assert_eq!(
+            line,
+            "{\"result\":\"error\",\"message\":\"invalid token\"}\n"
+        );
+
+        // Requests that never end are cut off
+        let stream = TcpStream::connect(server.address()).await.unwrap();
+        let (reader, mut writer) = stream.into_split();
+        writer.write_all(&[b' '; 4096]).await.unwrap();
+        let mut line = String::new();
+        BufReader::new(reader).read_line(&mut line).await.unwrap();
+        assert!(line.starts_with("{\"result\":\"error\""), "{}", line);
+    }
+
+    #[test]
+    fn compare_tokens() {
+        assert!(tokens_match("secret", "secret"));
+        assert!(!tokens_match("secreT", "secret"));
+        assert!(!tokens_match("secret!", "secret"));
+        assert!(!tokens_match("", "secret"));
+    }
+
+    #[tokio::test]
+    async fn refuse_remote_address() {
+        let result = ShareServer::bind(
+            "0.0.0.0:0".parse().unwrap(),
+            "secret".to_string(),
+            false,
+            false,
+            (80, 24),
+        )
+        .await;
+        assert!(result.is_err());
+    }
+}
@@ -1,3 +1,4 @@
+use super::control::CONTROL_ENV_VAR_NAME;
 use super::shell_type::ShellType;
 use super::terminal_extractor::{
     END_PROMPT_BYTES, END_PROMPT_CHAR, END_PROMPT_REPEATS, START_PROMPT_BYTES, START_PROMPT_CHAR,
@@ -15,27 +16,47 @@ use tracing::trace;
 pub struct ShellLauncher {
     shell_type: ShellType,
     path: PathBuf,
-    notebook_id: String,
+    /// Not set when the session is written to a local notebook
+    notebook_id: Option<String>,
+    control: String,
 }
 
 pub const NESTED_SHELL_SESSION_ENV_VAR_NAME: &str = "__FP_SHELL_SESSION";
 
+const NUSHELL_PROMPT_COMMAND: &str = "let __fp_prompt = ($env.PROMPT_COMMAND? | default ''); \
+    let __fp_indicator = ($env.PROMPT_INDICATOR? | default '> '); \
+    $env.PROMPT_COMMAND = {|| $\"{START}(if ($__fp_prompt | describe | str starts-with 'closure') { do $__fp_prompt } else { $__fp_prompt })\" }; \
+    $env.PROMPT_INDICATOR = {|| $\"(if ($__fp_indicator | describe | str starts-with 'closure') { do $__fp_indicator } else { $__fp_indicator }){END}\" }";
+
 impl ShellLauncher {
-    pub fn new(notebook_id: String) -> Self {
-        let (shell_type, path) = ShellType::auto_detect();
+    /// Create a launcher for the given shell, or for the shell `fp` was
+    /// launched from if none is given
+    pub fn new(notebook_id: Option<String>, control: String, shell: Option<PathBuf>) -> Self {
+        let (shell_type, path) = match shell {
+            Some(path) => (ShellType::from_path(&path), path),
+            None => ShellType::auto_detect(),
+        };
         Self {
             shell_type,
             path,
             notebook_id,
+            control,
         }
     }
 
+    pub fn shell_type(&self) -> &ShellType {
+        &self.shell_type
+    }
+
     pub fn build_command(&self) -> CommandBuilder {
         let mut cmd = CommandBuilder::new(&self.path);
 
         cmd.cwd(std::env::current_dir().unwrap());
-        cmd.env("NOTEBOOK_ID", &self.notebook_id);
+        if let Some(notebook_id) = &self.notebook_id {
+            cmd.env("NOTEBOOK_ID", notebook_id);
+        }
         cmd.env(NESTED_SHELL_SESSION_ENV_VAR_NAME, "1");
+        cmd.env(CONTROL_ENV_VAR_NAME, &self.control);
 
         if self.shell_type == ShellType::PowerShell {
             // Launch powershell with a custom command and don't exit (aka stay interactive) after completing it.
@@ -77,31 +98,64 @@ impl ShellLauncher {
     ) -> Result<()> {
         match self.shell_type {
             ShellType::Bash | ShellType::Sh | ShellType::Zsh => {
-                //this produces the escaped string: "\xE2\x80\x8B\xE2\x80\x8B"
-                let escaped_start_bytes = String::from_utf8(
-                    START_PROMPT_BYTES
-                        .iter()
-                        .flat_map(|b| std::ascii::escape_default(*b))
-                        .collect(),
-                )
-                .unwrap();
-
-                let escaped_end_bytes = String::from_utf8(
-                    END_PROMPT_BYTES
-                        .iter()
-                        .flat_map(|b| std::ascii::escape_default(*b))
-                        .collect(),
-                )
-                .unwrap();
+                //this produces the escaped string: "\342\200\213\342\200\213"
+                let escaped_start_bytes = octal_escaped_bytes(START_PROMPT_BYTES);
+                let escaped_end_bytes = octal_escaped_bytes(END_PROMPT_BYTES);
+                // The prompt reports the exit status of the previous command with the
+                // (zero width) escape sequence of the FinalTerm semantic prompt protocol
+                let exit_status = match self.shell_type {
+                    ShellType::Bash => "\\[\\e]133;D;\\$?\\a\\]",
+                    ShellType::Zsh => "%{$(printf '\\033]133;D;')%?$(printf '\\007')%}",
+                    _ => "",
+                };
 
                 // For unix shells we do more or less the same as for Powershell above but with the escaping done on the rust side.
                 // A magician never reveals his tricks so the export command from the shell history so the user can't press arrow up to see it :^)
                 stdin
                     .write_all(
-                            format!("export PS1=\"$(printf '{escaped_start_bytes}')${{PS1}}$(printf '{escaped_end_bytes}')\";history -d $(history 1)\n").as_bytes(),
+                            format!("export PS1=\"$(printf '{escaped_start_bytes}'){exit_status}${{PS1}}$(printf '{escaped_end_bytes}')\";history -d $(history 1)\n").as_bytes(),
                     )
                     .await?;
             }
+            ShellType::Ksh | ShellType::Dash => {
+                // Same as above, but these shells don't support deleting entries from the history
+                stdin
+                    .write_all(
+                        format!(
+                            "export PS1=\"$(printf '{}')${{PS1}}$(printf '{}')\"\n",
+                            octal_escaped_bytes(START_PROMPT_BYTES),
+                            octal_escaped_bytes(END_PROMPT_BYTES)
+                        )
+                        .as_bytes(),
+                    )
+                    .await?;
+            }
+            ShellType::Fish => {
+                // Fish renders its prompt with the `fish_prompt` function, so we wrap the existing one.
+                // Commands that start with a space don't end up in fish's history.
+                stdin
+                    .write_all(
+                        format!(
+                            " functions --copy fish_prompt __fp_fish_prompt; function fish_prompt; printf '{}'; __fp_fish_prompt; printf '{}'; end; function __fp_postexec --on-event fish_postexec; printf '\\e]133;D;%s\\a' $status; end\n",
+                            escaped_bytes(START_PROMPT_BYTES),
+                            escaped_bytes(END_PROMPT_BYTES)
+                        )
+                        .as_bytes(),
+                    )
+                    .await?;
+            }
+            ShellType::Nushell => {
+                // Nushell renders the prompt and the indicator after it (`> ` by default) separately, so the
+                // start marker goes into the first and the end marker into the latter. Both can either be a
+                // closure or a plain string.
+                let command = NUSHELL_PROMPT_COMMAND
+                    .replace(
+                        "{START}",
+                        &"(char --unicode 200b)".repeat(START_PROMPT_REPEATS),
+                    )
+                    .replace("{END}", &"(char --unicode 200c)".repeat(END_PROMPT_REPEATS));
+                stdin.write_all(format!("{command}\n").as_bytes()).await?;
+            }
             ShellType::PowerShell => {
                 // For whatever reason Powershell doesn't properly pickup where its current cursor position
                 // is when launched under a PTY so we have to clear and reset the cursor position get make
@@ -120,9 +174,50 @@ impl ShellLauncher {
                     )
                     .await?;
             }
+            ShellType::Unknown => {
+                tokio::io::stdout()
+                    .write_all(b"This shell is not supported, so all commands will be recorded into a single cell.\n")
+                    .await?;
+            }
         }
 
         tokio::io::stdout().write_all(b"Recording started! In order to finish recording and exit, press CTRL + D or type `exit`.\n").await?;
+        tokio::io::stdout().write_all(b"Use `fp shell pause`, `fp shell resume` and `fp shell mark <text>` to control the recording.\n").await?;
         Ok(())
     }
 }
+
+/// Escape the bytes as octal escapes for `printf`. Unlike `\x` escapes,
+/// these are supported by every POSIX `printf`, including the one of dash.
+fn octal_escaped_bytes(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("\\{byte:03o}")).collect()
+}
+
+/// Escape the bytes so they can be passed to fish's `printf`
+fn escaped_bytes(bytes: &[u8]) -> String {
+    String::from_utf8(
+        bytes
+            .iter()
+            .flat_map(|b| std::ascii::escape_default(*b))
+            .collect(),
+    )
+    .unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn posix_shells_use_octal_escapes() {
+        for shell in ["sh", "bash", "zsh", "ksh", "mksh", "dash"] {
+            let launcher = ShellLauncher::new(None, String::new(), Some(PathBuf::from(shell)));
+            let mut stdin = futures::io::Cursor::new(Vec::new());
+            launcher.initialize_shell(&mut stdin).await.unwrap();
+
+            let init = String::from_utf8(stdin.into_inner()).unwrap();
+            assert!(init.contains("printf '\\342\\200\\213"), "{shell}: {init}");
+            assert!(!init.contains("\\x"), "{shell}: {init}");
+        }
+    }
+}
@@ -1,5 +1,6 @@
-use std::{ffi::OsStr, path::PathBuf};
+use std::{ffi::OsStr, path::Path, path::PathBuf};
 use sysinfo::{ProcessExt, ProcessRefreshKind, RefreshKind, SystemExt};
+use tracing::debug;
 
 #[derive(Debug, PartialEq, Eq)]
 pub enum ShellType {
@@ -8,6 +9,13 @@ pub enum ShellType {
     Bash,
     Sh,
     Zsh,
+    Ksh,
+    Dash,
+    Fish,
+    Nushell,
+    /// A shell we don't know how to inject the prompt markers into. Sessions
+    /// still get recorded, but without separating the commands.
+    Unknown,
 }
 
 impl ShellType {
@@ -20,34 +28,86 @@ impl ShellType {
     /// will still be `bash`.
     /// Some more information on guessing the current shell can be found here:
     /// https://man.archlinux.org/man/community/perl-shell-guess/Shell::Guess.3pm.en
+    ///
+    /// If the parent process is not a shell we know, we fall back to the
+    /// `$SHELL` (or `%COMSPEC%` on Windows) env var after all.
     pub fn auto_detect() -> (Self, PathBuf) {
         let sys = sysinfo::System::new_with_specifics(
             RefreshKind::new().with_processes(ProcessRefreshKind::everything()),
         );
 
-        let path = sys
-            .process(sysinfo::get_current_pid().unwrap())
+        let parent_path = sysinfo::get_current_pid()
+            .ok()
+            .and_then(|pid| sys.process(pid))
             .and_then(ProcessExt::parent)
             .and_then(|pid| sys.process(pid))
-            .map(ProcessExt::exe)
-            .unwrap();
+            .map(|process| process.exe().to_owned());
+
+        if let Some(path) = parent_path {
+            let shell_type = Self::from_path(&path);
+            if shell_type != ShellType::Unknown {
+                return (shell_type, path);
+            }
+            debug!("Parent process {} is not a known shell", path.display());
+        }
+
+        let path = std::env::var_os("SHELL")
+            .or_else(|| std::env::var_os("COMSPEC"))
+            .map(PathBuf::from)
+            .unwrap_or_else(|| PathBuf::from(if cfg!(windows) { "cmd" } else { "sh" }));
+        (Self::from_path(&path), path)
+    }
 
+    /// Determine the type of the shell by the file name of its executable
+    pub fn from_path(path: &Path) -> Self {
         let exe = path
             .file_stem()
             .and_then(OsStr::to_str)
             .map(str::to_lowercase);
 
-        (
-            match exe.as_deref() {
-                Some("pwsh" | "powershell") => ShellType::PowerShell,
-                Some("cmd") => ShellType::Cmd,
-                Some("bash") => ShellType::Bash,
-                Some("sh") => ShellType::Sh,
-                Some("zsh") => ShellType::Zsh,
-                Some(shell) => panic!("Unsupported shell type {}", shell),
-                None => panic!("Must be launched from a shell parent"),
-            },
-            path.to_owned(),
-        )
+        match exe.as_deref() {
+            Some("pwsh" | "powershell") => ShellType::PowerShell,
+            Some("cmd") => ShellType::Cmd,
+            Some("bash") => ShellType::Bash,
+            Some("sh") => ShellType::Sh,
+            Some("zsh") => ShellType::Zsh,
+            Some("ksh" | "ksh93" | "mksh") => ShellType::Ksh,
+            Some("dash") => ShellType::Dash,
+            Some("fish") => ShellType::Fish,
+            Some("nu") => ShellType::Nushell,
+            _ => ShellType::Unknown,
+        }
+    }
+
+    /// Whether we can inject markers into the prompt of this shell, which is
+    /// what allows us to tell the commands apart
+    pub fn supports_prompt_markers(&self) -> bool {
+        *self != ShellType::Unknown
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn shell_type_from_path() {
+        assert_eq!(
+            ShellType::from_path(Path::new("/bin/bash")),
+            ShellType::Bash
+        );
+        assert_eq!(
+            ShellType::from_path(Path::new("/opt/homebrew/bin/fish")),
+            ShellType::Fish
+        );
+        assert_eq!(ShellType::from_path(Path::new("nu")), ShellType::Nushell);
+        assert_eq!(
+            ShellType::from_path(Path::new("pwsh.exe")),
+            ShellType::PowerShell
+        );
+        assert_eq!(
+            ShellType::from_path(Path::new("/usr/bin/xonsh")),
+            ShellType::Unknown
+        );
     }
 }
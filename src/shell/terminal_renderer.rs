@@ -10,7 +10,7 @@ pub struct TerminalRenderer<W: AsyncWriteExt> {
     stdout: W,
 }
 
-fn get_styled_bytes() -> &'static [u8] {
+pub(super) fn get_styled_bytes() -> &'static [u8] {
     // ---------------------- ATTENTION ----------------------
     // Don't change this unless you know what you're doing!
     // Check the explanation above `START_PROMPT_CHAR` in
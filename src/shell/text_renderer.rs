@@ -1,26 +1,86 @@
 use super::terminal_extractor::PtyOutput;
+use crate::ansi::Style;
 use anyhow::Result;
-use termwiz::escape::csi::{DecPrivateMode, DecPrivateModeCode, Mode};
-use termwiz::escape::{parser::Parser, Action, ControlCode, CSI};
+use std::cmp;
+use termwiz::escape::csi::{
+    Cursor, DecPrivateMode, DecPrivateModeCode, Edit, EraseInDisplay, EraseInLine, Mode,
+};
+use termwiz::escape::osc::FinalTermSemanticPrompt;
+use termwiz::escape::{
+    parser::Parser, Action, ControlCode, Esc, EscCode, OperatingSystemCommand, CSI,
+};
 use tokio::io::AsyncWriteExt;
 use tracing::trace;
 
+/// Renders the output of the terminal to plain text.
+///
+/// The lines that are still on the screen are kept in a small virtual screen,
+/// so that carriage returns, cursor movements and erase sequences (used by
+/// progress bars and the like) are applied before the text is written. Lines
+/// are only written once they scroll off the screen or when the renderer is
+/// flushed.
+///
+/// The text style is kept as well: changes to it are written as escape
+/// sequences, which can be translated to formatting with
+/// [`StyledText`](crate::ansi::StyledText).
 pub struct TextRenderer<W: AsyncWriteExt> {
     parser: Parser,
     alternate_mode: bool,
     writer: W,
-    current_line: String,
-    position: usize,
+    /// The lines that are still on the screen. There is always at least one.
+    lines: Vec<Line>,
+    row: usize,
+    col: usize,
+    /// The style of the text that is printed next
+    style: Style,
+    screen_width: usize,
+    screen_height: usize,
+    /// Number of lines that were written already, which is needed to restore
+    /// a saved cursor position
+    written_lines: usize,
+    saved_position: Option<(usize, usize)>,
+    /// Lines of the prompt that are held back until we know whether the user
+    /// actually entered a command
+    held_prompt: Option<String>,
+    /// Column at which the prompt ends and the command that the user types
+    /// starts, on the first line of the screen
+    prompt_end: Option<usize>,
+    /// The command that was entered, if it wasn't taken yet
+    command_line: Option<CommandLine>,
+    /// Exit status of the last command, as reported by the prompt hook
+    exit_status: Option<i32>,
 }
 
+/// A command the user entered at the prompt
+#[derive(Debug, PartialEq)]
+pub struct CommandLine {
+    /// The prompt and the command, the way they were shown in the terminal
+    pub text: String,
+    /// Only the command itself
+    pub command: String,
+}
+
+/// A line on the screen with the style of each character
+type Line = Vec<(char, Style)>;
+
 impl<W: AsyncWriteExt + Unpin> TextRenderer<W> {
-    pub fn new(writer: W) -> Self {
+    pub fn new(writer: W, screen_width: usize, screen_height: usize) -> Self {
         Self {
             parser: Parser::new(),
             alternate_mode: false,
             writer,
-            current_line: String::new(),
-            position: 0,
+            lines: vec![Vec::new()],
+            row: 0,
+            col: 0,
+            style: Style::default(),
+            screen_width: cmp::max(screen_width, 1),
+            screen_height: cmp::max(screen_height, 1),
+            written_lines: 0,
+            saved_position: None,
+            held_prompt: None,
+            prompt_end: None,
+            command_line: None,
+            exit_status: None,
         }
     }
 
@@ -28,59 +88,176 @@ impl<W: AsyncWriteExt + Unpin> TextRenderer<W> {
         &mut self.writer
     }
 
-    fn push_char(&mut self, c: char) {
-        if !self.alternate_mode {
-            self.current_line.push(c);
-            self.position += c.len_utf8();
+    /// Take the prompt and the command the user entered. This is not written
+    /// to the inner writer, so everything written there after this belongs
+    /// to the output of the command.
+    pub fn take_command_line(&mut self) -> Option<CommandLine> {
+        self.command_line.take()
+    }
+
+    /// Take the exit status of the last command, if the shell reported it
+    /// since the last time
+    pub fn take_exit_status(&mut self) -> Option<i32> {
+        self.exit_status.take()
+    }
+
+    fn line_mut(&mut self) -> &mut Line {
+        &mut self.lines[self.row]
+    }
+
+    fn print(&mut self, c: char) {
+        let col = self.col;
+        let style = self.style;
+        let line = self.line_mut();
+        if col < line.len() {
+            line[col] = (c, style);
+        } else {
+            line.resize(col, BLANK);
+            line.push((c, style));
+        }
+        self.col += 1;
+    }
+
+    fn move_up(&mut self, count: u32) {
+        // Lines that were written already can't be changed anymore
+        self.row = self.row.saturating_sub(count as usize);
+    }
+
+    /// Move the cursor down, stopping at the bottom of the screen like a
+    /// terminal does. Only line feeds scroll the screen.
+    fn move_down(&mut self, count: u32) {
+        self.row = cmp::min(
+            self.row.saturating_add(count as usize),
+            self.screen_height - 1,
+        );
+        if self.row >= self.lines.len() {
+            self.lines.resize(self.row + 1, Vec::new());
         }
     }
 
+    /// Move the cursor to the next line, scrolling the screen if the cursor
+    /// is on the last line
+    async fn line_feed(&mut self) -> Result<()> {
+        self.row += 1;
+        if self.row >= self.lines.len() {
+            self.lines.push(Vec::new());
+        }
+
+        // Write the line that scrolled off the screen
+        if self.row >= self.screen_height {
+            let line = self.take_lines(1);
+            self.write_line(line).await?;
+        }
+        Ok(())
+    }
+
+    /// Move the cursor right, stopping at the last column. Lines are not
+    /// wrapped, so the cursor can be beyond that already.
+    fn move_right(&mut self, count: u32) {
+        let last_col = cmp::max(self.screen_width - 1, self.col);
+        self.col = cmp::min(self.col.saturating_add(count as usize), last_col);
+    }
+
+    /// Move the cursor to a column, stopping at the last one
+    fn set_col(&mut self, col: usize) {
+        self.col = cmp::min(col, self.screen_width - 1);
+    }
+
+    /// Remove the first `count` lines from the screen
+    fn take_lines(&mut self, count: usize) -> String {
+        self.row -= count;
+        self.written_lines += count;
+        self.lines
+            .drain(..count)
+            .map(|line| line_to_string(&line) + "\n")
+            .collect()
+    }
+
+    async fn write_line(&mut self, line: String) -> Result<()> {
+        match &mut self.held_prompt {
+            Some(held_prompt) => held_prompt.push_str(&line),
+            None => self.writer.write_all(line.as_bytes()).await?,
+        }
+        Ok(())
+    }
+
+    /// Called when the user pressed enter after the prompt
+    fn submit_command(&mut self) {
+        let prompt_end = self.prompt_end.take().unwrap_or_default();
+        let command: String = self.lines[..self.row]
+            .iter()
+            .enumerate()
+            .flat_map(|(index, line)| {
+                let start = if index == 0 { prompt_end } else { 0 };
+                line.get(start..).unwrap_or_default().iter().map(|(c, _)| c)
+            })
+            .collect();
+
+        let lines = self.take_lines(self.row);
+        let mut prompt = self.held_prompt.take().unwrap_or_default();
+        prompt.push_str(&lines);
+
+        // Prompts where the user didn't enter a command are dropped
+        let command = command.trim();
+        if !command.is_empty() {
+            self.command_line = Some(CommandLine {
+                text: prompt,
+                command: command.to_string(),
+            });
+        }
+    }
+
+    /// Write all lines that are on the screen, including the last one even
+    /// if it doesn't end with a newline yet
     pub async fn flush(&mut self) -> Result<()> {
-        self.writer.write_all(self.current_line.as_bytes()).await?;
-        self.current_line.clear();
-        self.position = 0;
+        let last_line = line_to_string(&self.lines.pop().unwrap_or_default());
+        let row_count = self.lines.len();
+        self.row = row_count;
+        let mut text = self.take_lines(row_count);
+        text.push_str(&last_line);
+
+        self.lines = vec![Vec::new()];
+        self.row = 0;
+        self.col = 0;
+
+        // A prompt that is still being held back was never submitted
+        if self.held_prompt.take().is_none() {
+            self.writer.write_all(text.as_bytes()).await?;
+        }
+        self.prompt_end = None;
 
         Ok(())
     }
 
     pub async fn handle_pty_output<'a>(&mut self, output: &'a PtyOutput<'a>) -> Result<()> {
-        if let PtyOutput::Data(data) = output {
-            self.on_data(data).await?
+        match output {
+            PtyOutput::Data(data) => self.on_data(data).await?,
+            PtyOutput::PromptStart => {
+                // Anything before the prompt belongs to the previous command
+                self.flush().await?;
+                self.held_prompt = Some(String::new());
+                self.prompt_end = None;
+            }
+            PtyOutput::PromptEnd => {
+                if self.held_prompt.is_some() {
+                    // Only keep the line the command is typed on on the screen
+                    let lines = self.take_lines(self.row);
+                    if let Some(held_prompt) = &mut self.held_prompt {
+                        held_prompt.push_str(&lines);
+                    }
+                    self.prompt_end = Some(self.col);
+                }
+            }
         }
         Ok(())
     }
 
     pub async fn on_data(&mut self, data: &[u8]) -> Result<()> {
-        let mut i = 0;
-        while let Some((action, consumed)) = self.parser.parse_first(&data[i..]) {
-            i += consumed;
+        // A single escape sequence can result in multiple actions, such as
+        // `ESC[1;31m` which sets both the intensity and the colour
+        for action in self.parser.parse_as_vec(data) {
             trace!(?action);
             match action {
-                Action::Print(c) => self.push_char(c),
-                Action::Control(c @ ControlCode::HorizontalTab) => self.push_char(c as u8 as char),
-                Action::Control(c @ ControlCode::LineFeed) => {
-                    self.push_char(c as u8 as char);
-                    self.flush().await?;
-                }
-                Action::Control(ControlCode::Backspace) => {
-                    if !self.alternate_mode {
-                        trace!(
-                            "Removing char at position {}, from current_line with len {}",
-                            self.position,
-                            self.current_line.len()
-                        );
-                        let len = if self.position == self.current_line.len() {
-                            self.current_line
-                                .pop()
-                                .map(char::len_utf8)
-                                .unwrap_or_default()
-                        } else {
-                            self.current_line.remove(self.position).len_utf8()
-                        };
-
-                        self.position -= len;
-                    }
-                }
                 // This matches the magic incantation terminal programs output in order to enter alternate mode/screen:
                 // https://superuser.com/a/321233
                 // Since that mode is generally used for interactive programs like htop and vim we don't want
@@ -105,12 +282,147 @@ impl<W: AsyncWriteExt + Unpin> TextRenderer<W> {
                         _ => {}
                     }
                 }
+                Action::OperatingSystemCommand(osc) => {
+                    if let OperatingSystemCommand::FinalTermSemanticPrompt(
+                        FinalTermSemanticPrompt::CommandStatus { status, .. },
+                    ) = *osc
+                    {
+                        self.exit_status = Some(status);
+                    }
+                }
+                _ if self.alternate_mode => {}
+                Action::Print(c) => self.print(c),
+                Action::Control(c @ ControlCode::HorizontalTab) => self.print(c as u8 as char),
+                // The PTY translates newlines into CRLF, but we don't rely on that
+                Action::Control(ControlCode::LineFeed) => {
+                    self.col = 0;
+                    self.line_feed().await?;
+                    if self.held_prompt.is_some() && self.prompt_end.is_some() {
+                        self.submit_command();
+                    }
+                }
+                Action::Esc(Esc::Code(EscCode::NextLine)) => {
+                    self.col = 0;
+                    self.line_feed().await?;
+                }
+                Action::Esc(Esc::Code(EscCode::Index)) => self.line_feed().await?,
+                Action::Control(ControlCode::CarriageReturn) => self.col = 0,
+                Action::Control(ControlCode::Backspace) => {
+                    self.col = self.col.saturating_sub(1);
+                }
+                Action::CSI(CSI::Cursor(cursor)) => self.on_cursor(cursor),
+                Action::CSI(CSI::Edit(edit)) => self.on_edit(edit),
+                Action::CSI(CSI::Sgr(sgr)) => self.style.apply(&sgr),
+                Action::Esc(Esc::Code(EscCode::DecSaveCursorPosition)) => self.save_cursor(),
+                Action::Esc(Esc::Code(EscCode::DecRestoreCursorPosition)) => self.restore_cursor(),
                 _ => {}
             }
         }
 
         Ok(())
     }
+
+    fn on_cursor(&mut self, cursor: Cursor) {
+        match cursor {
+            Cursor::Up(count) | Cursor::LinePositionBackward(count) => self.move_up(count),
+            Cursor::PrecedingLine(count) => {
+                self.move_up(count);
+                self.col = 0;
+            }
+            Cursor::Down(count) | Cursor::LinePositionForward(count) => self.move_down(count),
+            Cursor::NextLine(count) => {
+                self.col = 0;
+                self.move_down(count);
+            }
+            Cursor::Left(count) | Cursor::CharacterPositionBackward(count) => {
+                self.col = self.col.saturating_sub(count as usize)
+            }
+            Cursor::Right(count) | Cursor::CharacterPositionForward(count) => {
+                self.move_right(count)
+            }
+            // We don't know where the top of the screen is, so for absolute
+            // positions only the column is used
+            Cursor::CharacterAbsolute(col)
+            | Cursor::CharacterPositionAbsolute(col)
+            | Cursor::Position { col, .. }
+            | Cursor::CharacterAndLinePosition { col, .. } => {
+                self.set_col(col.as_zero_based() as usize)
+            }
+            Cursor::SaveCursor => self.save_cursor(),
+            Cursor::RestoreCursor => self.restore_cursor(),
+            _ => {}
+        }
+    }
+
+    fn on_edit(&mut self, edit: Edit) {
+        let col = self.col;
+        let row = self.row;
+        let line = self.line_mut();
+        match edit {
+            Edit::EraseInLine(EraseInLine::EraseToEndOfLine) => line.truncate(col),
+            Edit::EraseInLine(EraseInLine::EraseToStartOfLine) => {
+                let end = cmp::min(col + 1, line.len());
+                line[..end].fill(BLANK);
+            }
+            Edit::EraseInLine(EraseInLine::EraseLine) => line.clear(),
+            Edit::EraseCharacter(count) => {
+                let end = cmp::min(col + count as usize, line.len());
+                if col < end {
+                    line[col..end].fill(BLANK);
+                }
+            }
+            Edit::DeleteCharacter(count) => {
+                let end = cmp::min(col + count as usize, line.len());
+                if col < end {
+                    line.drain(col..end);
+                }
+            }
+            Edit::InsertCharacter(count) => {
+                if col < line.len() {
+                    line.splice(col..col, std::iter::repeat(BLANK).take(count as usize));
+                }
+            }
+            Edit::EraseInDisplay(EraseInDisplay::EraseToEndOfDisplay) => {
+                line.truncate(col);
+                self.lines.truncate(row + 1);
+            }
+            // Clearing the screen doesn't undo what the user has seen before
+            _ => {}
+        }
+    }
+
+    fn save_cursor(&mut self) {
+        self.saved_position = Some((self.written_lines + self.row, self.col));
+    }
+
+    fn restore_cursor(&mut self) {
+        if let Some((line, col)) = self.saved_position {
+            self.row = cmp::min(
+                line.saturating_sub(self.written_lines),
+                self.lines.len() - 1,
+            );
+            self.col = col;
+        }
+    }
+}
+
+const BLANK: (char, Style) = (' ', Style::DEFAULT);
+
+/// Convert the line to text, with escape sequences where the style changes
+fn line_to_string(line: &[(char, Style)]) -> String {
+    let mut text = String::with_capacity(line.len());
+    let mut current_style = Style::default();
+    for (c, style) in line {
+        if *style != current_style {
+            text.push_str(&style.escape_sequence());
+            current_style = *style;
+        }
+        text.push(*c);
+    }
+    if current_style != Style::default() {
+        text.push_str(&Style::default().escape_sequence());
+    }
+    text
 }
 
 #[cfg(test)]
@@ -120,15 +432,16 @@ mod tests {
     #[tokio::test]
     async fn basic_test() {
         let mut buf = vec![];
-        let mut render = TextRenderer::new(&mut buf);
+        let mut render = TextRenderer::new(&mut buf, 80, 24);
         render.on_data("hello world\n".as_bytes()).await.unwrap();
+        render.flush().await.unwrap();
         assert_eq!(&buf, "hello world\n".as_bytes());
     }
 
     #[tokio::test]
     async fn strips_alternate_mode() {
         let mut buf = vec![];
-        let mut render = TextRenderer::new(&mut buf);
+        let mut render = TextRenderer::new(&mut buf, 80, 24);
         render
             .on_data(
                 format!(
@@ -144,6 +457,123 @@ mod tests {
             )
             .await
             .unwrap();
+        render.flush().await.unwrap();
         assert_eq!(&buf, "hello world\n".as_bytes());
     }
+
+    #[tokio::test]
+    async fn separates_commands() {
+        let mut buf = vec![];
+        let mut render = TextRenderer::new(&mut buf, 80, 24);
+        for output in [
+            PtyOutput::PromptStart,
+            PtyOutput::Data(b"~/code\n$ "),
+            PtyOutput::PromptEnd,
+            PtyOutput::Data(b"ls\n"),
+        ] {
+            render.handle_pty_output(&output).await.unwrap();
+        }
+        assert_eq!(
+            render.take_command_line(),
+            Some(CommandLine {
+                text: "~/code\n$ ls\n".to_string(),
+                command: "ls".to_string(),
+            })
+        );
+
+        for output in [
+            PtyOutput::Data(b"README.md\n"),
+            PtyOutput::PromptStart,
+            PtyOutput::Data(b"\x1b]133;D;0\x07$ "),
+            PtyOutput::PromptEnd,
+            PtyOutput::Data(b"\n"),
+            PtyOutput::PromptStart,
+        ] {
+            render.handle_pty_output(&output).await.unwrap();
+        }
+        // Empty commands are dropped
+        assert_eq!(render.take_command_line(), None);
+        assert_eq!(render.take_exit_status(), Some(0));
+        assert_eq!(&buf, "README.md\n".as_bytes());
+    }
+
+    #[tokio::test]
+    async fn applies_cursor_movement() {
+        let mut buf = vec![];
+        let mut render = TextRenderer::new(&mut buf, 80, 24);
+        // A progress bar that is redrawn using a carriage return
+        render
+            .on_data(b"Downloading  10%\rDownloading 100%\n")
+            .await
+            .unwrap();
+        // Two lines that are updated by moving the cursor up and erasing them
+        render
+            .on_data(b"layer 1: waiting\nlayer 2: waiting\n\x1b[2A\x1b[2Klayer 1: done\n\x1b[2Klayer 2: done\n")
+            .await
+            .unwrap();
+        // Backspaces move the cursor, the characters are overwritten
+        render.on_data(b"abc\x08\x08XY\n").await.unwrap();
+        render.flush().await.unwrap();
+        assert_eq!(
+            String::from_utf8(buf).unwrap(),
+            "Downloading 100%\nlayer 1: done\nlayer 2: done\naXY\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn writes_lines_that_scroll_off_the_screen() {
+        let mut buf = vec![];
+        let mut render = TextRenderer::new(&mut buf, 80, 2);
+        render.on_data(b"one\ntwo\nthree").await.unwrap();
+        assert_eq!(render.inner_mut().as_slice(), b"one\n");
+        // Lines that were written can't be changed anymore
+        render.on_data(b"\x1b[5A\r\x1b[2Kfour").await.unwrap();
+        render.flush().await.unwrap();
+        assert_eq!(&buf, b"one\nfour\nthree");
+    }
+
+    #[tokio::test]
+    async fn cursor_movement_stops_at_the_bottom() {
+        let mut buf = vec![];
+        let mut render = TextRenderer::new(&mut buf, 80, 3);
+        render.on_data(b"one\n").await.unwrap();
+        // Moving the cursor down doesn't scroll, unlike line feeds
+        render.on_data(b"\x1b[100Btwo\x1b[5Bthree").await.unwrap();
+        assert_eq!(render.inner_mut().as_slice(), b"");
+        render.on_data(b"\x1bEfour").await.unwrap();
+        render.flush().await.unwrap();
+        assert_eq!(&buf, b"one\n\ntwothree\nfour");
+    }
+
+    #[tokio::test]
+    async fn cursor_movement_stops_at_the_last_column() {
+        let mut buf = vec![];
+        let mut render = TextRenderer::new(&mut buf, 10, 24);
+        render
+            .on_data(b"a\x1b[4294967295Cb\n\x1b[4294967295Gc\n")
+            .await
+            .unwrap();
+        // Lines aren't wrapped, so the cursor doesn't move back
+        render.on_data(b"0123456789abc\x1b[5Cd\n").await.unwrap();
+        render.flush().await.unwrap();
+        assert_eq!(
+            String::from_utf8(buf).unwrap(),
+            "a        b\n         c\n0123456789abcd\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn keeps_styles() {
+        let mut buf = vec![];
+        let mut render = TextRenderer::new(&mut buf, 80, 24);
+        render
+            .on_data(b"\x1b[1;31mfailed\x1b[0m: 1 test\n\x1b[32mok\x1b[0m\rOK\n")
+            .await
+            .unwrap();
+        render.flush().await.unwrap();
+        assert_eq!(
+            String::from_utf8(buf).unwrap(),
+            "\x1b[0;1;7mfailed\x1b[0m: 1 test\nOK\n"
+        );
+    }
 }
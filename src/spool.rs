@@ -0,0 +1,878 @@
+//! A write-ahead spool for the output that `fp run` and `fp shell` send to a
+//! notebook.
+//!
+//! Every operation is written to a file before it is sent, and operations that
+//! could not be sent are retried with exponential backoff. This way a flaky
+//! connection doesn't end the session, and if `fp` is stopped before it could
+//! send everything, `fp sync` uploads the rest.
+//!
+//! A failed attempt might still have been applied by the server, for example
+//! when the connection dropped before the response arrived. Before an
+//! operation is retried, the notebook is checked for the cells, text and files
+//! it would add.
+
+use crate::attachments::{file_link_cell, find_uploaded_file, upload_file};
+use crate::config::api_client_configuration;
+use crate::local_notebook::LocalNotebook;
+use anyhow::{bail, Context, Result};
+use clap::Parser;
+use directories::ProjectDirs;
+use fiberplane::api_client::clients::ApiClient;
+use fiberplane::api_client::{notebook_cell_append_text, notebook_cells_append, notebook_get};
+use fiberplane::base64uuid::Base64Uuid;
+use fiberplane::models::formatting::Formatting;
+use fiberplane::models::notebooks::operations::CellAppendText;
+use fiberplane::models::notebooks::Cell;
+use fiberplane::models::utils::char_count;
+use serde::{Deserialize, Serialize};
+use std::cmp;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+use sysinfo::{Pid, ProcessExt, System, SystemExt};
+use time::{format_description::well_known::Rfc3339, OffsetDateTime};
+use tokio::fs::{self, File, OpenOptions};
+use tokio::io::AsyncWriteExt;
+use tracing::{debug, info, warn};
+use url::Url;
+
+const INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+
+/// How long we keep trying to send the remaining operations when a session
+/// ends, before leaving them for `fp sync`
+const FINISH_TIMEOUT: Duration = Duration::from_secs(30);
+
+#[derive(Parser)]
+pub struct Arguments {
+    #[clap(from_global)]
+    config: Option<PathBuf>,
+}
+
+/// Upload the output of sessions that could not be sent completely, for
+/// example because `fp` was stopped while the connection was down
+pub async fn handle_command(args: Arguments) -> Result<()> {
+    let mut paths = Vec::new();
+    match fs::read_dir(spool_dir()).await {
+        Ok(mut entries) => {
+            while let Some(entry) = entries.next_entry().await? {
+                if entry.path().extension().map_or(false, |ext| ext == "jsonl") {
+                    paths.push(entry.path());
+                }
+            }
+        }
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => {}
+        Err(err) => return Err(err.into()),
+    }
+    paths.sort();
+
+    let mut failed = 0;
+    let mut synced = 0;
+    for path in &paths {
+        match sync_spool(args.config.clone(), path).await {
+            Ok(true) => synced += 1,
+            Ok(false) => {}
+            Err(err) => {
+                warn!("Unable to upload {}: {:?}", path.display(), err);
+                failed += 1;
+            }
+        }
+    }
+
+    if failed > 0 {
+        bail!(
+            "{} session(s) could not be uploaded, try again later",
+            failed
+        );
+    }
+    if synced == 0 {
+        info!("Nothing to upload");
+    } else {
+        info!("Uploaded the output of {} session(s)", synced);
+    }
+    Ok(())
+}
+
+/// Upload the remaining operations of a spool. Returns false if nothing was
+/// uploaded, because the spool belongs to a session that is still running or
+/// there was nothing left to send.
+async fn sync_spool(config: Option<PathBuf>, path: &Path) -> Result<bool> {
+    let content = fs::read_to_string(path).await?;
+    let contents = SpoolContents::parse(&content)?;
+
+    if is_running(contents.header.pid, contents.header.process_started_at) {
+        debug!("Skipping {}, its session is still running", path.display());
+        return Ok(false);
+    }
+    if contents.pending.is_empty() {
+        debug!("Removing {}, everything was sent already", path.display());
+        fs::remove_file(path).await?;
+        return Ok(false);
+    }
+
+    let base_url = Url::parse(&contents.header.base_url)?;
+    let client = api_client_configuration(config, base_url).await?;
+    let mut spool = Spool {
+        client,
+        notebook_id: contents.header.notebook_id,
+        path: path.to_owned(),
+        file: OpenOptions::new().append(true).open(path).await?,
+        pending: contents.pending,
+        next_id: contents.next_id,
+        cell_ids: contents.cell_ids,
+        text_lengths: HashMap::new(),
+        failures: 0,
+        next_attempt: None,
+    };
+
+    info!(
+        "Uploading {} operation(s) to notebook {}",
+        spool.pending.len(),
+        spool.notebook_id
+    );
+    while !spool.pending.is_empty() {
+        let sent = spool.send_next().await?;
+        spool.complete(sent).await?;
+    }
+    fs::remove_file(path).await?;
+    Ok(true)
+}
+
+fn spool_dir() -> PathBuf {
+    ProjectDirs::from("com", "Fiberplane", "fiberplane-cli")
+        .unwrap()
+        .data_dir()
+        .join("spool")
+}
+
+/// Whether the process that created a spool is still running. The start time
+/// of the process is compared as well, in case its PID was reused since.
+fn is_running(pid: u32, started_at: Option<u64>) -> bool {
+    match (process_start_time(Pid::from(pid as usize)), started_at) {
+        (Some(start_time), Some(started_at)) => start_time == started_at,
+        (Some(_), None) => true,
+        (None, _) => false,
+    }
+}
+
+/// When the process started, in seconds since the Unix epoch, or `None` if it
+/// isn't running
+fn process_start_time(pid: Pid) -> Option<u64> {
+    let mut system = System::new();
+    if !system.refresh_process(pid) {
+        return None;
+    }
+    system.process(pid).map(ProcessExt::start_time)
+}
+
+/// Generate the ID for a new cell, so later operations can refer to the cell
+/// before the server has seen it
+pub fn new_cell_id() -> String {
+    Base64Uuid::new().to_string()
+}
+
+/// An operation on the notebook that is written to the spool before it is sent
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Operation {
+    AppendCells {
+        cells: Vec<Cell>,
+    },
+    AppendText {
+        cell_id: String,
+        content: String,
+        formatting: Formatting,
+    },
+    /// Upload a file and append a cell that links to it. Once the file is
+    /// uploaded, this is replaced by an operation that appends the cell. The
+    /// file name gets a unique prefix, so a retry can find the file if it was
+    /// uploaded already.
+    AttachFile {
+        file_name: String,
+        mime_type: String,
+        #[serde(with = "base64_content")]
+        content: Vec<u8>,
+        link_prefix: String,
+        link_text: String,
+    },
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum Entry {
+    Header(Header),
+    Operation {
+        id: u64,
+        operation: Operation,
+        /// For text that is appended, the length of the cell before it
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        text_offset: Option<u32>,
+    },
+    /// The operation was sent successfully
+    Done {
+        id: u64,
+        /// Cells that the server gave a different ID than the one we sent
+        #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+        cell_ids: HashMap<String, String>,
+    },
+    /// The first step of the operation was sent, and the operation was
+    /// replaced by what is left to do
+    Replaced {
+        id: u64,
+        operation: Operation,
+    },
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct Header {
+    notebook_id: Base64Uuid,
+    base_url: String,
+    /// The process that writes to the spool
+    pid: u32,
+    /// When that process started, so a process that got the same PID later
+    /// isn't mistaken for it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    process_started_at: Option<u64>,
+    created_at: String,
+}
+
+#[derive(Debug)]
+struct PendingOperation {
+    id: u64,
+    operation: Operation,
+    /// Set once we tried to send the operation, in which case the server
+    /// might have applied it already
+    attempted: bool,
+    /// For text that is appended, the length of the cell before it, so a
+    /// retry can tell whether it was appended already
+    text_offset: Option<u32>,
+}
+
+/// What is left of an operation after it was sent
+enum Sent {
+    /// The operation is done. Contains the cells that the server gave a
+    /// different ID than the one we sent.
+    Done(HashMap<String, String>),
+    /// Only the first step of the operation was done
+    Replaced(Operation),
+}
+
+/// What was read back from a spool file
+struct SpoolContents {
+    header: Header,
+    pending: VecDeque<PendingOperation>,
+    next_id: u64,
+    cell_ids: HashMap<String, String>,
+}
+
+impl SpoolContents {
+    fn parse(content: &str) -> Result<Self> {
+        let mut lines = content.lines().filter(|line| !line.trim().is_empty());
+        let header = match lines.next().map(serde_json::from_str) {
+            Some(Ok(Entry::Header(header))) => header,
+            _ => bail!("Spool does not start with a header"),
+        };
+
+        let mut contents = Self {
+            header,
+            pending: VecDeque::new(),
+            next_id: 0,
+            cell_ids: HashMap::new(),
+        };
+        for line in lines {
+            // The last line might be incomplete if `fp` was stopped while
+            // writing it, which is fine since that operation was not sent yet
+            let entry = match serde_json::from_str(line) {
+                Ok(entry) => entry,
+                Err(err) => {
+                    debug!(%err, "Skipping invalid spool entry");
+                    continue;
+                }
+            };
+            match entry {
+                Entry::Header(_) => bail!("Spool contains multiple headers"),
+                Entry::Operation {
+                    id,
+                    operation,
+                    text_offset,
+                } => {
+                    contents.next_id = id + 1;
+                    // We don't know whether the session tried to send it
+                    contents.pending.push_back(PendingOperation {
+                        id,
+                        operation,
+                        attempted: true,
+                        text_offset,
+                    });
+                }
+                Entry::Done { id, cell_ids } => {
+                    contents.pending.retain(|entry| entry.id != id);
+                    contents.cell_ids.extend(cell_ids);
+                }
+                Entry::Replaced { id, operation } => {
+                    if let Some(entry) = contents.pending.iter_mut().find(|entry| entry.id == id) {
+                        entry.operation = operation;
+                    }
+                }
+            }
+        }
+
+        Ok(contents)
+    }
+}
+
+/// Where `fp run` and `fp shell` write their output
+pub enum Destination {
+    /// A notebook on Fiberplane, which the output is sent to through the spool
+    Notebook(Spool),
+    /// A notebook file, when `--local` is used
+    Local(LocalNotebook),
+}
+
+impl Destination {
+    pub async fn push(&mut self, operation: Operation) -> Result<()> {
+        match self {
+            Self::Notebook(spool) => spool.push(operation).await,
+            Self::Local(notebook) => notebook.push(operation).await,
+        }
+    }
+
+    pub async fn flush(&mut self) -> Result<()> {
+        match self {
+            Self::Notebook(spool) => spool.flush().await,
+            Self::Local(notebook) => notebook.flush().await,
+        }
+    }
+
+    pub async fn finish(&mut self) -> Result<()> {
+        match self {
+            Self::Notebook(spool) => spool.finish().await,
+            Self::Local(notebook) => notebook.finish().await,
+        }
+    }
+
+    /// The ID of the cell in the notebook, for a cell that was appended with
+    /// the given ID
+    pub fn cell_id<'a>(&'a self, cell_id: &'a str) -> &'a str {
+        match self {
+            Self::Notebook(spool) => spool.cell_id(cell_id),
+            Self::Local(_) => cell_id,
+        }
+    }
+}
+
+/// Sends operations to a notebook in order, after writing them to the spool
+pub struct Spool {
+    client: ApiClient,
+    notebook_id: Base64Uuid,
+    path: PathBuf,
+    file: File,
+    /// Operations that were not sent yet, oldest first
+    pending: VecDeque<PendingOperation>,
+    next_id: u64,
+    /// IDs the server gave to cells, if they differ from the ones we sent
+    cell_ids: HashMap<String, String>,
+    /// Length of the text of the cells that were appended, including the
+    /// text that was appended to them since
+    text_lengths: HashMap<String, u32>,
+    /// Number of consecutive attempts that failed
+    failures: u32,
+    next_attempt: Option<Instant>,
+}
+
+impl Spool {
+    pub async fn create(client: ApiClient, notebook_id: Base64Uuid) -> Result<Self> {
+        let dir = spool_dir();
+        fs::create_dir_all(&dir)
+            .await
+            .with_context(|| format!("Error creating spool directory: {}", dir.display()))?;
+
+        let now = OffsetDateTime::now_utc();
+        let path = dir.join(format!(
+            "{}-{}.jsonl",
+            now.unix_timestamp_nanos(),
+            Base64Uuid::new()
+        ));
+        let file = File::create(&path)
+            .await
+            .with_context(|| format!("Error creating spool: {}", path.display()))?;
+
+        let header = Header {
+            notebook_id,
+            base_url: client.server.to_string(),
+            pid: std::process::id(),
+            process_started_at: sysinfo::get_current_pid().ok().and_then(process_start_time),
+            created_at: now.format(&Rfc3339)?,
+        };
+        let mut spool = Self {
+            client,
+            notebook_id,
+            path,
+            file,
+            pending: VecDeque::new(),
+            next_id: 0,
+            cell_ids: HashMap::new(),
+            text_lengths: HashMap::new(),
+            failures: 0,
+            next_attempt: None,
+        };
+        spool.write_entry(&Entry::Header(header)).await?;
+        Ok(spool)
+    }
+
+    /// The ID of the cell in the notebook, for a cell that was appended with
+    /// the given ID
+    pub fn cell_id<'a>(&'a self, cell_id: &'a str) -> &'a str {
+        self.cell_ids
+            .get(cell_id)
+            .map(String::as_str)
+            .unwrap_or(cell_id)
+    }
+
+    /// Write the operation to the spool and try to send it. Failing to send
+    /// it is not an error, it will be retried on the next flush.
+    pub async fn push(&mut self, mut operation: Operation) -> Result<()> {
+        // Cells and files get an ID before they are written to the spool, and
+        // we keep track of how long the text of each cell is, so a retry can
+        // tell whether they were appended already
+        let mut text_offset = None;
+        match &mut operation {
+            Operation::AppendCells { cells } => {
+                *cells = std::mem::take(cells)
+                    .into_iter()
+                    .map(|cell| {
+                        if cell.id().is_empty() {
+                            cell.with_id(&new_cell_id())
+                        } else {
+                            cell
+                        }
+                    })
+                    .collect();
+                for cell in cells.iter() {
+                    if let Some(text) = cell.content() {
+                        self.text_lengths
+                            .insert(cell.id().to_string(), char_count(text));
+                    }
+                }
+            }
+            Operation::AppendText {
+                cell_id, content, ..
+            } => {
+                if let Some(length) = self.text_lengths.get_mut(cell_id) {
+                    text_offset = Some(*length);
+                    *length += char_count(content);
+                }
+            }
+            Operation::AttachFile { file_name, .. } => {
+                *file_name = format!("{}-{}", Base64Uuid::new(), file_name);
+            }
+        }
+
+        let id = self.next_id;
+        self.next_id += 1;
+        let entry = Entry::Operation {
+            id,
+            operation,
+            text_offset,
+        };
+        self.write_entry(&entry).await?;
+        if let Entry::Operation { id, operation, .. } = entry {
+            self.pending.push_back(PendingOperation {
+                id,
+                operation,
+                attempted: false,
+                text_offset,
+            });
+        }
+
+        self.flush().await
+    }
+
+    /// Try to send the pending operations, unless we're waiting to retry
+    /// after a failed attempt. Only errors writing the spool are returned.
+    pub async fn flush(&mut self) -> Result<()> {
+        if matches!(self.next_attempt, Some(next_attempt) if Instant::now() < next_attempt) {
+            return Ok(());
+        }
+
+        while !self.pending.is_empty() {
+            match self.send_next().await {
+                Ok(sent) => {
+                    if self.failures > 0 {
+                        info!("Connection restored, uploading the buffered output");
+                        self.failures = 0;
+                        self.next_attempt = None;
+                    }
+                    self.complete(sent).await?;
+                }
+                Err(err) => {
+                    self.failures += 1;
+                    let delay = backoff(self.failures);
+                    if self.failures == 1 {
+                        warn!(
+                            "Unable to send output to the notebook, it is buffered and will be retried: {}",
+                            err
+                        );
+                    } else {
+                        debug!(%err, ?delay, "Sending output failed again");
+                    }
+                    self.next_attempt = Some(Instant::now() + delay);
+                    break;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Keep trying to send the pending operations for a while. If they
+    /// can't be sent, the spool is left for `fp sync`, otherwise it's removed.
+    pub async fn finish(&mut self) -> Result<()> {
+        let deadline = Instant::now() + FINISH_TIMEOUT;
+        loop {
+            self.flush().await?;
+            if self.pending.is_empty() {
+                fs::remove_file(&self.path).await?;
+                return Ok(());
+            }
+
+            match self.next_attempt {
+                Some(next_attempt) if next_attempt < deadline => {
+                    tokio::time::sleep_until(next_attempt.into()).await
+                }
+                _ => break,
+            }
+        }
+
+        warn!(
+            "Some of the output could not be sent to the notebook. It is saved in {} and will be uploaded when you run `fp sync`.",
+            self.path.display()
+        );
+        Ok(())
+    }
+
+    /// Send the oldest pending operation
+    async fn send_next(&mut self) -> Result<Sent> {
+        let attempted = std::mem::replace(&mut self.pending[0].attempted, true);
+        let pending = &self.pending[0];
+        self.send(&pending.operation, attempted, pending.text_offset)
+            .await
+    }
+
+    /// Send an operation. If it was attempted before, only the parts that
+    /// are not in the notebook yet are sent.
+    async fn send(
+        &self,
+        operation: &Operation,
+        attempted: bool,
+        text_offset: Option<u32>,
+    ) -> Result<Sent> {
+        let mut cell_ids = HashMap::new();
+        match operation {
+            Operation::AppendCells { cells } => {
+                let cells = if attempted {
+                    let notebook = notebook_get(&self.client, self.notebook_id).await?;
+                    missing_cells(&notebook.cells, cells)
+                } else {
+                    cells.clone()
+                };
+                if cells.is_empty() {
+                    return Ok(Sent::Done(cell_ids));
+                }
+
+                let appended = notebook_cells_append(
+                    &self.client,
+                    self.notebook_id,
+                    None,
+                    None,
+                    cells.clone(),
+                )
+                .await?;
+                for (cell, appended) in cells.iter().zip(&appended) {
+                    if !cell.id().is_empty() && cell.id() != appended.id() {
+                        cell_ids.insert(cell.id().to_string(), appended.id().to_string());
+                    }
+                }
+            }
+            Operation::AppendText {
+                cell_id,
+                content,
+                formatting,
+            } => {
+                if attempted {
+                    let notebook = notebook_get(&self.client, self.notebook_id).await?;
+                    let cell_id = self.cell_id(cell_id);
+                    let text = notebook
+                        .cells
+                        .iter()
+                        .find(|cell| cell.id() == cell_id)
+                        .and_then(Cell::content);
+                    let appended = match (text, text_offset) {
+                        // Comparing the length rather than the text itself
+                        // means output that repeats isn't mistaken for text
+                        // that was appended already
+                        (Some(text), Some(offset)) => {
+                            char_count(text) >= offset + char_count(content)
+                        }
+                        (Some(text), None) => text.ends_with(content.as_str()),
+                        (None, _) => false,
+                    };
+                    if appended {
+                        return Ok(Sent::Done(cell_ids));
+                    }
+                }
+
+                notebook_cell_append_text(
+                    &self.client,
+                    self.notebook_id,
+                    self.cell_id(cell_id),
+                    CellAppendText::builder()
+                        .content(content.clone())
+                        .formatting(formatting.clone())
+                        .build(),
+                )
+                .await?;
+            }
+            Operation::AttachFile {
+                file_name,
+                mime_type,
+                content,
+                link_prefix,
+                link_text,
+            } => {
+                let uploaded = if attempted {
+                    find_uploaded_file(&self.client, self.notebook_id, file_name).await?
+                } else {
+                    None
+                };
+                let url = match uploaded {
+                    Some(url) => url,
+                    None => {
+                        upload_file(
+                            &self.client,
+                            self.notebook_id,
+                            file_name,
+                            mime_type,
+                            content.clone(),
+                        )
+                        .await?
+                    }
+                };
+                // The cell is appended separately, so the file isn't uploaded
+                // again if appending the cell fails
+                let cell = file_link_cell(link_prefix, link_text, &url).with_id(&new_cell_id());
+                return Ok(Sent::Replaced(Operation::AppendCells { cells: vec![cell] }));
+            }
+        }
+        Ok(Sent::Done(cell_ids))
+    }
+
+    /// Record the progress of the oldest pending operation
+    async fn complete(&mut self, sent: Sent) -> Result<()> {
+        let id = match self.pending.front() {
+            Some(entry) => entry.id,
+            None => return Ok(()),
+        };
+        match sent {
+            Sent::Done(cell_ids) => {
+                self.write_entry(&Entry::Done {
+                    id,
+                    cell_ids: cell_ids.clone(),
+                })
+                .await?;
+                self.pending.pop_front();
+                self.cell_ids.extend(cell_ids);
+            }
+            Sent::Replaced(operation) => {
+                let entry = Entry::Replaced { id, operation };
+                self.write_entry(&entry).await?;
+                if let (Entry::Replaced { operation, .. }, Some(pending)) =
+                    (entry, self.pending.front_mut())
+                {
+                    pending.operation = operation;
+                    pending.attempted = false;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    async fn write_entry(&mut self, entry: &Entry) -> Result<()> {
+        let line = format!("{}\n", serde_json::to_string(entry)?);
+        self.file
+            .write_all(line.as_bytes())
+            .await
+            .with_context(|| format!("Error writing to spool: {}", self.path.display()))?;
+        self.file.flush().await?;
+        Ok(())
+    }
+}
+
+/// The cells that are not in the notebook yet
+fn missing_cells(notebook_cells: &[Cell], cells: &[Cell]) -> Vec<Cell> {
+    let existing: HashSet<String> = notebook_cells
+        .iter()
+        .map(|cell| cell.id().to_string())
+        .collect();
+    cells
+        .iter()
+        .filter(|cell| !existing.contains(cell.id()))
+        .cloned()
+        .collect()
+}
+
+/// Time to wait before the next attempt, after the given number of attempts
+/// failed in a row
+fn backoff(failures: u32) -> Duration {
+    let exponent = cmp::min(failures.saturating_sub(1), 16);
+    cmp::min(INITIAL_BACKOFF * 2u32.pow(exponent), MAX_BACKOFF)
+}
+
+mod base64_content {
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(content: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&base64::encode(content))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<u8>, D::Error> {
+        let content = String::deserialize(deserializer)?;
+        base64::decode(content).map_err(serde::de::Error::custom)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use fiberplane::models::notebooks::TextCell;
+
+    #[test]
+    fn exponential_backoff() {
+        assert_eq!(backoff(1), Duration::from_secs(1));
+        assert_eq!(backoff(2), Duration::from_secs(2));
+        assert_eq!(backoff(5), Duration::from_secs(16));
+        assert_eq!(backoff(7), MAX_BACKOFF);
+        assert_eq!(backoff(100), MAX_BACKOFF);
+    }
+
+    #[test]
+    fn parse_spool() {
+        let header = Entry::Header(Header {
+            notebook_id: Base64Uuid::new(),
+            base_url: "https://studio.fiberplane.com/".to_string(),
+            pid: 1,
+            process_started_at: None,
+            created_at: "2022-10-19T12:00:00Z".to_string(),
+        });
+        let text = |id, content: &str| Entry::Operation {
+            id,
+            operation: Operation::AppendText {
+                cell_id: "local".to_string(),
+                content: content.to_string(),
+                formatting: Formatting::new(),
+            },
+            text_offset: Some(4),
+        };
+        let done = Entry::Done {
+            id: 0,
+            cell_ids: HashMap::from([("local".to_string(), "remote".to_string())]),
+        };
+        let content = [header, text(0, "sent"), done, text(1, "pending")]
+            .iter()
+            .map(|entry| serde_json::to_string(entry).unwrap())
+            .collect::<Vec<_>>()
+            .join("\n")
+            // An entry that was cut off when `fp` was stopped
+            + "\n{\"type\":\"operation\",\"id\":2,";
+
+        let contents = SpoolContents::parse(&content).unwrap();
+        assert_eq!(contents.next_id, 2);
+        assert_eq!(contents.cell_ids["local"], "remote");
+        assert_eq!(contents.pending.len(), 1);
+        assert_eq!(contents.pending[0].id, 1);
+        assert!(contents.pending[0].attempted);
+        assert_eq!(contents.pending[0].text_offset, Some(4));
+        assert_eq!(
+            contents.pending[0].operation,
+            Operation::AppendText {
+                cell_id: "local".to_string(),
+                content: "pending".to_string(),
+                formatting: Formatting::new(),
+            }
+        );
+    }
+
+    #[test]
+    fn parse_replaced_operations() {
+        let header = Entry::Header(Header {
+            notebook_id: Base64Uuid::new(),
+            base_url: "https://studio.fiberplane.com/".to_string(),
+            pid: 1,
+            process_started_at: None,
+            created_at: "2022-10-19T12:00:00Z".to_string(),
+        });
+        let attach = Entry::Operation {
+            id: 0,
+            operation: Operation::AttachFile {
+                file_name: "output.txt".to_string(),
+                mime_type: "text/plain".to_string(),
+                content: b"output".to_vec(),
+                link_prefix: "Full output: ".to_string(),
+                link_text: "output.txt".to_string(),
+            },
+            text_offset: None,
+        };
+        let append = Operation::AppendCells {
+            cells: vec![cell("link")],
+        };
+        let replaced = Entry::Replaced {
+            id: 0,
+            operation: append,
+        };
+        let content = [header, attach, replaced]
+            .iter()
+            .map(|entry| serde_json::to_string(entry).unwrap())
+            .collect::<Vec<_>>()
+            .join("\n");
+
+        // The file was uploaded, so only the cell is left to append
+        let contents = SpoolContents::parse(&content).unwrap();
+        assert_eq!(contents.pending.len(), 1);
+        assert_eq!(
+            contents.pending[0].operation,
+            Operation::AppendCells {
+                cells: vec![cell("link")],
+            }
+        );
+    }
+
+    #[test]
+    fn detect_running_process() {
+        let pid = sysinfo::get_current_pid().unwrap();
+        let started_at = process_start_time(pid);
+        assert!(started_at.is_some());
+        assert!(is_running(std::process::id(), started_at));
+        assert!(is_running(std::process::id(), None));
+        // The PID was reused by another process
+        assert!(!is_running(
+            std::process::id(),
+            started_at.map(|time| time - 1)
+        ));
+    }
+
+    #[test]
+    fn find_missing_cells() {
+        let notebook_cells = vec![cell("one"), cell("two")];
+        let missing = missing_cells(&notebook_cells, &[cell("two"), cell("three")]);
+        assert_eq!(missing, vec![cell("three")]);
+    }
+
+    fn cell(id: &str) -> Cell {
+        Cell::Text(
+            TextCell::builder()
+                .id(id.to_string())
+                .content(format!("cell {id}"))
+                .build(),
+        )
+    }
+}
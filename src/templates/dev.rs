@@ -0,0 +1,279 @@
+//! Watch mode for template authors: `fp templates dev` expands a template
+//! every time it is saved and shows the result in a scratch notebook.
+//!
+//! The result is shown in a scratch notebook that is created for the first
+//! expansion, or in the notebook that is given with `--notebook`. Every
+//! expansion replaces the cells of the previous one, so the notebook and its
+//! URL stay the same while the template is being edited.
+
+use super::{describe_expansion_error, TemplateArguments};
+use crate::config::api_client_configuration;
+use crate::fp_urls::NotebookUrlBuilder;
+use crate::interactive::workspace_picker;
+use anyhow::{Context, Result};
+use clap::{Parser, ValueHint};
+use fiberplane::api_client::clients::ApiClient;
+use fiberplane::api_client::{notebook_cells_append, notebook_create};
+use fiberplane::base64uuid::Base64Uuid;
+use fiberplane::models::notebooks::{Cell, HeadingCell, HeadingType, NewNotebook};
+use fiberplane::templates::expand_template;
+use lazy_static::lazy_static;
+use regex::Regex;
+use std::collections::BTreeSet;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+use time::{macros::format_description, OffsetDateTime};
+use tokio::signal;
+use tracing::{error, info, warn};
+use url::Url;
+
+const WATCH_INTERVAL: Duration = Duration::from_millis(500);
+
+lazy_static! {
+    /// Locations in Jsonnet errors, such as `template.jsonnet:12:5-20`
+    static ref LOCATION_REGEX: Regex = Regex::new(r":(\d+):(\d+)(?:-\d+)?").unwrap();
+}
+
+#[derive(Parser)]
+pub(super) struct DevArguments {
+    /// Workspace to create the scratch notebook in
+    #[clap(from_global)]
+    workspace_id: Option<Base64Uuid>,
+
+    /// Path of the template file
+    #[clap(value_hint = ValueHint::FilePath)]
+    template: PathBuf,
+
+    /// Values to inject into the template
+    ///
+    /// Can be passed as a JSON object or as a comma-separated list of key=value pairs
+    template_arguments: Option<TemplateArguments>,
+
+    /// Notebook to show the expanded template in, instead of a scratch
+    /// notebook
+    ///
+    /// Every expansion replaces the cells that the previous expansion added
+    /// to the notebook. Other cells of the notebook are left as they are.
+    #[clap(long = "notebook", short, env)]
+    notebook_id: Option<Base64Uuid>,
+
+    #[clap(from_global)]
+    base_url: Url,
+
+    #[clap(from_global)]
+    config: Option<PathBuf>,
+}
+
+pub(super) async fn handle_dev_command(args: DevArguments) -> Result<()> {
+    let client = api_client_configuration(args.config.clone(), args.base_url.clone()).await?;
+    let workspace_id = workspace_picker(&client, args.workspace_id).await?;
+    let template_arguments = args.template_arguments.unwrap_or_default().0;
+
+    let mut notebook_id = args.notebook_id;
+    if let Some(notebook_id) = notebook_id {
+        print_notebook_url(workspace_id, notebook_id, &args.base_url)?;
+    }
+    // The cells of the last expansion, which are replaced by the next one
+    let mut preview_cell_ids = Vec::new();
+    info!(
+        "Watching {} for changes, press Ctrl+C to stop",
+        args.template.display()
+    );
+
+    let mut last_modified = None;
+    let mut interval = tokio::time::interval(WATCH_INTERVAL);
+    loop {
+        tokio::select! {
+            _ = interval.tick() => {}
+            _ = signal::ctrl_c() => break,
+        }
+
+        let modified = modified_time(&args.template);
+        if modified.is_none() || modified == last_modified {
+            continue;
+        }
+        last_modified = modified;
+
+        let template = match tokio::fs::read_to_string(&args.template).await {
+            Ok(template) => template,
+            Err(err) => {
+                error!("Unable to read {}: {}", args.template.display(), err);
+                continue;
+            }
+        };
+        let mut notebook = match expand_template(template.clone(), template_arguments.clone()) {
+            Ok(notebook) => notebook,
+            Err(err) => {
+                let err = describe_expansion_error(err).to_string();
+                error!("{}", annotate_error(&err, &template));
+                continue;
+            }
+        };
+
+        let title = notebook.title.clone();
+        let cells = std::mem::take(&mut notebook.cells);
+        let id = match notebook_id {
+            Some(id) => id,
+            None => match create_scratch_notebook(&client, workspace_id, notebook).await {
+                Ok(id) => {
+                    if let Err(err) = print_notebook_url(workspace_id, id, &args.base_url) {
+                        warn!("Unable to show the URL of the notebook: {:?}", err);
+                    }
+                    notebook_id = Some(id);
+                    id
+                }
+                Err(err) => {
+                    error!("Unable to create notebook: {:?}", err);
+                    continue;
+                }
+            },
+        };
+
+        remove_preview(&client, id, &mut preview_cell_ids).await;
+        match append_preview(&client, id, &title, cells).await {
+            Ok(cell_ids) => {
+                preview_cell_ids = cell_ids;
+                info!("Updated notebook");
+            }
+            Err(err) => error!("Unable to update notebook: {:?}", err),
+        }
+    }
+
+    Ok(())
+}
+
+fn print_notebook_url(
+    workspace_id: Base64Uuid,
+    notebook_id: Base64Uuid,
+    base_url: &Url,
+) -> Result<()> {
+    let url = NotebookUrlBuilder::new(workspace_id, notebook_id)
+        .base_url(base_url.clone())
+        .url()?;
+    info!("Previewing template in notebook: {}", url);
+    Ok(())
+}
+
+async fn create_scratch_notebook(
+    client: &ApiClient,
+    workspace_id: Base64Uuid,
+    notebook: NewNotebook,
+) -> Result<Base64Uuid> {
+    let notebook = notebook_create(client, workspace_id, notebook)
+        .await
+        .context("Error creating notebook")?;
+    Ok(Base64Uuid::parse_str(&notebook.id)?)
+}
+
+/// Append the cells of the expanded template under a heading with the time
+/// of the expansion, and return the IDs of the appended cells
+async fn append_preview(
+    client: &ApiClient,
+    notebook_id: Base64Uuid,
+    title: &str,
+    cells: Vec<Cell>,
+) -> Result<Vec<String>> {
+    let timestamp = OffsetDateTime::now_utc()
+        .format(format_description!("[hour]:[minute]:[second]"))
+        .unwrap_or_default();
+    let mut preview = vec![Cell::Heading(
+        HeadingCell::builder()
+            .id(String::new())
+            .heading_type(HeadingType::H1)
+            .content(format!("{title} (expanded at {timestamp} UTC)"))
+            .build(),
+    )];
+    preview.extend(cells.into_iter().map(|cell| cell.with_id("")));
+
+    let appended = notebook_cells_append(client, notebook_id, None, None, preview).await?;
+    Ok(appended.iter().map(|cell| cell.id().to_string()).collect())
+}
+
+/// Remove the cells of the previous expansion from the notebook. If that
+/// fails, the cells are left in the notebook and we don't try again.
+async fn remove_preview(client: &ApiClient, notebook_id: Base64Uuid, cell_ids: &mut Vec<String>) {
+    for cell_id in std::mem::take(cell_ids) {
+        if let Err(err) = delete_cell(client, notebook_id, &cell_id).await {
+            warn!(
+                "Unable to remove the previous expansion from the notebook: {:?}",
+                err
+            );
+            return;
+        }
+    }
+}
+
+/// Delete a cell from the notebook.
+///
+/// The API client has no call for this, so the request is made directly.
+/// NOTE: this endpoint follows the other notebook routes, but hasn't been
+/// checked against the API yet.
+async fn delete_cell(client: &ApiClient, notebook_id: Base64Uuid, cell_id: &str) -> Result<()> {
+    let url = client
+        .server
+        .join(&format!("api/notebooks/{notebook_id}/cells/{cell_id}"))?;
+    client
+        .client
+        .delete(url)
+        .send()
+        .await
+        .and_then(|response| response.error_for_status())
+        .with_context(|| format!("Error deleting cell {cell_id}"))?;
+    Ok(())
+}
+
+fn modified_time(path: &Path) -> Option<SystemTime> {
+    std::fs::metadata(path)
+        .and_then(|metadata| metadata.modified())
+        .ok()
+}
+
+/// Add the lines of the template that are referenced by an error message
+fn annotate_error(message: &str, template: &str) -> String {
+    let lines: Vec<&str> = template.lines().collect();
+    let line_numbers: BTreeSet<usize> = LOCATION_REGEX
+        .captures_iter(message)
+        .filter_map(|captures| captures[1].parse().ok())
+        .filter(|line_number| (1..=lines.len()).contains(line_number))
+        .collect();
+    if line_numbers.is_empty() {
+        return message.to_string();
+    }
+
+    let width = line_numbers.iter().last().unwrap().to_string().len();
+    let mut annotated = format!("{message}\n");
+    for line_number in line_numbers {
+        annotated.push_str(&format!(
+            "\n{:>width$} | {}",
+            line_number,
+            lines[line_number - 1]
+        ));
+    }
+    annotated
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn annotates_errors_with_template_lines() {
+        let template = "local fp = import 'fiberplane.libsonnet';\n\
+                        function()\n  fp.notebook('Incident')\n    .addCell(c.text('hi'))";
+        assert_eq!(
+            annotate_error(
+                "Error evaluating template: variable is not defined: c\n    \
+                 template.jsonnet:4:14-15",
+                template
+            ),
+            "Error evaluating template: variable is not defined: c\n    \
+             template.jsonnet:4:14-15\n\n\
+             4 |     .addCell(c.text('hi'))"
+        );
+
+        assert_eq!(
+            annotate_error("Template did not produce a valid Notebook", template),
+            "Template did not produce a valid Notebook"
+        );
+    }
+}
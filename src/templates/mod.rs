@@ -1,9 +1,11 @@
+use self::preview::{render_notebook, PreviewStyle};
 use crate::interactive::{self, workspace_picker};
 use crate::output::{output_details, output_json, output_list, GenericKeyValue};
 use crate::{config::api_client_configuration, fp_urls::NotebookUrlBuilder};
 use anyhow::{anyhow, bail, Context, Error, Result};
 use clap::{Parser, ValueEnum, ValueHint};
 use cli_table::Table;
+use crossterm::tty::IsTty;
 use fiberplane::api_client::clients::ApiClient;
 use fiberplane::api_client::{
     notebook_create, notebook_get, template_create, template_delete, template_expand, template_get,
@@ -30,6 +32,9 @@ use tokio::fs;
 use tracing::{debug, info, warn};
 use url::Url;
 
+mod dev;
+mod preview;
+
 lazy_static! {
     pub static ref NOTEBOOK_ID_REGEX: Regex = Regex::from_str("([a-zA-Z0-9_-]{22})$").unwrap();
 }
@@ -75,6 +80,18 @@ enum SubCommand {
     ///
     /// Note that only templates without required parameters can be fully validated.
     Validate(ValidateArguments),
+
+    /// Expand a local template without creating a notebook
+    ///
+    /// By default, a preview of the notebook is shown in the terminal.
+    Render(RenderArguments),
+
+    /// Expand a local template every time it is saved and show the result in
+    /// a scratch notebook
+    ///
+    /// Expansion errors are shown in the terminal, with the lines of the
+    /// template that they refer to.
+    Dev(dev::DevArguments),
 }
 
 pub async fn handle_command(args: Arguments) -> Result<()> {
@@ -89,6 +106,8 @@ pub async fn handle_command(args: Arguments) -> Result<()> {
         List(args) => handle_list_command(args).await,
         Update(args) => handle_update_command(args).await,
         Validate(args) => handle_validate_command(args).await,
+        Render(args) => handle_render_command(args).await,
+        Dev(args) => dev::handle_dev_command(args).await,
     }
 }
 
@@ -347,6 +366,34 @@ struct ValidateArguments {
     template_arguments: Option<TemplateArguments>,
 }
 
+#[derive(Parser)]
+struct RenderArguments {
+    /// Path or URL of the template file
+    #[clap(value_hint = ValueHint::AnyPath)]
+    template: String,
+
+    /// Values to inject into the template
+    ///
+    /// Can be passed as a JSON object or as a comma-separated list of key=value pairs
+    template_arguments: Option<TemplateArguments>,
+
+    /// Output of the notebook
+    #[clap(long, short, default_value = "preview", value_enum)]
+    output: RenderOutput,
+}
+
+#[derive(ValueEnum, Clone)]
+enum RenderOutput {
+    /// Show a preview of the notebook in the terminal
+    Preview,
+
+    /// Output the notebook as a JSON encoded object
+    Json,
+
+    /// Output the notebook as Markdown
+    Markdown,
+}
+
 #[derive(ValueEnum, Clone)]
 enum TemplateOutput {
     /// Output the details of the template as a table (excluding body)
@@ -763,6 +810,51 @@ async fn handle_validate_command(args: ValidateArguments) -> Result<()> {
     }
 }
 
+async fn handle_render_command(args: RenderArguments) -> Result<()> {
+    let template = load_template(&args.template).await?;
+    let params = args.template_arguments.unwrap_or_default();
+    let notebook = expand_template(template, params.0).map_err(describe_expansion_error)?;
+
+    match args.output {
+        RenderOutput::Json => output_json(&notebook),
+        RenderOutput::Markdown => {
+            print!(
+                "{}",
+                render_notebook(&notebook.title, &notebook.cells, PreviewStyle::Markdown)
+            );
+            Ok(())
+        }
+        RenderOutput::Preview => {
+            let colors = std::io::stdout().is_tty();
+            print!(
+                "{}",
+                render_notebook(
+                    &notebook.title,
+                    &notebook.cells,
+                    PreviewStyle::Terminal { colors }
+                )
+            );
+            Ok(())
+        }
+    }
+}
+
+/// Explain why a template could not be expanded
+fn describe_expansion_error(err: TemplateError) -> Error {
+    match err {
+        TemplateError::MissingArgument(param) => anyhow!(
+            "Missing argument for the template parameter `{}`. \
+            Pass it as an argument, for example: {}=value",
+            param,
+            param
+        ),
+        TemplateError::InvalidOutput(err) => {
+            anyhow!("Template did not produce a valid Notebook: {:?}", err)
+        }
+        TemplateError::Evaluation(err) => anyhow!("Error evaluating template: {}", err),
+    }
+}
+
 #[derive(Table)]
 pub struct TemplateRow {
     #[table(title = "Name")]
@@ -0,0 +1,167 @@
+//! Previews of expanded templates, shown in the terminal or as Markdown.
+//!
+//! Cells are shown with their plain content: formatting such as links and
+//! mentions is not rendered.
+
+use crossterm::style::{Color, Stylize};
+use fiberplane::models::notebooks::{Cell, HeadingType};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum PreviewStyle {
+    /// Text for the terminal, styled when `colors` is set
+    Terminal {
+        colors: bool,
+    },
+    Markdown,
+}
+
+/// Render the title and cells of a notebook
+pub(crate) fn render_notebook(title: &str, cells: &[Cell], style: PreviewStyle) -> String {
+    let mut output = match style {
+        PreviewStyle::Markdown => format!("# {title}\n\n"),
+        PreviewStyle::Terminal { colors } => {
+            let underline = "═".repeat(title.chars().count().max(3));
+            if colors {
+                format!("{}\n{}\n\n", title.bold(), underline)
+            } else {
+                format!("{title}\n{underline}\n\n")
+            }
+        }
+    };
+
+    for cell in cells {
+        output.push_str(&render_cell(cell, style));
+        output.push('\n');
+    }
+    output
+}
+
+fn render_cell(cell: &Cell, style: PreviewStyle) -> String {
+    let content = cell.content().unwrap_or_default();
+    let colors = matches!(style, PreviewStyle::Terminal { colors: true });
+    let styled = |text: String, color: Color| {
+        if colors {
+            text.with(color).to_string()
+        } else {
+            text
+        }
+    };
+
+    match (cell, style) {
+        (Cell::Heading(heading), PreviewStyle::Markdown) => {
+            let level = match heading.heading_type {
+                HeadingType::H1 => "##",
+                HeadingType::H2 => "###",
+                HeadingType::H3 => "####",
+            };
+            format!("{level} {content}\n")
+        }
+        (Cell::Heading(heading), PreviewStyle::Terminal { .. }) => {
+            let text = match heading.heading_type {
+                HeadingType::H1 => content.to_uppercase(),
+                HeadingType::H2 | HeadingType::H3 => content.to_string(),
+            };
+            if colors {
+                format!("{}\n", text.bold())
+            } else {
+                format!("{text}\n")
+            }
+        }
+        (Cell::Code(_), PreviewStyle::Markdown) => format!("```\n{content}\n```\n"),
+        (Cell::Code(_), PreviewStyle::Terminal { .. }) => {
+            let lines: Vec<String> = content.lines().map(|line| format!("  │ {line}")).collect();
+            format!("{}\n", styled(lines.join("\n"), Color::DarkGrey))
+        }
+        (Cell::Provider(provider), PreviewStyle::Markdown) => format!(
+            "```{}\n{}\n```\n",
+            provider.intent,
+            provider.query_data.as_deref().unwrap_or_default()
+        ),
+        (Cell::Provider(provider), PreviewStyle::Terminal { .. }) => {
+            let query = provider.query_data.as_deref().unwrap_or_default();
+            format!(
+                "{}\n",
+                styled(format!("▶ {}  {}", provider.intent, query), Color::Cyan)
+            )
+        }
+        (Cell::Divider(_), PreviewStyle::Markdown) => "---\n".to_string(),
+        (Cell::Divider(_), PreviewStyle::Terminal { .. }) => {
+            format!("{}\n", styled("─".repeat(40), Color::DarkGrey))
+        }
+        (Cell::Text(_), _) => format!("{content}\n"),
+        _ => {
+            let label = styled(format!("[{}]", cell.type_str()), Color::DarkGrey);
+            if content.is_empty() {
+                format!("{label}\n")
+            } else {
+                format!("{label} {content}\n")
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use fiberplane::models::notebooks::{CodeCell, HeadingCell, ProviderCell, TextCell};
+
+    fn cells() -> Vec<Cell> {
+        vec![
+            Cell::Heading(
+                HeadingCell::builder()
+                    .id(String::new())
+                    .heading_type(HeadingType::H1)
+                    .content("Impact".to_string())
+                    .build(),
+            ),
+            Cell::Text(
+                TextCell::builder()
+                    .id(String::new())
+                    .content("Checkout is failing".to_string())
+                    .build(),
+            ),
+            Cell::Code(
+                CodeCell::builder()
+                    .id(String::new())
+                    .content("kubectl get pods".to_string())
+                    .build(),
+            ),
+            Cell::Provider(
+                ProviderCell::builder()
+                    .id(String::new())
+                    .intent("prometheus,timeseries")
+                    .query_data("application/x-www-form-urlencoded,query=up")
+                    .title("")
+                    .build(),
+            ),
+        ]
+    }
+
+    #[test]
+    fn renders_markdown() {
+        assert_eq!(
+            render_notebook("Incident", &cells(), PreviewStyle::Markdown),
+            "# Incident\n\n\
+             ## Impact\n\n\
+             Checkout is failing\n\n\
+             ```\nkubectl get pods\n```\n\n\
+             ```prometheus,timeseries\napplication/x-www-form-urlencoded,query=up\n```\n\n"
+        );
+    }
+
+    #[test]
+    fn renders_for_the_terminal() {
+        assert_eq!(
+            render_notebook(
+                "Incident",
+                &cells(),
+                PreviewStyle::Terminal { colors: false }
+            ),
+            "Incident\n════════\n\n\
+             IMPACT\n\n\
+             Checkout is failing\n\n  \
+             │ kubectl get pods\n\n\
+             ▶ prometheus,timeseries  application/x-www-form-urlencoded,query=up\n\n"
+        );
+    }
+}